@@ -0,0 +1,119 @@
+use std::cmp;
+use std::fmt;
+use chrono::{Timelike, UTC};
+
+/// A hybrid logical clock value. Timestamps are ordered by wall time, then counter, then
+/// device, which gives a total order over the entries of all devices that respects causality:
+/// an entry written after another one was seen always has a bigger timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, RustcEncodable, RustcDecodable)]
+pub struct Timestamp {
+    /// Milliseconds since the Unix epoch, the highest physical time seen so far.
+    pub wall: i64,
+    /// Counts events within the same wall time.
+    pub counter: u32,
+    /// Id of the device that made the timestamp.
+    pub device: String,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}@{}", self.wall, self.counter, self.device)
+    }
+}
+
+/// A remote timestamp that is further ahead of the physical time than the clock allows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockSkew {
+    pub remote: Timestamp,
+    /// The physical time when it was seen, in milliseconds since the Unix epoch.
+    pub physical: i64,
+}
+
+/// Hybrid logical clock of a device, see "Logical Physical Clocks" by Kulkarni et al.
+///
+/// The clock follows the physical time as long as it moves forward, but never goes backwards
+/// and never falls behind a timestamp it has seen. So a device with a slow clock still orders
+/// its entries after those it synchronized, and a device with a fast clock only moves the
+/// others forward as far as max_drift allows.
+#[derive(Debug, Clone)]
+pub struct HybridClock {
+    device: String,
+    wall: i64,
+    counter: u32,
+    max_drift: i64,
+}
+
+impl HybridClock {
+    /// Creates the clock of a device, accepting remote timestamps up to an hour ahead.
+    pub fn new(device: &str) -> HybridClock {
+        HybridClock {
+            device: device.to_string(),
+            wall: 0,
+            counter: 0,
+            max_drift: 60 * 60 * 1000,
+        }
+    }
+
+    /// Sets how many milliseconds remote timestamps may be ahead of the physical time.
+    pub fn set_max_drift(&mut self, millis: i64) {
+        self.max_drift = millis;
+    }
+
+    /// Returns a timestamp for a local event.
+    pub fn now(&mut self) -> Timestamp {
+        self.now_at(physical_time())
+    }
+
+    /// Like now, with the physical time given in milliseconds since the Unix epoch.
+    pub fn now_at(&mut self, physical: i64) -> Timestamp {
+        if physical > self.wall {
+            self.wall = physical;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+        self.current()
+    }
+
+    /// Moves the clock past a timestamp of another device, so everything stamped afterwards
+    /// is ordered after it.
+    pub fn update(&mut self, remote: &Timestamp) -> Result<Timestamp, ClockSkew> {
+        self.update_at(remote, physical_time())
+    }
+
+    /// Like update, with the physical time given in milliseconds since the Unix epoch.
+    pub fn update_at(&mut self, remote: &Timestamp, physical: i64) -> Result<Timestamp, ClockSkew> {
+        if remote.wall - physical > self.max_drift {
+            return Err(ClockSkew {
+                remote: remote.clone(),
+                physical: physical,
+            });
+        }
+
+        let wall = cmp::max(cmp::max(self.wall, remote.wall), physical);
+        self.counter = if wall == self.wall && wall == remote.wall {
+            cmp::max(self.counter, remote.counter) + 1
+        } else if wall == self.wall {
+            self.counter + 1
+        } else if wall == remote.wall {
+            remote.counter + 1
+        } else {
+            0
+        };
+        self.wall = wall;
+        Ok(self.current())
+    }
+
+    fn current(&self) -> Timestamp {
+        Timestamp {
+            wall: self.wall,
+            counter: self.counter,
+            device: self.device.clone(),
+        }
+    }
+}
+
+fn physical_time() -> i64 {
+    let now = UTC::now();
+    now.timestamp() * 1000 + (now.nanosecond() / 1000000) as i64
+}
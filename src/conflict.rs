@@ -0,0 +1,212 @@
+use chrono::{DateTime, Duration, UTC};
+use rustc_serialize::json;
+use uuid::Uuid;
+use clock::{HybridClock, Timestamp};
+use crypto::CryptoManager;
+use domain::{EntryType, EventData, EventLogEntry};
+use storage::{load_from, save_to};
+use storage::backend::{Backend, StorageError};
+
+/// Name of the conflict queue in the (local) backend it is saved to.
+pub const CONFLICTS_NAME: &'static str = "conflicts";
+
+/// Two devices changed the same event without seeing the change of the other.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Conflict {
+    pub id: String,
+    pub obj_id: String,
+    /// The event as both devices last saw it, if it is still in the shared log.
+    pub base: Option<EventData>,
+    pub ours: EventData,
+    pub theirs: EventData,
+    pub ours_timestamp: Option<Timestamp>,
+    pub theirs_timestamp: Option<Timestamp>,
+    pub detected: DateTime<UTC>,
+}
+
+impl Conflict {
+    /// True if our change is the later one, which wins with last-writer-wins.
+    pub fn ours_newer(&self) -> bool {
+        self.ours_timestamp > self.theirs_timestamp
+    }
+
+    /// Combines the changes field by field: a field changed by one device only gets its value,
+    /// a field changed by both the value of the later change. Without base every field counts
+    /// as changed by both.
+    pub fn merge_fields(&self) -> EventData {
+        let newer = self.ours_newer();
+        let base = self.base.as_ref();
+        let (ours, theirs) = (&self.ours.event, &self.theirs.event);
+        let mut merged = if newer { ours.clone() } else { theirs.clone() };
+        merged.name = pick(base.map(|b| &b.event.name), &ours.name, &theirs.name, newer);
+        merged.desc = pick(base.map(|b| &b.event.desc), &ours.desc, &theirs.desc, newer);
+        merged.location = pick(base.map(|b| &b.event.location), &ours.location, &theirs.location, newer);
+        merged.start = pick(base.map(|b| &b.event.start), &ours.start, &theirs.start, newer);
+        merged.end = pick(base.map(|b| &b.event.end), &ours.end, &theirs.end, newer);
+        EventData {
+            calendar: pick(base.map(|b| &b.calendar), &self.ours.calendar, &self.theirs.calendar, newer),
+            event: merged,
+        }
+    }
+}
+
+fn pick<T: PartialEq + Clone>(base: Option<&T>, ours: &T, theirs: &T, ours_newer: bool) -> T {
+    if base == Some(ours) {
+        theirs.clone()
+    } else if base == Some(theirs) || ours_newer {
+        ours.clone()
+    } else {
+        theirs.clone()
+    }
+}
+
+/// How a Synchronizer resolves conflicts.
+pub enum ConflictPolicy {
+    /// The later change wins, the other one is lost. This is what interleaving by timestamp
+    /// does anyway.
+    LastWriterWins,
+    /// The changes are combined with Conflict::merge_fields.
+    MergeFields,
+    /// The later change wins and the other one is kept as a copy of the event.
+    KeepBoth,
+    /// The function decides. If it returns None the later change wins for now and the
+    /// conflict is reported as unresolved, e.g. to ask the user.
+    Ask(Box<Fn(&Conflict) -> Option<EventData>>),
+}
+
+/// Finds the conflicts between the merged remote log and the entries of other devices since
+/// the last synchronization and resolves them with policy.
+///
+/// Resolutions are new entries, stamped after everything seen, so the devices that already
+/// synchronized their change get the result as well. Returns the remote log with them and the
+/// unresolved conflicts.
+pub fn resolve_conflicts(policy: &ConflictPolicy, remote: Vec<EventLogEntry>, incoming: &[EventLogEntry],
+                         seen: &[EventLogEntry], clock: &mut HybridClock)
+                         -> (Vec<EventLogEntry>, Vec<Conflict>) {
+    let mut resolved = Vec::with_capacity(remote.len());
+    let mut unresolved = Vec::new();
+    for entry in remote {
+        let conflict = match find_conflict(&entry, incoming, seen) {
+            Some(c) => c,
+            None => {
+                resolved.push(entry);
+                continue;
+            }
+        };
+        let outcome = match *policy {
+            ConflictPolicy::LastWriterWins => None,
+            ConflictPolicy::MergeFields => Some(conflict.merge_fields()),
+            ConflictPolicy::KeepBoth => {
+                let loser = if conflict.ours_newer() { &conflict.theirs } else { &conflict.ours };
+                let copy = EventData {
+                    calendar: loser.calendar.clone(),
+                    event: loser.event.repeat(Duration::zero()),
+                };
+                resolved.push(stamped(EntryType::Create, &copy, clock));
+                None
+            }
+            ConflictPolicy::Ask(ref f) => {
+                let outcome = f(&conflict);
+                if outcome.is_none() {
+                    unresolved.push(conflict.clone());
+                }
+                outcome
+            }
+        };
+        match outcome {
+            Some(data) => resolved.push(stamped(EntryType::Update, &data, clock)),
+            None => resolved.push(entry),
+        }
+    }
+    (resolved, unresolved)
+}
+
+/// A conflict if entry updates an event the last incoming entry of which is an update as well.
+fn find_conflict(entry: &EventLogEntry, incoming: &[EventLogEntry], seen: &[EventLogEntry])
+                 -> Option<Conflict> {
+    if entry.entry_type != EntryType::Update {
+        return None;
+    }
+    let theirs = match incoming.iter().rev().find(|e| e.obj_id == entry.obj_id) {
+        Some(e) if e.entry_type == EntryType::Update => e,
+        _ => return None,
+    };
+    let (ours_data, theirs_data) = match (event_data(entry), event_data(theirs)) {
+        (Some(o), Some(t)) => (o, t),
+        _ => return None,
+    };
+    if ours_data == theirs_data {
+        return None;
+    }
+    let base = seen.iter()
+                   .rev()
+                   .find(|e| e.obj_id == entry.obj_id)
+                   .and_then(|e| event_data(e));
+    Some(Conflict {
+        id: Uuid::new_v4().to_string(),
+        obj_id: entry.obj_id.clone(),
+        base: base,
+        ours: ours_data,
+        theirs: theirs_data,
+        ours_timestamp: entry.timestamp.clone(),
+        theirs_timestamp: theirs.timestamp.clone(),
+        detected: UTC::now(),
+    })
+}
+
+fn event_data(entry: &EventLogEntry) -> Option<EventData> {
+    json::decode(&entry.data).ok()
+}
+
+fn stamped(entry_type: EntryType, data: &EventData, clock: &mut HybridClock) -> EventLogEntry {
+    let mut entry = EventLogEntry::new(entry_type, &data.event.id, &json::encode(data).unwrap());
+    entry.stamp(clock);
+    entry
+}
+
+/// Conflicts waiting for the user, kept across restarts.
+#[derive(Debug, Clone, PartialEq, Default, RustcEncodable, RustcDecodable)]
+pub struct ConflictQueue {
+    conflicts: Vec<Conflict>,
+}
+
+impl ConflictQueue {
+    pub fn new() -> ConflictQueue {
+        ConflictQueue::default()
+    }
+
+    /// Loads the queue from b, an empty one if none has been saved.
+    pub fn load<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<ConflictQueue, StorageError> {
+        match load_from(b, CONFLICTS_NAME, c) {
+            Err(StorageError::NotFound(_)) => Ok(ConflictQueue::new()),
+            r => r,
+        }
+    }
+
+    pub fn save<B: Backend>(&self, b: &mut B, c: &mut CryptoManager) -> Result<(), StorageError> {
+        save_to(b, CONFLICTS_NAME, c, self)
+    }
+
+    /// Conflicts in the order they were detected.
+    pub fn pending(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// Adds conflicts. An earlier conflict about the same object is replaced.
+    pub fn extend<I: IntoIterator<Item = Conflict>>(&mut self, conflicts: I) {
+        for conflict in conflicts {
+            self.conflicts.retain(|c| c.obj_id != conflict.obj_id);
+            self.conflicts.push(conflict);
+        }
+    }
+
+    /// Removes and returns the conflict with the given id, once it has been resolved, e.g. by
+    /// updating the event in the TrackedAccount.
+    pub fn resolve(&mut self, id: &str) -> Option<Conflict> {
+        let index = match self.conflicts.iter().position(|c| c.id == id) {
+            Some(i) => i,
+            None => return None,
+        };
+        Some(self.conflicts.remove(index))
+    }
+}
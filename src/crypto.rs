@@ -76,10 +76,16 @@ impl CryptoManager {
 
     /// Encrypts the str with key and new nonce
     pub fn encrypt(&mut self, plaintext: &str) -> Option<Vec<u8>> {
+        self.encrypt_bytes(plaintext.as_bytes())
+    }
+
+    /// Encrypts arbitrary data with key and new nonce. The nonce is put in front of the
+    /// ciphertext.
+    pub fn encrypt_bytes(&mut self, plaintext: &[u8]) -> Option<Vec<u8>> {
         //TODO: Check for errors
 
         self.new_nonce();
-        let mut ct = secretbox::seal(plaintext.as_bytes(), &self.symnonce, &self.symkey);
+        let mut ct = secretbox::seal(plaintext, &self.symnonce, &self.symkey);
         let secretbox::Nonce(nb) = self.symnonce.clone();
         let mut out = nb.to_vec();
         out.append(&mut ct);
@@ -89,12 +95,16 @@ impl CryptoManager {
     /// Decrypts the ciphertext with key and nonce. Nonce and Key has to be the same for encryption and
     /// decryption
     pub fn decrypt(&self, ciphertext: Vec<u8>) -> Option<String> {
+        self.decrypt_bytes(ciphertext).and_then(|p| String::from_utf8(p).ok())
+    }
+
+    /// Decrypts data encrypted with encrypt_bytes.
+    pub fn decrypt_bytes(&self, ciphertext: Vec<u8>) -> Option<Vec<u8>> {
+        if ciphertext.len() < secretbox::NONCEBYTES {
+            return None;
+        }
         let (nb, ciphertext) = ciphertext.split_at(secretbox::NONCEBYTES);
         let nonce = slice_to_array(nb);
-        let plain = match secretbox::open(ciphertext, &secretbox::Nonce(nonce), &self.symkey) {
-            Ok(o) => o,
-            Err(_) => return None,
-        };
-        String::from_utf8(plain).ok()
+        secretbox::open(ciphertext, &secretbox::Nonce(nonce), &self.symkey).ok()
     }
 }
@@ -4,17 +4,26 @@ use chrono::Date;
 use chrono::DateTime;
 use chrono::Local;
 use chrono::Duration;
+use clock::{HybridClock, Timestamp};
 
+/// Everything a user stores, the state the shared log describes.
+#[derive(Debug, PartialEq, Clone, RustcEncodable, RustcDecodable)]
 pub struct Account {
     pub items: Vec<Calendar>
 }
 
+impl Account {
+    pub fn new() -> Account {
+        Account { items: Vec::new() }
+    }
+}
+
 
 /// This struct is used to store information about a single calendar,
 /// including the events in it.
 ///
 /// Events are stored in a HashMap, saved as Days containing a list of Events.
-#[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
+#[derive(Debug, PartialEq, Clone, RustcEncodable, RustcDecodable)]
 pub struct Calendar {
     pub id: String,
     pub name: String,
@@ -36,7 +45,7 @@ pub struct Event {
     pub sync: bool,
 }
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 /// Different types of entries.
 pub enum EntryType {
     Create,
@@ -44,13 +53,24 @@ pub enum EntryType {
     Delete
 }
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 /// Representation of a single entry in an Eventlog.
 pub struct EventLogEntry {
     pub id: String,
     pub entry_type: EntryType,
     pub obj_id: String,
-    pub data: String
+    pub data: String,
+    /// None until the entry is stamped, which happens once it is synchronized. Entries written
+    /// before timestamps existed have none either.
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Data of the log entries creating or updating an Event: the event and the id of the Calendar
+/// it is in. Entries about calendars have the Calendar itself as data.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct EventData {
+    pub calendar: String,
+    pub event: Event,
 }
 
 impl EventLogEntry {
@@ -60,8 +80,14 @@ impl EventLogEntry {
             entry_type: entry_type,
             obj_id: obj_id.to_string(),
             data: data.to_string(),
+            timestamp: None,
         }
     }
+
+    /// Gives the entry the current time of clock.
+    pub fn stamp(&mut self, clock: &mut HybridClock) {
+        self.timestamp = Some(clock.now());
+    }
 }
 
 /// In Calendar the Hashmaps uses DateTime<Local> as keys, because they have serde support. If
@@ -127,6 +153,11 @@ impl Calendar {
         self.days.get_mut(&date).unwrap().remove(index);
     }
 
+    /// Returns the event with the given id.
+    pub fn get_event(&self, id: &str) -> Option<&Event> {
+        self.days.values().flat_map(|d| d.iter()).find(|e| e.id == id)
+    }
+
     /// Repeats the event n times changing only the dates, with one week distance between them.
     pub fn repeat_event_n_times(&mut self, e: &Event, n: usize) {
         for _ in 0..n {
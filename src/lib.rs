@@ -9,6 +9,12 @@ extern crate chrono;
 extern crate uuid;
 extern crate sodiumoxide;
 extern crate rustc_serialize;
+extern crate futures;
+extern crate futures_cpupool;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(test)]
+extern crate quickcheck;
 
 /// This module contains all the data types that are used to store information. They are all
 /// serializeble and of course also deserializeble.
@@ -20,6 +26,21 @@ pub mod crypto;
 /// Module for managing storage
 pub mod storage;
 
+/// Hybrid logical clocks, ordering the log entries of all devices.
+pub mod clock;
+
+/// Recording changes of the Account as log entries.
+pub mod tracking;
+
+/// Applying log entries to an Account.
+pub mod replay;
+
+/// Resolving conflicting changes of different devices.
+pub mod conflict;
+
+/// Synchronizing an Account with the shared log.
+pub mod sync;
+
 #[cfg(test)]
 mod tests {
 
@@ -35,7 +56,190 @@ mod tests {
     use std::error::Error;
     use std::fs;
     use storage::{load, save};
+    use storage::{Backend, StorageError, Version};
+    use storage::backend::{content_version, update};
+    use storage::dropbox::{DropboxBackend, DropboxCredentials, HttpRequest, HttpResponse, Transport};
+    use storage::git::GitBackend;
+    use storage::memory::{Fault, FaultConfig, MemoryBackend};
+    use storage::{load_from, save_to};
+    use storage::lock::{LockError, LockInfo, RemoteLock};
+    use storage::replicated::{ReplicatedBackend, PENDING_NAME};
+    use storage::directory::DirectoryBackend;
+    use storage::cache::{CachedBackend, QueuedWrite};
+    use storage::chunks::ChunkStore;
+    use storage::log::{append_log, read_log, LogPosition, LOG_PREFIX};
+    use storage::snapshot::Snapshots;
+    use storage::scrub::{scrub, Problem, Repair};
+    use storage::asynchronous::{append_log_async, load_async, read_log_async, save_async, AsyncBackend,
+                                Blocking, Pooled};
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::sync::{Arc, Mutex};
+    use storage::retry::{Progress, RetryPolicy, RetryingBackend};
+    use storage::{Resumable, UploadSession};
+    use storage::backup::{read_index, segment_name, Sanitizer, BACKUP_PREFIX};
+    use storage::migrate::{migrate, read_redirect};
+    use storage::imap::ImapBackend;
+    use clock::{HybridClock, Timestamp};
+    use tracking::{compact_local_log, merge_remote_log, TrackedAccount};
+    use sync::{merge_shared_log, SyncError, Synchronizer};
+    use replay::{rebuild, Replay, ReplayError};
+    use storage::cursor::{load_cursor, read_cursors, save_cursor};
+    use conflict::{ConflictPolicy, ConflictQueue};
+    use sync::SyncSummary;
+    use quickcheck::quickcheck;
+    use domain::EventData;
+    #[cfg(feature = "sqlite")]
+    use storage::sqlite::{LocalLog, SqliteStore};
+    use std::collections::{BTreeSet, HashMap};
+    use sodiumoxide::crypto::secretbox;
+    use std::io::{BufRead, Read};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use storage::repository::{encode_manifest, Repository, RepositoryError, RepositoryManifest, DEVICES_NAME,
+                              FORMAT_VERSION, KEYS_PREFIX, REPOSITORY_NAME};
+    use std::time;
+    use storage::stream::{load_stream, read_entries, save_stream, write_entries, EncryptWriter,
+                          SEGMENT_SIZE};
+    use domain::{Account, EntryType, EventLogEntry};
+    use std::process::Command;
+    use std::env;
+    use uuid::Uuid;
     use rustc_serialize::json;
+    use rustc_serialize::json::Json;
+    use std::collections::VecDeque;
+    use chrono::UTC;
+
+    /// Transport replaying recorded Dropbox responses and remembering the requests it got.
+    struct MockTransport {
+        responses: VecDeque<HttpResponse>,
+        requests: Vec<HttpRequest>,
+    }
+
+    impl MockTransport {
+        fn new(responses: Vec<HttpResponse>) -> MockTransport {
+            MockTransport {
+                responses: responses.into_iter().collect(),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse, StorageError> {
+            self.requests.push(request.clone());
+            match self.responses.pop_front() {
+                Some(r) => Ok(r),
+                None => panic!("Unexpected request to {}", request.url),
+            }
+        }
+    }
+
+    /// Messages of the folders of FakeImapServer: UID, message and whether it is \Deleted.
+    type Folders = Arc<Mutex<HashMap<String, Vec<(u64, Vec<u8>, bool)>>>>;
+
+    /// Local IMAP server understanding the commands ImapBackend sends, for user "user" with
+    /// password "secret". Every connection is served by a thread of its own.
+    fn imap_server() -> (SocketAddr, Folders) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let folders = Arc::new(Mutex::new(HashMap::new()));
+        let shared = folders.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let folders = shared.clone();
+                thread::spawn(move || serve_imap(stream.unwrap(), folders));
+            }
+        });
+        (addr, folders)
+    }
+
+    fn serve_imap(stream: TcpStream, folders: Folders) {
+        stream.set_nodelay(true).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut out = stream;
+        let mut selected = String::new();
+        write!(out, "* OK Fake IMAP ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let parts = line.trim_right().splitn(3, ' ').map(|p| p.to_string()).collect::<Vec<_>>();
+            let (tag, command) = (&parts[0], parts[1].to_uppercase());
+            let args = parts.get(2).cloned().unwrap_or(String::new());
+            let mut folders = folders.lock().unwrap();
+            let mut status = "OK";
+            match &command[..] {
+                "LOGIN" if args != "\"user\" \"secret\"" => status = "NO",
+                "LOGIN" => (),
+                "CREATE" => {
+                    folders.insert(args.trim_matches('"').to_string(), Vec::new());
+                }
+                "SELECT" if folders.contains_key(args.trim_matches('"')) => {
+                    selected = args.trim_matches('"').to_string();
+                    write!(out, "* OK [UIDVALIDITY 42] UIDs valid\r\n").unwrap();
+                }
+                "SELECT" => status = "NO",
+                "APPEND" => {
+                    let size = args[args.rfind('{').unwrap() + 1..args.len() - 1].parse().unwrap();
+                    write!(out, "+ Ready\r\n").unwrap();
+                    let mut message = vec![0; size];
+                    reader.read_exact(&mut message).unwrap();
+                    reader.read_line(&mut String::new()).unwrap();
+                    let messages = folders.get_mut(args[1..].split('"').next().unwrap()).unwrap();
+                    let uid = messages.last().map_or(0, |m| m.0) + 1;
+                    messages.push((uid, message, false));
+                }
+                "UID" => {
+                    let messages = folders.get_mut(&selected).unwrap();
+                    let mut args = args.splitn(3, ' ');
+                    let (op, set, items) = (args.next().unwrap(), args.next().unwrap(), args.next().unwrap());
+                    let uids = set.split(',').filter_map(|u| u.parse().ok()).collect::<Vec<u64>>();
+                    for (i, m) in messages.iter_mut().enumerate() {
+                        if set != "1:*" && !uids.contains(&m.0) {
+                            continue;
+                        }
+                        let text = String::from_utf8(m.1.clone()).unwrap();
+                        let split = text.find("\r\n\r\n").unwrap();
+                        if op == "STORE" {
+                            m.2 = true;
+                        } else if items.contains("TEXT") {
+                            let body = &text[split + 4..];
+                            write!(out, "* {} FETCH (UID {} BODY[TEXT] {{{}}}\r\n{})\r\n",
+                                   i + 1, m.0, body.len(), body).unwrap();
+                        } else {
+                            let header = text[..split].split("\r\n")
+                                                     .filter(|l| l.starts_with("X-Cryptocontent-"))
+                                                     .map(|l| format!("{}\r\n", l))
+                                                     .collect::<String>() + "\r\n";
+                            let flags = if m.2 { "\\Seen \\Deleted" } else { "\\Seen" };
+                            let section = &items[items.find("BODY.PEEK[").unwrap() + 10..items.len() - 1];
+                            write!(out, "* {} FETCH (UID {} FLAGS ({}) BODY[{} {{{}}}\r\n{})\r\n",
+                                   i + 1, m.0, flags, section, header.len(), header).unwrap();
+                        }
+                    }
+                }
+                "EXPUNGE" => {
+                    folders.get_mut(&selected).unwrap().retain(|m| !m.2);
+                }
+                "LOGOUT" => write!(out, "* BYE Logging out\r\n").unwrap(),
+                _ => status = "BAD",
+            }
+            write!(out, "{} {} Done\r\n", tag, status).unwrap();
+        }
+    }
+
+    fn dropbox_credentials() -> DropboxCredentials {
+        DropboxCredentials {
+            app_key: "key".to_string(),
+            app_secret: "secret".to_string(),
+            access_token: "old".to_string(),
+            refresh_token: "refresh".to_string(),
+            expires_at: Some(UTC::now()),
+        }
+    }
 
     #[test]
     fn test_calendar() {
@@ -236,4 +440,1786 @@ mod tests {
         assert_eq!(cal, loadedcal);
         fs::remove_file("test_file4.json").unwrap();
     }
+
+    #[test]
+    fn test_dropbox_refresh_upload_download() {
+        let mut download = HttpResponse::new(200, b"ciphertext");
+        download.headers.push(("Dropbox-API-Result".to_string(),
+                               r#"{"name": "log", "rev": "a2"}"#.to_string()));
+        let mut transport = MockTransport::new(vec![
+            HttpResponse::new(200, br#"{"access_token": "new", "expires_in": 14400}"#),
+            HttpResponse::new(200, br#"{"name": "log", "rev": "a1"}"#),
+            HttpResponse::new(401, br#"{"error_summary": "expired_access_token/"}"#),
+            HttpResponse::new(200, br#"{"access_token": "newer", "expires_in": 14400}"#),
+            download,
+        ]);
+
+        {
+            let mut db = DropboxBackend::new(&mut transport, dropbox_credentials(), "/cc/");
+            db.put("log", b"ciphertext").unwrap();
+            assert_eq!(db.rev("log"), Some("a1"));
+            assert_eq!(db.get("log").unwrap(), b"ciphertext".to_vec());
+            assert_eq!(db.rev("log"), Some("a2"));
+            assert_eq!(db.credentials().access_token, "newer");
+        }
+
+        let r = &transport.requests;
+        assert_eq!(r.len(), 5);
+        assert!(r[0].url.ends_with("/oauth2/token"));
+        assert!(String::from_utf8_lossy(&r[0].body).contains("refresh_token=refresh"));
+        assert!(r[1].url.ends_with("/files/upload"));
+        assert_eq!(r[1].header("Authorization"), Some("Bearer new"));
+        assert!(r[1].header("Dropbox-API-Arg").unwrap().contains(r#""path":"/cc/log""#));
+        assert_eq!(r[1].body, b"ciphertext".to_vec());
+        assert_eq!(r[4].header("Authorization"), Some("Bearer newer"));
+    }
+
+    #[test]
+    fn test_dropbox_list_sessions_conflict() {
+        let mut creds = dropbox_credentials();
+        creds.expires_at = None;
+        let mut transport = MockTransport::new(vec![
+            HttpResponse::new(200, br#"{"entries": [{".tag": "file", "path_display": "/cc/log", "rev": "1"},
+                                                     {".tag": "folder", "path_display": "/cc/objects"}],
+                                         "cursor": "c1", "has_more": true}"#),
+            HttpResponse::new(200, r#"{"entries": [{".tag": "file", "path_display": "/cc/objects/ä", "rev": "2"}],
+                                        "cursor": "c2", "has_more": false}"#.as_bytes()),
+            HttpResponse::new(200, br#"{"session_id": "s1"}"#),
+            HttpResponse::new(200, b"null"),
+            HttpResponse::new(200, r#"{"name": "ä", "rev": "3"}"#.as_bytes()),
+            HttpResponse::new(409, br#"{"error_summary": "path/conflict/file/.."}"#),
+        ]);
+
+        {
+            let mut db = DropboxBackend::new(&mut transport, creds, "/cc");
+            assert_eq!(db.list("").unwrap(), vec!["log".to_string(), "objects/ä".to_string()]);
+            assert_eq!(db.rev("objects/ä"), Some("2"));
+
+            db.set_chunk_size(4);
+            db.put("objects/ä", b"01234567").unwrap();
+            assert_eq!(db.rev("objects/ä"), Some("3"));
+
+            match db.put_if_rev("log", b"x", Some("0")) {
+                Err(StorageError::Conflict(_)) => (),
+                r => panic!("Expected conflict, got {:?}", r),
+            }
+        }
+
+        let r = &transport.requests;
+        assert!(r[1].url.ends_with("/files/list_folder/continue"));
+        assert!(r[2].url.ends_with("/files/upload_session/start"));
+        assert_eq!(r[2].body, b"0123".to_vec());
+        assert!(r[3].url.ends_with("/files/upload_session/append_v2"));
+        assert_eq!(r[3].body, b"4567".to_vec());
+        let finish = r[4].header("Dropbox-API-Arg").unwrap();
+        assert!(finish.contains(r#""offset":8"#));
+        assert!(finish.contains(r#""path":"/cc/objects/\u00e4""#));
+        assert!(r[5].header("Dropbox-API-Arg").unwrap().contains(r#""update":"0""#));
+    }
+
+    #[test]
+    fn test_git_backend() {
+        let dir = env::temp_dir().join(format!("cryptocontent-{}", Uuid::new_v4()));
+        let remote = dir.join("remote.git");
+        fs::create_dir_all(&remote).unwrap();
+        assert!(Command::new("git").arg("init").arg("--quiet").arg("--bare").arg(&remote)
+                    .status().unwrap().success());
+        let remote = remote.to_str().unwrap();
+
+        let mut a = GitBackend::open(&dir.join("a"), remote).unwrap();
+        let mut b = GitBackend::open(&dir.join("b"), remote).unwrap();
+
+        a.put("log", b"first").unwrap();
+        a.put("objects/1", b"object").unwrap();
+        assert!(a.commit("sync 1").unwrap().is_some());
+        assert_eq!(a.commit("sync 2").unwrap(), None);
+
+        b.pull().unwrap();
+        assert_eq!(b.get("log").unwrap(), b"first".to_vec());
+        assert_eq!(b.list("objects/").unwrap(), vec!["objects/1".to_string()]);
+
+        // Both devices change the log, the second push has to be rejected.
+        a.put("log", b"second").unwrap();
+        a.commit("sync 3").unwrap();
+        b.put("log", b"other").unwrap();
+        b.delete("objects/1").unwrap();
+        assert_eq!(b.list("").unwrap(), vec!["log".to_string()]);
+        match b.commit("sync 4") {
+            Err(StorageError::Conflict(_)) => (),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+
+        b.reset_to_remote().unwrap();
+        assert_eq!(b.get("log").unwrap(), b"second".to_vec());
+        assert_eq!(b.get("objects/1").unwrap(), b"object".to_vec());
+
+        let history = b.history("log").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].message, "sync 3");
+        assert_eq!(b.get_at("log", &history[1].id).unwrap(), b"first".to_vec());
+        assert!(b.get("../escape").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_backend_faults() {
+        let cal = Calendar::new("TestCalendar", "This is a test instance for calendar", true);
+        let mut cm = CryptoManager::new();
+        let mut mem = MemoryBackend::new();
+        let mut other = mem.clone();
+
+        save_to(&mut mem, "cal", &mut cm, &cal).unwrap();
+        let loaded: Calendar = load_from(&mut other, "cal", &cm).unwrap();
+        assert_eq!(cal, loaded);
+
+        mem.inject(Fault::PartialWrite);
+        save_to(&mut mem, "cal", &mut cm, &cal).unwrap();
+        match load_from::<_, Calendar>(&mut mem, "cal", &cm) {
+            Err(StorageError::Corrupt(_)) => (),
+            r => panic!("Expected corrupt object, got {:?}", r),
+        }
+
+        mem.put("log", b"1").unwrap();
+        mem.put("log", b"2").unwrap();
+        mem.inject(Fault::StaleRead);
+        mem.inject(Fault::LostUpdate);
+        assert_eq!(mem.get("log").unwrap(), b"1".to_vec());
+        mem.put("log", b"3").unwrap();
+        assert_eq!(mem.get("log").unwrap(), b"2".to_vec());
+
+        mem.inject(Fault::Transient);
+        match mem.delete("log") {
+            Err(StorageError::Unavailable(_)) => (),
+            r => panic!("Expected transient error, got {:?}", r),
+        }
+        assert_eq!(mem.injected(),
+                   &[Fault::PartialWrite, Fault::StaleRead, Fault::LostUpdate, Fault::Transient]);
+        mem.delete("log").unwrap();
+        assert!(other.get("log").is_err());
+
+        // Failed conditional writes of new objects leave nothing behind.
+        match mem.put_if("missing", b"1", Some(&Version("0".to_string()))) {
+            Err(StorageError::Conflict(_)) => (),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+        mem.inject(Fault::LostUpdate);
+        mem.put_if("lost", b"1", None).unwrap();
+        for name in ["missing", "lost"].iter() {
+            match mem.get(name) {
+                Err(StorageError::NotFound(_)) => (),
+                r => panic!("Expected missing object, got {:?}", r),
+            }
+        }
+        assert!(mem.list("").unwrap().iter().all(|n| n != "missing" && n != "lost"));
+        assert_eq!(mem.put_if("missing", b"1", None).unwrap(), Version("0".to_string()));
+
+        // Random faults are the same for the same seed.
+        let config = FaultConfig {
+            seed: 42,
+            transient: 0.3,
+            reordered_listing: 0.5,
+            ..FaultConfig::default()
+        };
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let mut faulty = mem.clone();
+            faulty.set_faults(config.clone());
+            for i in 0..20 {
+                let _ = faulty.put(&format!("objects/{}", i), b"x");
+            }
+            for _ in 0..10 {
+                let _ = faulty.list("objects/");
+            }
+            results.push(faulty.injected().to_vec());
+        }
+        assert_eq!(results[0], results[1]);
+        assert!(results[0].contains(&Fault::Transient));
+        assert!(results[0].contains(&Fault::ReorderedListing));
+    }
+
+    #[test]
+    fn test_remote_lock() {
+        let mut mem = MemoryBackend::new();
+        let mut a = RemoteLock::new("device-a", Duration::minutes(5));
+        let mut b = RemoteLock::new("device-b", Duration::minutes(5));
+
+        let info = a.acquire(&mut mem).unwrap();
+        assert!(!a.needs_renewal());
+        match b.acquire(&mut mem) {
+            Err(LockError::Held(ref h)) if h.holder == "device-a" => (),
+            r => panic!("Expected lock held by a, got {:?}", r),
+        }
+        assert_eq!(b.holder(&mut mem).unwrap().unwrap().holder, "device-a");
+
+        let renewed = a.renew(&mut mem).unwrap();
+        assert_eq!(renewed.token, info.token);
+        assert!(renewed.expires >= info.expires);
+        a.release(&mut mem).unwrap();
+        assert_eq!(a.holder(&mut mem).unwrap(), None);
+        b.acquire(&mut mem).unwrap();
+
+        // Device c crashed long ago while holding the lock.
+        let stale = LockInfo {
+            holder: "device-c".to_string(),
+            token: "t".to_string(),
+            acquired: UTC::now() - Duration::hours(2),
+            expires: UTC::now() - Duration::hours(1),
+        };
+        mem.put("lock", json::encode(&stale).unwrap().as_bytes()).unwrap();
+        a.acquire(&mut mem).unwrap();
+        assert_eq!(a.broken(), Some(&stale));
+
+        // b still thinks it holds the lock, but it is gone.
+        match b.renew(&mut mem) {
+            Err(LockError::Lost(Some(ref h))) if h.holder == "device-a" => (),
+            r => panic!("Expected lost lock, got {:?}", r),
+        }
+        assert!(b.release(&mut mem).is_err());
+        assert_eq!(a.holder(&mut mem).unwrap().unwrap().holder, "device-a");
+
+        // Releasing only replaces the lock that was read, not one taken over right after.
+        let mut d = RemoteLock::new("device-d", Duration::minutes(5));
+        d.set_grace(Duration::minutes(-10));
+        d.acquire(&mut mem).unwrap();
+        mem.inject(Fault::StaleRead);
+        match a.release(&mut mem) {
+            Err(LockError::Lost(Some(ref h))) if h.holder == "device-d" => (),
+            r => panic!("Expected lost lock, got {:?}", r),
+        }
+        assert_eq!(a.holder(&mut mem).unwrap().unwrap().holder, "device-d");
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut a = MemoryBackend::new();
+        let mut b = a.clone();
+
+        let v1 = a.put_if("log", b"1", None).unwrap();
+        match b.put_if("log", b"other", None) {
+            Err(StorageError::Conflict(_)) => (),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+        let (data, version) = b.get_versioned("log").unwrap();
+        assert_eq!((data, &version), (b"1".to_vec(), &v1));
+
+        let v2 = a.put_if("log", b"2", Some(&v1)).unwrap();
+        assert!(v2 != v1);
+        assert!(b.put_if("log", b"3", Some(&v1)).is_err());
+        assert!(b.put_if("log", b"3", Some(&Version("unknown".to_string()))).is_err());
+
+        // The update is retried after b changes the log in between.
+        let mut calls = 0;
+        update(&mut a, "log", 3, |current| {
+            calls += 1;
+            if calls == 1 {
+                b.put("log", b"changed").unwrap();
+            }
+            let mut data = current.unwrap().to_vec();
+            data.push(b'+');
+            Ok(data)
+        }).unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(a.get("log").unwrap(), b"changed+".to_vec());
+    }
+
+    #[test]
+    fn test_replicated_backend() {
+        let (a, b, c) = (MemoryBackend::new(), MemoryBackend::new(), MemoryBackend::new());
+        let mut rep = ReplicatedBackend::new(Arc::new(Mutex::new(CryptoManager::new())));
+        rep.add("a", a.clone());
+        rep.add("b", b.clone());
+        rep.add("c", c.clone());
+
+        rep.put("log", b"1").unwrap();
+        rep.put("old", b"old").unwrap();
+        let stale = a.raw("log").unwrap();
+        assert_eq!(stale, c.raw("log").unwrap());
+
+        // c is down, but reading and writing goes on.
+        c.set_offline(true);
+        rep.put("log", b"2").unwrap();
+        rep.put("new", b"new").unwrap();
+        rep.delete("old").unwrap();
+        assert_eq!(rep.get("log").unwrap(), b"2".to_vec());
+        assert_eq!(rep.list("").unwrap(), vec!["log".to_string(), "new".to_string()]);
+        let status = rep.status();
+        assert!(status[0].healthy && !status[2].healthy);
+        assert_eq!(status[2].pending, 3);
+
+        c.set_offline(false);
+        assert_eq!(rep.repair().unwrap(), 3);
+        assert!(rep.status()[2].healthy);
+        assert_eq!(c.raw("log"), a.raw("log"));
+        assert_eq!(c.raw("new"), a.raw("new"));
+        assert_eq!(c.raw("old"), None);
+
+        // A lagging replica is repaired when reading.
+        let mut b2 = b.clone();
+        b2.put("log", &stale).unwrap();
+        let (data, version) = rep.get_versioned("log").unwrap();
+        assert_eq!(data, b"2".to_vec());
+        assert_eq!(b.raw("log"), a.raw("log"));
+
+        rep.put_if("log", b"3", Some(&version)).unwrap();
+        assert!(rep.put_if("log", b"4", Some(&version)).is_err());
+        assert_eq!(rep.get("log").unwrap(), b"3".to_vec());
+    }
+
+    #[test]
+    fn test_cached_backend() {
+        let dir = env::temp_dir().join(format!("cryptocontent-{}", Uuid::new_v4()));
+        let remote = MemoryBackend::new();
+        let cm = Arc::new(Mutex::new(CryptoManager::new()));
+        let mut other = remote.clone();
+        other.put("log", b"1").unwrap();
+        other.put("objects/1", b"object").unwrap();
+        let version = other.version("objects/1").unwrap();
+
+        let mut cache = CachedBackend::open(remote.clone(), DirectoryBackend::new(&dir).unwrap(), cm.clone())
+                            .unwrap();
+        assert_eq!(cache.get("log").unwrap(), b"1".to_vec());
+        assert_eq!(cache.get("objects/1").unwrap(), b"object".to_vec());
+
+        // Changes of other devices are noticed when revalidating.
+        other.put("log", b"2").unwrap();
+        assert_eq!(cache.get("log").unwrap(), b"2".to_vec());
+
+        // Offline, reads are served from the cache and writes are queued.
+        remote.set_offline(true);
+        assert_eq!(cache.get("log").unwrap(), b"2".to_vec());
+        cache.put("objects/2", b"new").unwrap();
+        cache.delete("objects/1").unwrap();
+        assert!(!cache.is_online());
+        assert_eq!(cache.get("objects/2").unwrap(), b"new".to_vec());
+        assert_eq!(cache.list("objects/").unwrap(), vec!["objects/2".to_string()]);
+        match cache.put_if("log", b"3", None) {
+            Err(StorageError::Unavailable(_)) => (),
+            r => panic!("Expected unavailable, got {:?}", r),
+        }
+        assert_eq!(remote.raw("objects/2"), None);
+
+        // The queue survives a restart and is sent once the remote is back.
+        drop(cache);
+        let index = DirectoryBackend::new(&dir).unwrap().get("index").unwrap();
+        assert!(cm.lock().unwrap().decrypt_bytes(index).is_some());
+        let mut cache = CachedBackend::open(remote.clone(), DirectoryBackend::new(&dir).unwrap(), cm.clone())
+                            .unwrap();
+        assert_eq!(cache.queued(),
+                   &[QueuedWrite::Put("objects/2".to_string(), None),
+                     QueuedWrite::Delete("objects/1".to_string(), Some(version))]);
+        remote.set_offline(false);
+        assert_eq!(cache.list("").unwrap(), vec!["log".to_string(), "objects/2".to_string()]);
+        assert!(cache.is_online() && cache.queued().is_empty());
+        assert_eq!(remote.raw("objects/2"), Some(b"new".to_vec()));
+        assert_eq!(remote.raw("objects/1"), None);
+
+        // A queued write doesn't replace a change made elsewhere in the meantime.
+        remote.set_offline(true);
+        cache.put("log", b"mine").unwrap();
+        remote.set_offline(false);
+        other.put("log", b"theirs").unwrap();
+        match cache.flush() {
+            Err(StorageError::Conflict(_)) => (),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+        assert_eq!(cache.queued().len(), 1);
+        cache.discard("log").unwrap();
+        assert_eq!(cache.get("log").unwrap(), b"theirs".to_vec());
+        other.put("log", b"2").unwrap();
+
+        // Objects deleted elsewhere disappear from the cache, old ones are evicted.
+        other.delete("objects/2").unwrap();
+        assert!(cache.get("objects/2").is_err());
+        cache.put("big", &[0; 100]).unwrap();
+        cache.get("log").unwrap();
+        cache.set_max_bytes(50).unwrap();
+        assert_eq!(cache.cached_bytes(), 1);
+        remote.set_offline(true);
+        assert_eq!(cache.get("log").unwrap(), b"2".to_vec());
+        assert!(cache.get("big").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_store() {
+        // A fixed key, so the chunk boundaries are the same in every run.
+        let mut cm = CryptoManager::new();
+        cm.symkey = secretbox::Key([7; secretbox::KEYBYTES]);
+        let backend = MemoryBackend::new();
+        let mut store = ChunkStore::new(backend.clone(), &cm);
+        store.set_chunk_sizes(256, 1024, 4096);
+
+        let mut state = 7u64;
+        let mut data = Vec::new();
+        for _ in 0..100000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.push(state as u8);
+        }
+
+        let first = store.put("attachment", &data, &mut cm).unwrap();
+        assert!(first.chunks > 20 && first.uploaded == first.chunks);
+        assert_eq!(store.get("attachment", &cm).unwrap(), data);
+
+        // Inserting a few bytes only uploads the chunks around them.
+        let mut changed = data.clone();
+        for i in 0..10 {
+            changed.insert(50000, i);
+        }
+        let second = store.put("attachment.v2", &changed, &mut cm).unwrap();
+        let old = store.manifest("attachment", &cm).unwrap().chunks;
+        let new = store.manifest("attachment.v2", &cm).unwrap().chunks;
+        assert_eq!(second.uploaded, new.iter().filter(|id| !old.contains(id)).count());
+        assert!(second.uploaded <= 2);
+        assert!(second.uploaded_bytes < 10000);
+        assert_eq!(store.get("attachment.v2", &cm).unwrap(), changed);
+        assert_eq!(store.put("copy", &data, &mut cm).unwrap().uploaded, 0);
+
+        // Chunk names don't reveal plain hashes of the content.
+        let chunks = backend.clone().list("chunks/").unwrap();
+        assert!(!chunks.iter().any(|c| c.contains(&content_version(&data[..100]).0)));
+
+        store.delete("attachment").unwrap();
+        store.delete("copy").unwrap();
+        assert_eq!(store.list().unwrap(), vec!["attachment.v2".to_string()]);
+        // gc removes exactly the chunks no manifest refers to.
+        let referenced = new.iter().map(|id| format!("chunks/{}", id)).collect::<BTreeSet<_>>();
+        let stored = backend.clone().list("chunks/").unwrap().into_iter().collect::<BTreeSet<_>>();
+        assert!(referenced.is_subset(&stored));
+        assert_eq!(store.gc(&cm).unwrap(), stored.difference(&referenced).count());
+        let stored = backend.clone().list("chunks/").unwrap().into_iter().collect::<BTreeSet<_>>();
+        assert_eq!(stored, referenced);
+        assert_eq!(store.gc(&cm).unwrap(), 0);
+        assert_eq!(store.get("attachment.v2", &cm).unwrap(), changed);
+
+        let id = &store.manifest("attachment.v2", &cm).unwrap().chunks[0];
+        backend.clone().put(&format!("chunks/{}", id), b"garbage").unwrap();
+        match store.get("attachment.v2", &cm) {
+            Err(StorageError::Corrupt(_)) => (),
+            r => panic!("Expected corrupt, got {:?}", r.map(|d| d.len())),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut cm = CryptoManager::new();
+        let mut b = MemoryBackend::new();
+        let mut good = Account::new();
+        good.items.push(Calendar::new("Work", "", true));
+        good.items.push(Calendar::new("Home", "", true));
+        let dinner = Event::new("Dinner", "", "");
+        let lunch = Event::new("Lunch", "", "");
+        good.items[1].add_event(dinner.clone());
+        good.items[1].add_event(lunch.clone());
+        let mut tracked = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        for cal in good.items.iter() {
+            tracked.add_calendar(cal.clone());
+        }
+        let created = tracked.take_logs().0;
+        let position = append_log(&mut b, &mut cm, &LogPosition::end_of(&[]), &created).unwrap();
+        assert_eq!(position, LogPosition::end_of(&created));
+
+        let mut snapshots = Snapshots::new("device-a");
+        let info = snapshots.create_if_due(&mut b, &mut cm, &good, position.clone()).unwrap().unwrap();
+        assert_eq!(snapshots.create_if_due(&mut b, &mut cm, &good, position.clone()).unwrap(), None);
+        assert_eq!(snapshots.list(&mut b, &cm).unwrap(), vec![info.clone()]);
+        assert_eq!(snapshots.load(&mut b, &cm, &info.id).unwrap(), good);
+
+        // Another device messes up the account.
+        let mut tracked = TrackedAccount::new(good.clone(), HybridClock::new("device-b"));
+        let work = good.items[0].id.clone();
+        let home = good.items[1].id.clone();
+        tracked.delete_calendar(&work);
+        tracked.update_calendar(&home, "Garbage", "", true);
+        tracked.add_event(&home, Event::new("Garbage", "", ""));
+        let mut moved = dinner.clone();
+        moved.start = moved.start + Duration::days(1);
+        tracked.update_event(&home, moved);
+        tracked.delete_event(&home, &lunch);
+        tracked.add_calendar(Calendar::new("Spam", "", true));
+        let messed = tracked.take_logs();
+        let after = append_log(&mut b, &mut cm, &position, &messed.1).unwrap();
+        append_log(&mut b, &mut cm, &after, &messed.0).unwrap();
+        let (bad, report) = rebuild(&read_log(&mut b, &cm).unwrap());
+        assert!(report.rejected.is_empty());
+        assert_eq!(bad.items.len(), 2);
+
+        let mut clock = HybridClock::new("device-a");
+        let entries = snapshots.restore(&mut b, &mut cm, &info.id, &bad, &mut clock).unwrap();
+        let kinds = entries.iter().map(|e| (e.entry_type.clone(), e.obj_id.clone())).collect::<Vec<_>>();
+        assert_eq!(kinds[0], (EntryType::Create, work.clone()));
+        assert_eq!(kinds[1], (EntryType::Update, home.clone()));
+        assert!(kinds.contains(&(EntryType::Create, lunch.id.clone())));
+        assert!(kinds.contains(&(EntryType::Update, dinner.id.clone())));
+        assert_eq!(kinds.last().unwrap(), &(EntryType::Delete, bad.items[1].id.clone()));
+        assert_eq!(entries.len(), 6);
+        let latest = messed.1.iter().filter_map(|e| e.timestamp.as_ref()).max().unwrap();
+        assert!(entries.iter().all(|e| e.timestamp.as_ref().map_or(false, |ts| ts > latest)));
+
+        // Replaying the log gives the state of the snapshot again.
+        let log = read_log(&mut b, &cm).unwrap();
+        assert_eq!(&log[log.len() - entries.len()..], &entries[..]);
+        let (restored, report) = rebuild(&log);
+        assert!(report.rejected.is_empty());
+        let state = |account: &Account| {
+            let mut cals = account.items
+                                  .iter()
+                                  .map(|c| {
+                                      let mut events = c.get_events().into_iter().cloned().collect::<Vec<_>>();
+                                      events.sort_by(|a, b| a.id.cmp(&b.id));
+                                      (c.id.clone(), c.name.clone(), events)
+                                  })
+                                  .collect::<Vec<_>>();
+            cals.sort_by(|a, b| a.0.cmp(&b.0));
+            cals
+        };
+        assert_eq!(state(&restored), state(&good));
+        assert!(snapshots.restore(&mut b, &mut cm, &info.id, &restored, &mut clock).unwrap().is_empty());
+
+        // Only the newest snapshots are kept.
+        snapshots.set_interval(Duration::zero());
+        snapshots.set_keep(1);
+        let newer = snapshots.create_if_due(&mut b, &mut cm, &good, LogPosition::end_of(&log))
+                             .unwrap()
+                             .unwrap();
+        assert_eq!(snapshots.list(&mut b, &cm).unwrap(), vec![newer]);
+        assert!(snapshots.load(&mut b, &cm, &info.id).is_err());
+    }
+
+    #[test]
+    fn test_scrub() {
+        let mut cm = CryptoManager::new();
+        let mut b = MemoryBackend::new();
+        let cal = Calendar::new("Work", "", true);
+        let created = EventLogEntry::new(EntryType::Create, &cal.id, &json::encode(&cal).unwrap());
+        let position = append_log(&mut b, &mut cm, &LogPosition::end_of(&[]), &[created]).unwrap();
+        Snapshots::new("device-a").create(&mut b, &mut cm, &Account::new(), position).unwrap();
+        RemoteLock::new("device-a", Duration::minutes(5)).acquire(&mut b).unwrap();
+        {
+            let mut store = ChunkStore::new(&mut b, &cm);
+            store.set_chunk_sizes(64, 128, 256);
+            store.put("a", &[1; 1000], &mut cm).unwrap();
+            store.put("b", &(0..2000).map(|i| i as u8).collect::<Vec<_>>(), &mut cm).unwrap();
+        }
+
+        let report = scrub(&mut b, &cm).unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.entries, 1);
+
+        // Break a bit of everything.
+        let mut store = ChunkStore::new(b.clone(), &cm);
+        let a = store.manifest("a", &cm).unwrap();
+        let b_chunks = store.manifest("b", &cm).unwrap().chunks;
+        b.put(&format!("chunks/{}", a.chunks[0]), &cm.encrypt_bytes(b"other").unwrap()).unwrap();
+        b.delete(&format!("chunks/{}", b_chunks[0])).unwrap();
+        store.delete("b").unwrap();
+        b.put("notes", b"plain text").unwrap();
+        let mut log = read_log(&mut b, &cm).unwrap();
+        log.push(EventLogEntry::new(EntryType::Update, "unknown", "{}"));
+        let mut log = match Json::from_str(&json::encode(&log).unwrap()).unwrap() {
+            Json::Array(l) => l,
+            _ => unreachable!(),
+        };
+        log.push(Json::from_str(r#"{"bogus": 1}"#).unwrap());
+        b.put("log/0", &cm.encrypt(&Json::Array(log).to_string()).unwrap()).unwrap();
+
+        let report = scrub(&mut b, &cm).unwrap();
+        assert_eq!(report.entries, 3);
+        let corrupt = report.with_problem(Problem::Corrupt);
+        assert_eq!(corrupt.len(), 3);
+        assert_eq!(corrupt[0].repair, Repair::Reupload);
+        assert!(corrupt.iter().any(|f| f.name == "notes" && f.repair == Repair::Delete));
+        assert!(corrupt.iter().any(|f| f.repair == Repair::DropLogEntry(2)));
+        let missing = report.with_problem(Problem::Missing);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].repair, Repair::DropLogEntry(1));
+        let orphaned = report.with_problem(Problem::Orphaned);
+        let mut unique = b_chunks.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(orphaned.len(), unique.len() - 1);
+        assert!(orphaned.iter().all(|f| f.repair == Repair::CollectGarbage));
+    }
+
+    #[test]
+    fn test_stream_save_load() {
+        let cm = CryptoManager::new();
+        let mut cal = Calendar::new("Big", "Calendar with ümlauts", true);
+        let e = Event::new("Meeting", "", "Room 1");
+        cal.add_event(e.clone());
+        cal.repeat_event_n_times(&e, 2000);
+
+        let data = save_stream(Vec::new(), &cm, &cal).unwrap();
+        assert!(data.len() > 2 * SEGMENT_SIZE);
+        let loaded: Calendar = load_stream(&data[..], &cm).unwrap();
+        assert_eq!(loaded, cal);
+        let mut account = Account::new();
+        account.items.push(cal.clone());
+        account.items.push(Calendar::new("Empty", "", false));
+        let data = save_stream(Vec::new(), &cm, &account).unwrap();
+        assert_eq!(load_stream::<_, Account>(&data[..], &cm).unwrap(), account);
+        // Missing fields and data after the value don't decode.
+        for text in vec![r#"{"id":"1","name":"","desc":"","sync":true}"#,
+                         r#"{"id":"1","name":"","desc":"","sync":true,"days":{}} []"#] {
+            let mut enc = EncryptWriter::new(Vec::new(), &cm).unwrap();
+            enc.write_all(text.as_bytes()).unwrap();
+            let data = enc.finish().unwrap();
+            assert!(load_stream::<_, Calendar>(&data[..], &cm).is_err());
+        }
+        let data = save_stream(Vec::new(), &cm, &cal).unwrap();
+
+        // Cut off, reordered or changed streams are rejected.
+        for broken in vec![data[..data.len() - 10].to_vec(),
+                           data[..28 + 4 + SEGMENT_SIZE + 16].to_vec(),
+                           { let mut d = data.clone(); d[100] ^= 1; d }] {
+            match load_stream::<_, Calendar>(&broken[..], &cm) {
+                Err(StorageError::Corrupt(_)) => (),
+                r => panic!("Expected corrupt, got {:?}", r.map(|c| c.name)),
+            }
+        }
+
+        let entries = (0..3000)
+                          .map(|i| EventLogEntry::new(EntryType::Update, &cal.id, &format!(r#"{{"n":"{},]\"}}"#, i)))
+                          .collect::<Vec<_>>();
+        let data = write_entries(Vec::new(), &cm, &entries).unwrap();
+        let read = read_entries(&data[..], &cm).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read, entries);
+        let loaded: Vec<EventLogEntry> = load_stream(&data[..], &cm).unwrap();
+        assert_eq!(loaded.len(), 3000);
+        assert_eq!(read_entries(&write_entries(Vec::new(), &cm, &[]).unwrap()[..], &cm).count(), 0);
+        assert!(read_entries(&data[..data.len() - 1], &cm).any(|e| e.is_err()));
+    }
+
+    #[test]
+    fn test_async_backend() {
+        let pool = CpuPool::new(4);
+        let memory = MemoryBackend::new();
+        let a = Pooled::new(memory.clone(), pool.clone());
+        let b = Pooled::new(memory.clone(), pool.clone());
+        let cm = Arc::new(Mutex::new(CryptoManager::new()));
+
+        let version = a.put_if("object", b"1".to_vec(), None).wait().unwrap();
+        assert_eq!(b.get_versioned("object").wait().unwrap(), (b"1".to_vec(), version.clone()));
+        assert!(b.put_if("object", b"2".to_vec(), None).wait().is_err());
+
+        let cal = Calendar::new("Async", "", true);
+        save_async(&a, "calendar", &cm, &cal).wait().unwrap();
+        let loaded: Calendar = load_async(&b, "calendar", cm.clone()).wait().unwrap();
+        assert_eq!(loaded, cal);
+
+        // Two devices appending at the same time both end up in the log.
+        let start = LogPosition::end_of(&[]);
+        let first = append_log_async(&a, cm.clone(), &start, vec![EventLogEntry::new(EntryType::Create, "1", "{}")]);
+        let second = append_log_async(&b, cm.clone(), &start, vec![EventLogEntry::new(EntryType::Create, "2", "{}")]);
+        let (p1, p2) = first.join(second).wait().unwrap();
+        assert_eq!((p1.index, p2.index), (1, 1));
+        // The futures can run on another thread.
+        let log = CpuPool::new(1).spawn(read_log_async(&a, cm.clone())).wait().unwrap();
+        assert_eq!(log.len(), 2);
+
+        // Blocking code runs on the pool or on top of the futures.
+        let lock = a.with_backend(|b| {
+                        RemoteLock::new("device-a", Duration::minutes(5))
+                            .acquire(b)
+                            .map_err(|e| StorageError::Protocol(format!("{}", e)))
+                    })
+                    .wait()
+                    .unwrap();
+        let mut blocking = Blocking::new(b.clone());
+        let mut other = RemoteLock::new("device-b", Duration::minutes(5));
+        match other.acquire(&mut blocking) {
+            Err(LockError::Held(info)) => assert_eq!(info, lock),
+            r => panic!("Expected held lock, got {:?}", r),
+        }
+        assert_eq!(blocking.list("").unwrap(),
+                   vec!["calendar", "lock", "log/0", "log/1", "object"]);
+    }
+
+    #[test]
+    fn test_retry_and_resumable_upload() {
+        let policy = RetryPolicy {
+            attempts: 4,
+            initial_delay: time::Duration::from_millis(100),
+            max_delay: time::Duration::from_millis(300),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(3, 0.5), time::Duration::from_millis(300));
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let memory = MemoryBackend::new();
+        let mut b = RetryingBackend::new(memory.clone(), policy);
+        let w = waits.clone();
+        b.set_sleep(move |d| w.lock().unwrap().push(d));
+
+        b.inner().inject(Fault::Transient);
+        b.inner().inject(Fault::Transient);
+        b.put("log", b"1").unwrap();
+        assert_eq!(b.retries(), 2);
+        assert_eq!(*waits.lock().unwrap(),
+                   vec![time::Duration::from_millis(100), time::Duration::from_millis(200)]);
+        for _ in 0..4 {
+            b.inner().inject(Fault::Transient);
+        }
+        assert!(b.get("log").is_err());
+        b.get("log").unwrap();
+        match b.put_if("log", b"2", None) {
+            Err(StorageError::Conflict(_)) => assert_eq!(b.retries(), 5),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+
+        // Failed parts are resent from where the provider got to.
+        let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        b.set_part_size(100);
+        b.inner().inject(Fault::PartialWrite);
+        b.inner().inject(Fault::PartialWrite);
+        b.inner().inject(Fault::LostUpdate);
+        let mut reports = Vec::new();
+        b.upload("attachment", &data, &mut |p: Progress| reports.push(p)).unwrap();
+        assert_eq!(memory.raw("attachment"), Some(data.clone()));
+        assert!(reports.windows(2).all(|w| w[0].sent <= w[1].sent));
+        assert_eq!(reports.last().unwrap().sent, 1000);
+        assert_eq!(reports.last().unwrap().retries, 8);
+
+        // An upload that keeps failing can be continued later.
+        let mut session = b.inner().start_upload("big").unwrap();
+        b.inner().upload_part(&mut session, &data[..300]).unwrap();
+        let saved = json::encode(&session).unwrap();
+        memory.set_offline(true);
+        assert!(b.continue_upload(&mut session, &data, &mut |_| ()).is_err());
+        memory.set_offline(false);
+        let mut session: UploadSession = json::decode(&saved).unwrap();
+        let mut sent = Vec::new();
+        b.continue_upload(&mut session, &data, &mut |p: Progress| sent.push(p.sent)).unwrap();
+        assert_eq!(sent, vec![300, 400, 500, 600, 700, 800, 900, 1000]);
+        assert_eq!(memory.raw("big"), Some(data.clone()));
+
+        // Dropbox tells the right offset if a part arrived but the answer got lost.
+        let mut transport = MockTransport::new(vec![
+            HttpResponse::new(200, br#"{"access_token": "new", "expires_in": 14400}"#),
+            HttpResponse::new(200, br#"{"session_id": "s1"}"#),
+            HttpResponse::new(409, br#"{"error_summary": "incorrect_offset/..", "error": {".tag": "incorrect_offset", "correct_offset": 4}}"#),
+            HttpResponse::new(200, b"null"),
+            HttpResponse::new(200, br#"{"name": "big", "rev": "r1"}"#)]);
+        {
+            let mut dropbox = DropboxBackend::new(&mut transport, dropbox_credentials(), "/cc");
+            let mut session = dropbox.start_upload("big").unwrap();
+            dropbox.upload_part(&mut session, b"abcd").unwrap();
+            assert_eq!(session.offset, 4);
+            dropbox.upload_part(&mut session, b"efgh").unwrap();
+            assert_eq!(dropbox.finish_upload(&session).unwrap(), Version("r1".to_string()));
+        }
+        let finish = transport.requests[4].header("Dropbox-API-Arg").unwrap();
+        assert!(finish.contains(r#""offset":8"#) && finish.contains(r#""session_id":"s1""#));
+    }
+
+    #[test]
+    fn test_sanitize_backup_log() {
+        let mut cm = CryptoManager::new();
+        let mut b = MemoryBackend::new();
+        let entries = (0..12)
+                          .map(|i| EventLogEntry::new(EntryType::Create, &i.to_string(), ""))
+                          .collect::<Vec<_>>();
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&[]), &entries[..4]).unwrap();
+        let last_sync = UTC::now();
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&entries[..4]), &entries[4..7]).unwrap();
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&entries[..7]), &entries[7..10]).unwrap();
+        // Appending only lists the segments.
+        assert!(b.reads().is_empty());
+
+        let mut sanitizer = Sanitizer::new();
+        sanitizer.set_keep(3);
+        // The log isn't changed while another device holds the lock.
+        let mut other = RemoteLock::new("device-b", Duration::minutes(5));
+        other.acquire(&mut b).unwrap();
+        let mut lock = RemoteLock::new("device-a", Duration::minutes(5));
+        match sanitizer.sanitize_if_due(&mut b, &mut cm, &mut lock) {
+            Err(LockError::Held(ref h)) if h.holder == "device-b" => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        other.release(&mut b).unwrap();
+        let segment = sanitizer.sanitize_if_due(&mut b, &mut cm, &mut lock).unwrap().unwrap();
+        assert_eq!((segment.start, segment.count), (0, 7));
+        assert_eq!(sanitizer.sanitize_if_due(&mut b, &mut cm, &mut lock).unwrap(), None);
+        assert_eq!(read_log(&mut b, &cm).unwrap(), &entries[7..10]);
+        let index = read_index(&mut b, &cm).unwrap();
+        assert_eq!(index.boundary(), Some(segment.archived));
+        assert!(last_sync < segment.archived);
+
+        // A device that synchronized before the sanitation reads the segment archived since.
+        assert_eq!(index.archived_since(Some(last_sync)), vec![segment.clone()]);
+        assert_eq!(index.archived_since(None), vec![segment.clone()]);
+        assert!(index.archived_since(Some(UTC::now())).is_empty());
+
+        // Whole segments are moved, the newest one always stays.
+        sanitizer.set_interval(Duration::zero());
+        sanitizer.set_keep(1);
+        assert_eq!(sanitizer.sanitize_if_due(&mut b, &mut cm, &mut lock).unwrap(), None);
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&entries[..10]), &entries[10..]).unwrap();
+        let between = UTC::now();
+        let newer = sanitizer.sanitize_if_due(&mut b, &mut cm, &mut lock).unwrap().unwrap();
+        assert_eq!((newer.start, newer.count), (7, 3));
+        let index = read_index(&mut b, &cm).unwrap();
+        assert_eq!(index.archived(), 10);
+        assert_eq!(index.archived_since(Some(between)), vec![newer]);
+        assert_eq!(read_log(&mut b, &cm).unwrap(), &entries[10..]);
+        assert_eq!(lock.holder(&mut b).unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut cm = CryptoManager::new();
+        let mut old = MemoryBackend::new();
+        let entry = EventLogEntry::new(EntryType::Create, "1", "");
+        let position = append_log(&mut old, &mut cm, &LogPosition::end_of(&[]), &[entry.clone()]).unwrap();
+        Snapshots::new("device-a").create(&mut old, &mut cm, &Account::new(), position).unwrap();
+        ChunkStore::new(&mut old, &cm).put("attachment", &[7; 5000], &mut cm).unwrap();
+        RemoteLock::new("device-a", Duration::minutes(5)).acquire(&mut old).unwrap();
+        // Only device b can open the key wrapped for it.
+        old.put(DEVICES_NAME, b"device-a device-b").unwrap();
+        old.put(&format!("{}device-b", KEYS_PREFIX), &[3; 72]).unwrap();
+
+        // A copy that doesn't verify leaves no redirect.
+        for &fault in [Fault::LostUpdate, Fault::PartialWrite].iter() {
+            let mut broken = MemoryBackend::new();
+            broken.inject(fault);
+            match migrate(&mut old, &mut broken, &mut cm, "broken") {
+                Err(StorageError::Corrupt(_)) => (),
+                r => panic!("Expected corrupt copy, got {:?}", r),
+            }
+        }
+        assert_eq!(read_redirect(&mut old, &cm).unwrap(), None);
+
+        let mut new = MemoryBackend::new();
+        let report = migrate(&mut old, &mut new, &mut cm, "dropbox:/cc").unwrap();
+        let names = old.list("").unwrap();
+        assert_eq!(report.copied, names.len() - 2);
+        assert_eq!(report.keys, 2);
+        assert_eq!(report.decrypted, report.copied - 2);
+        assert_eq!(new.list("").unwrap().len(), report.copied);
+        assert_eq!(new.raw("keys/device-b"), old.raw("keys/device-b"));
+        assert!(new.get("lock").is_err());
+        assert_eq!(read_log(&mut new, &cm).unwrap(), vec![entry]);
+        assert_eq!(ChunkStore::new(&mut new, &cm).get("attachment", &cm).unwrap(), vec![7; 5000]);
+
+        // Other devices find the way to the new location.
+        let redirect = read_redirect(&mut old, &cm).unwrap().unwrap();
+        assert_eq!(redirect, report.redirect);
+        assert_eq!(redirect.location, "dropbox:/cc");
+        assert_eq!(read_redirect(&mut new, &cm).unwrap(), None);
+        assert!(migrate(&mut old, &mut MemoryBackend::new(), &mut cm, "again").is_err());
+    }
+
+    #[test]
+    fn test_repository_init_open() {
+        let cm = CryptoManager::new();
+        let mut b = MemoryBackend::new();
+        match Repository::open(b.clone(), &cm) {
+            Err(RepositoryError::NotARepository) => (),
+            r => panic!("Expected no repository, got {:?}", r.map(|r| r.manifest().clone())),
+        }
+        let manifest = Repository::init(b.clone(), &cm).unwrap().manifest().clone();
+        assert_eq!(manifest.format, FORMAT_VERSION);
+        assert_eq!(manifest.layout.log, "log/");
+        assert!(Repository::init(b.clone(), &cm).is_err());
+        assert_eq!(Repository::open(b.clone(), &cm).unwrap().manifest(), &manifest);
+        assert!(scrub(&mut b, &cm).unwrap().is_clean());
+
+        // Another key or a changed manifest don't verify.
+        match Repository::open(b.clone(), &CryptoManager::new()) {
+            Err(RepositoryError::BadSignature) => (),
+            r => panic!("Expected bad signature, got {:?}", r.map(|r| r.manifest().clone())),
+        }
+        let stored = String::from_utf8(b.get(REPOSITORY_NAME).unwrap()).unwrap();
+        b.put(REPOSITORY_NAME, stored.replace("xsalsa20", "aes").as_bytes()).unwrap();
+        assert!(Repository::open(b.clone(), &cm).is_err());
+        assert_eq!(scrub(&mut b, &cm).unwrap().with_problem(Problem::Corrupt).len(), 1);
+
+        // Newer formats are refused, even if they are signed properly.
+        let mut newer = RepositoryManifest::new();
+        newer.format = FORMAT_VERSION + 1;
+        b.put(REPOSITORY_NAME, &encode_manifest(&newer, &cm)).unwrap();
+        match Repository::open(b.clone(), &cm) {
+            Err(RepositoryError::Incompatible(v)) => assert_eq!(v, FORMAT_VERSION + 1),
+            r => panic!("Expected incompatible format, got {:?}", r.map(|r| r.manifest().clone())),
+        }
+        // So are older ones, their log would go unnoticed.
+        let mut older = RepositoryManifest::new();
+        older.format = 1;
+        b.put(REPOSITORY_NAME, &encode_manifest(&older, &cm)).unwrap();
+        match Repository::open(b.clone(), &cm) {
+            Err(RepositoryError::Incompatible(v)) => assert_eq!(v, 1),
+            r => panic!("Expected incompatible format, got {:?}", r.map(|r| r.manifest().clone())),
+        }
+
+        // A migrated repository points to its new location.
+        let mut cm = cm;
+        let mut new = MemoryBackend::new();
+        let mut old = MemoryBackend::new();
+        Repository::init(old.clone(), &cm).unwrap();
+        migrate(&mut old, &mut new, &mut cm, "elsewhere").unwrap();
+        match Repository::open(old, &cm) {
+            Err(RepositoryError::Moved(r)) => assert_eq!(r.location, "elsewhere"),
+            r => panic!("Expected redirect, got {:?}", r.map(|r| r.manifest().clone())),
+        }
+        assert!(Repository::open(new, &cm).is_ok());
+    }
+
+    #[test]
+    fn test_imap_backend() {
+        let (addr, folders) = imap_server();
+        let connect = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            stream
+        };
+        assert!(ImapBackend::connect(connect(), "user", "wrong", "Cryptocontent").is_err());
+        let mut a = ImapBackend::connect(connect(), "user", "secret", "Cryptocontent").unwrap();
+        let mut b = ImapBackend::connect(connect(), "user", "secret", "Cryptocontent").unwrap();
+
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        a.put("objects/1", &data).unwrap();
+        assert_eq!(b.get("objects/1").unwrap(), data);
+        let v1 = b.put_if("log", b"1", None).unwrap();
+        assert!(a.put_if("log", b"other", None).is_err());
+        let (log, version) = a.get_versioned("log").unwrap();
+        assert_eq!((log, &version), (b"1".to_vec(), &v1));
+
+        // Replacing keeps a single message per object.
+        let v2 = a.put_if("log", b"12", Some(&v1)).unwrap();
+        match b.put_if("log", b"13", Some(&v1)) {
+            Err(StorageError::Conflict(_)) => (),
+            r => panic!("Expected conflict, got {:?}", r),
+        }
+        assert_eq!(b.get("log").unwrap(), b"12".to_vec());
+        assert_eq!(b.version("log").unwrap(), v2);
+        assert_eq!(folders.lock().unwrap()["Cryptocontent"].len(), 2);
+        assert_eq!(a.list("").unwrap(), vec!["log".to_string(), "objects/1".to_string()]);
+
+        // A message of a device that lost a race and crashed before removing it is ignored.
+        {
+            let mut folders = folders.lock().unwrap();
+            let messages = folders.get_mut("Cryptocontent").unwrap();
+            let uid = messages.last().unwrap().0 + 1;
+            let v1_uid = v1.0.split('.').nth(1).unwrap();
+            let message = format!("X-Cryptocontent-Object: log\r\nX-Cryptocontent-Replaces: {}\r\n\r\nMTQ=\r\n",
+                                  v1_uid);
+            messages.push((uid, message.into_bytes(), false));
+        }
+        assert_eq!(b.get("log").unwrap(), b"12".to_vec());
+        b.put("log", b"123").unwrap();
+        assert_eq!(a.get("log").unwrap(), b"123".to_vec());
+        assert_eq!(folders.lock().unwrap()["Cryptocontent"].len(), 2);
+
+        a.delete("objects/1").unwrap();
+        assert!(b.get("objects/1").is_err());
+        assert!(a.delete("objects/1").is_err());
+        assert_eq!(b.list("").unwrap(), vec!["log".to_string()]);
+        a.logout().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_sqlite_store() {
+        let mut cm = CryptoManager::new();
+        let path = env::temp_dir().join(format!("cryptocontent-{}.db", Uuid::new_v4()));
+        let mut account = Account::new();
+        let mut work = Calendar::new("Work", "Office", true);
+        let meeting = Event::new("Meeting", "", "Room 1");
+        let lunch = meeting.repeat(Duration::hours(3));
+        let next_week = meeting.repeat(Duration::weeks(1));
+        work.add_event(meeting.clone());
+        work.add_event(lunch.clone());
+        work.add_event(next_week.clone());
+        account.items.push(work.clone());
+        account.items.push(Calendar::new("Home", "", false));
+        {
+            let mut store = SqliteStore::open(&path, &cm).unwrap();
+            store.save_account(&mut cm, &account).unwrap();
+        }
+
+        let mut store = SqliteStore::open(&path, &cm).unwrap();
+        assert_eq!(store.load_account(&cm).unwrap(), account);
+        assert_eq!(store.events_on(&cm, &work.id, meeting.start.date()).unwrap(),
+                   vec![meeting.clone(), lunch.clone()]);
+
+        // Single rows change without rewriting everything.
+        let mut moved = lunch.clone();
+        moved.name = "Late lunch".to_string();
+        store.save_event(&mut cm, &work.id, &moved).unwrap();
+        store.delete_event(&meeting.id).unwrap();
+        assert_eq!(store.events_on(&cm, &work.id, meeting.start.date()).unwrap(), vec![moved]);
+        work.name = "Job".to_string();
+        store.save_calendar(&mut cm, &work).unwrap();
+        assert_eq!(store.calendar_ids().unwrap(), vec![work.id.clone(), account.items[1].id.clone()]);
+        assert_eq!(store.load_calendar(&cm, &work.id).unwrap(), work);
+        store.delete_calendar(&account.items[1].id).unwrap();
+        assert!(store.load_calendar(&cm, &account.items[1].id).is_err());
+
+        let entries = vec![EventLogEntry::new(EntryType::Create, "1", ""),
+                           EventLogEntry::new(EntryType::Update, "1", "{}")];
+        store.append_log(LocalLog::Local, &mut cm, &entries).unwrap();
+        store.append_log(LocalLog::Remote, &mut cm, &entries[1..]).unwrap();
+        assert_eq!(store.read_log(LocalLog::Local, &cm).unwrap(), entries);
+        store.clear_log(LocalLog::Local).unwrap();
+        assert!(store.read_log(LocalLog::Local, &cm).unwrap().is_empty());
+        assert_eq!(store.read_log(LocalLog::Remote, &cm).unwrap(), &entries[1..]);
+
+        // Nothing readable is in the database file, and another key can't read it.
+        drop(store);
+        let mut raw = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"Meeting" || w == b"Room 1\"" || w == b"Office\""));
+        let other = SqliteStore::open(&path, &CryptoManager::new()).unwrap();
+        assert!(other.load_account(&CryptoManager::new()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hybrid_clock() {
+        let mut a = HybridClock::new("a");
+        let mut b = HybridClock::new("b");
+        let t1 = a.now_at(1000);
+        let t2 = a.now_at(1000);
+        // The physical clock of a jumps back, a keeps going forward.
+        let t3 = a.now_at(900);
+        assert!(t1 < t2 && t2 < t3);
+        assert_eq!((t3.wall, t3.counter), (1000, 2));
+
+        // b is behind, but orders what it writes after what it has seen of a.
+        let seen = b.update_at(&t3, 500).unwrap();
+        let t4 = b.now_at(600);
+        assert!(t3 < seen && seen < t4);
+        assert_eq!((t4.wall, t4.counter, &t4.device[..]), (1000, 4, "b"));
+        assert_eq!(b.now_at(1200), Timestamp { wall: 1200, counter: 0, device: "b".to_string() });
+
+        // Equal wall time and counter are ordered by device.
+        let mut c = HybridClock::new("c");
+        assert!(b.now_at(5000) < c.now_at(5000));
+
+        // A device whose clock is far ahead can't drag the others along.
+        b.set_max_drift(1000);
+        let future = Timestamp { wall: 100000, counter: 0, device: "x".to_string() };
+        assert!(b.update_at(&future, 5000).is_err());
+        assert_eq!(b.now_at(5000).wall, 5000);
+
+        // Entries get their timestamp when they are stamped, old entries decode without one.
+        let mut entry = EventLogEntry::new(EntryType::Create, "1", "");
+        assert_eq!(entry.timestamp, None);
+        entry.stamp(&mut a);
+        assert!(entry.timestamp.clone().unwrap() > t3);
+        let decoded: EventLogEntry = json::decode(&json::encode(&entry).unwrap()).unwrap();
+        assert_eq!(decoded, entry);
+        let old: EventLogEntry = json::decode(r#"{"id": "1", "entry_type": "Create", "obj_id": "2", "data": ""}"#)
+                                     .unwrap();
+        assert_eq!(old.timestamp, None);
+    }
+
+    #[test]
+    fn test_tracked_account() {
+        let mut account = Account::new();
+        let work = Calendar::new("Work", "", true);
+        account.items.push(work.clone());
+        let mut tracked = TrackedAccount::new(account, HybridClock::new("device-a"));
+
+        // New objects go to the local log, unstamped.
+        let home = Calendar::new("Home", "", true);
+        tracked.add_calendar(home.clone());
+        let meeting = Event::new("Meeting", "", "");
+        assert!(tracked.add_event(&home.id, meeting.clone()));
+        assert!(!tracked.add_event("unknown", meeting.clone()));
+        assert!(tracked.repeat_event_n_times(&home.id, &meeting, 2));
+        assert_eq!(tracked.local_log().len(), 4);
+        assert!(tracked.local_log().iter().all(|e| e.timestamp.is_none()));
+        assert_eq!(tracked.local_log()[0].obj_id, home.id);
+        let data: EventData = json::decode(&tracked.local_log()[1].data).unwrap();
+        assert_eq!(data, EventData { calendar: home.id.clone(), event: meeting.clone() });
+        assert!(tracked.remote_log().is_empty());
+
+        // Changes of synchronized objects go to the remote log, stamped.
+        assert!(tracked.update_calendar(&work.id, "Job", "", true));
+        let mut moved = meeting.clone();
+        moved.start = moved.start + Duration::days(1);
+        tracked.mark_synced(vec![meeting.id.clone()]);
+        assert!(tracked.update_event(&home.id, moved.clone()));
+        assert_eq!(tracked.account().items[1].get_event(&meeting.id), Some(&moved));
+        // The stored event is deleted, even if the caller has an outdated copy.
+        assert!(tracked.delete_event(&home.id, &meeting));
+        assert_eq!(tracked.account().items[1].get_event(&meeting.id), None);
+        assert!(!tracked.delete_event(&home.id, &moved));
+        let remote = tracked.remote_log().to_vec();
+        assert_eq!(remote.iter().map(|e| e.entry_type.clone()).collect::<Vec<_>>(),
+                   vec![EntryType::Update, EntryType::Update, EntryType::Delete]);
+        let renamed: Calendar = json::decode(&remote[0].data).unwrap();
+        assert_eq!(renamed.name, "Job");
+        assert!(remote.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+
+        // Calendars that aren't synchronized aren't logged.
+        let private = Calendar::new("Private", "", false);
+        tracked.add_calendar(private.clone());
+        tracked.add_event(&private.id, Event::new("Secret", "", ""));
+        assert!(tracked.delete_calendar(&work.id).is_some());
+        let (local, remote) = tracked.take_logs();
+        assert_eq!((local.len(), remote.len()), (4, 4));
+        assert_eq!(remote[3].entry_type, EntryType::Delete);
+        assert!(tracked.local_log().is_empty() && tracked.remote_log().is_empty());
+
+        // Switching sync on logs the calendar with its events, switching it off is logged too.
+        assert!(tracked.update_calendar(&private.id, "Private", "", true));
+        assert_eq!(tracked.local_log().iter().map(|e| e.entry_type.clone()).collect::<Vec<_>>(),
+                   vec![EntryType::Create, EntryType::Create]);
+        tracked.mark_synced(vec![private.id.clone()]);
+        assert!(tracked.update_calendar(&private.id, "Private", "", false));
+        let off: Calendar = json::decode(&tracked.remote_log()[0].data).unwrap();
+        assert!(!off.sync);
+    }
+
+    /// Applies a log to a map from object id to data, as replaying it would.
+    /// Calendars of account with their properties and events, in a comparable order.
+    fn calendar_state(account: &Account) -> Vec<(String, String, Vec<(String, String)>)> {
+        let mut state = account.items
+                               .iter()
+                               .map(|c| (c.id.clone(), c.name.clone(), event_names(account, &c.id)))
+                               .collect::<Vec<_>>();
+        state.sort();
+        state
+    }
+
+    #[test]
+    fn test_compact_local_log() {
+        // The "Merge Local Log" example of the storage module.
+        let log = vec![EventLogEntry::new(EntryType::Create, "1234", "object data"),
+                       EventLogEntry::new(EntryType::Create, "1235", "object data"),
+                       EventLogEntry::new(EntryType::Update, "1234", "changed params"),
+                       EventLogEntry::new(EntryType::Update, "1235", "changed params"),
+                       EventLogEntry::new(EntryType::Update, "1234", "changed params 2"),
+                       EventLogEntry::new(EntryType::Delete, "1235", "")];
+        let compacted = compact_local_log(&log);
+        assert_eq!(compacted.len(), 1);
+        assert_eq!(compacted[0].id, log[0].id);
+        assert_eq!(compacted[0].entry_type, EntryType::Create);
+        assert_eq!(compacted[0].obj_id, "1234");
+        assert_eq!(compacted[0].data, "changed params 2");
+
+        // Objects that weren't created in the log keep all their entries.
+        let log = vec![EventLogEntry::new(EntryType::Update, "2236", "a"),
+                       EventLogEntry::new(EntryType::Update, "2236", "b"),
+                       EventLogEntry::new(EntryType::Delete, "4377", "")];
+        assert_eq!(compact_local_log(&log), log);
+
+        // Renaming a calendar doesn't bring back its events deleted afterwards.
+        let mut tracked = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let home = Calendar::new("Home", "", true);
+        let dinner = Event::new("Dinner", "", "");
+        tracked.add_calendar(home.clone());
+        tracked.add_event(&home.id, dinner.clone());
+        tracked.update_calendar(&home.id, "Family", "", true);
+        tracked.delete_event(&home.id, &dinner);
+        let (account, _) = rebuild(&compact_local_log(tracked.local_log()));
+        assert_eq!(account.items[0].name, "Family");
+        assert!(account.items[0].get_events().is_empty());
+
+        // Replaying the compacted log gives the same state as replaying the original one, it isn't
+        // longer and compacting it again changes nothing.
+        fn same_state(ops: Vec<(u8, u8, u8)>) -> bool {
+            let mut tracked = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+            for &(kind, cal, value) in ops.iter() {
+                let cal = format!("calendar-{}", cal % 2);
+                let id = format!("event-{}", value % 4);
+                let existing = tracked.account()
+                                      .items
+                                      .iter()
+                                      .filter_map(|c| c.get_event(&id).cloned())
+                                      .next();
+                match (kind % 6, existing) {
+                    (0, _) => {
+                        if tracked.account().items.iter().all(|c| c.id != cal) {
+                            let mut c = Calendar::new("Calendar", "", true);
+                            c.id = cal;
+                            tracked.add_calendar(c);
+                        }
+                    }
+                    (1, _) => {
+                        tracked.update_calendar(&cal, &value.to_string(), "", true);
+                    }
+                    (2, _) => {
+                        tracked.delete_calendar(&cal);
+                    }
+                    (3, None) => {
+                        let mut e = Event::new(&value.to_string(), "", "");
+                        e.id = id;
+                        tracked.add_event(&cal, e);
+                    }
+                    (4, Some(mut e)) => {
+                        e.name = format!("{} {}", e.name, value);
+                        tracked.update_event(&cal, e);
+                    }
+                    (5, Some(e)) => {
+                        tracked.delete_event(&cal, &e);
+                    }
+                    _ => (),
+                }
+            }
+            let log = tracked.local_log().to_vec();
+            let compacted = compact_local_log(&log);
+            let (replayed, report) = rebuild(&log);
+            let (compacted_state, compacted_report) = rebuild(&compacted);
+            calendar_state(&replayed) == calendar_state(tracked.account()) &&
+            calendar_state(&compacted_state) == calendar_state(&replayed) &&
+            report.rejected.is_empty() && compacted_report.rejected.is_empty() &&
+            compacted.len() <= log.len() && compact_local_log(&compacted) == compacted
+        }
+        quickcheck(same_state as fn(Vec<(u8, u8, u8)>) -> bool);
+    }
+
+    /// Names of the events in the calendar of account by id, sorted.
+    fn event_names(account: &Account, calendar: &str) -> Vec<(String, String)> {
+        let cal = account.items.iter().find(|c| c.id == calendar).unwrap();
+        let mut names = cal.get_events().iter().map(|e| (e.id.clone(), e.name.clone())).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_synchronizer() {
+        let mut cm = CryptoManager::new();
+        let mut backend = MemoryBackend::new();
+        let pause = || ::std::thread::sleep(::std::time::Duration::from_millis(5));
+        let renamed = |e: &Event, name: &str| {
+            let mut e = e.clone();
+            e.name = name.to_string();
+            e
+        };
+        let names = |log: &[EventLogEntry]| {
+            log.iter()
+               .map(|e| json::decode::<EventData>(&e.data).unwrap().event.name)
+               .collect::<Vec<_>>()
+        };
+
+        // Device a creates the objects 2236, 4377, 1444 and 1515 of the example in the storage
+        // module, device b gets them with its first synchronization.
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+        let events = (0..4).map(|i| Event::new(&i.to_string(), "", "")).collect::<Vec<_>>();
+        for e in events.iter() {
+            a.add_event(&cal.id, e.clone());
+        }
+        let summary = sync_a.sync(&mut a, &mut backend, &mut cm).unwrap();
+        assert_eq!((summary.sent, summary.received), (5, 0));
+        assert!(a.local_log().is_empty());
+        let mut b = TrackedAccount::new(Account::new(), HybridClock::new("device-b"));
+        let mut sync_b = Synchronizer::new("device-b");
+        assert_eq!(sync_b.sync(&mut b, &mut backend, &mut cm).unwrap().received, 5);
+        assert_eq!(b.account(), a.account());
+
+        // TS_1 to TS_3 on a, then b changes 2236 and 1515 and synchronizes first.
+        pause();
+        a.update_event(&cal.id, renamed(&events[0], "TS_1"));
+        a.update_event(&cal.id, renamed(&events[1], "TS_2"));
+        a.update_event(&cal.id, renamed(&events[2], "TS_3"));
+        pause();
+        b.update_event(&cal.id, renamed(&events[0], "TS_5"));
+        b.update_event(&cal.id, renamed(&events[3], "TS_6"));
+        sync_b.sync(&mut b, &mut backend, &mut cm).unwrap();
+        pause();
+        a.update_event(&cal.id, renamed(&events[0], "TS_4"));
+
+        // Objects 1234 and 1235 are created on a, 1235 is deleted again.
+        let created = Event::new("1234", "", "");
+        let deleted = Event::new("1235", "", "");
+        a.add_event(&cal.id, created.clone());
+        a.add_event(&cal.id, deleted.clone());
+        a.update_event(&cal.id, renamed(&created, "changed params"));
+        a.update_event(&cal.id, renamed(&deleted, "changed params"));
+        a.update_event(&cal.id, renamed(&created, "updated object data"));
+        a.delete_event(&cal.id, &renamed(&deleted, "changed params"));
+
+        // Merge Local Log, Merge Remote Log and Merge Remote Log and Shared Log.
+        let local = compact_local_log(a.local_log());
+        assert_eq!(names(&local), vec!["updated object data"]);
+        let remote = merge_remote_log(a.remote_log());
+        assert_eq!(names(&remote), vec!["TS_2", "TS_3", "TS_4"]);
+        let shared = read_log(&mut backend, &cm).unwrap();
+        assert_eq!(names(&merge_shared_log(&shared[5..], &remote)),
+                   vec!["TS_2", "TS_3", "TS_5", "TS_6", "TS_4"]);
+
+        // Nothing is lost while another device holds the lock.
+        let mut other = RemoteLock::new("device-c", Duration::minutes(5));
+        other.acquire(&mut backend).unwrap();
+        match sync_a.sync(&mut a, &mut backend, &mut cm) {
+            Err(SyncError::Lock(LockError::Held(_))) => (),
+            r => panic!("Expected held lock, got {:?}", r),
+        }
+        assert_eq!((a.local_log().len(), a.remote_log().len()), (6, 4));
+        other.release(&mut backend).unwrap();
+
+        let summary = sync_a.sync(&mut a, &mut backend, &mut cm).unwrap();
+        assert_eq!((summary.sent, summary.received), (4, 2));
+        assert!(summary.rejected.is_empty());
+        assert_eq!(summary.changed, vec![events[0].id.clone(), events[3].id.clone()]);
+        let log = read_log(&mut backend, &cm).unwrap();
+        assert_eq!(names(&log[5..]), vec!["TS_5", "TS_6", "TS_2", "TS_3", "TS_4", "updated object data"]);
+        assert!(log[..9].iter().all(|e| e.timestamp < log[9].timestamp));
+        assert_eq!(sync_a.position(), &LogPosition::end_of(&log));
+
+        // The later change of 2236 wins on both devices.
+        let mut expected = vec![(events[0].id.clone(), "TS_4".to_string()),
+                                (events[1].id.clone(), "TS_2".to_string()),
+                                (events[2].id.clone(), "TS_3".to_string()),
+                                (events[3].id.clone(), "TS_6".to_string()),
+                                (created.id.clone(), "updated object data".to_string())];
+        expected.sort();
+        assert_eq!(event_names(a.account(), &cal.id), expected);
+        let summary = sync_b.sync(&mut b, &mut backend, &mut cm).unwrap();
+        assert_eq!((summary.sent, summary.received), (0, 4));
+        assert_eq!(event_names(b.account(), &cal.id), expected);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut tracked = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let home = Calendar::new("Home", "", true);
+        tracked.add_calendar(home.clone());
+        let e = Event::new("Dinner", "", "");
+        tracked.add_event(&home.id, e.clone());
+        tracked.repeat_event_n_times(&home.id, &e, 2);
+        let mut moved = e.clone();
+        moved.start = moved.start + Duration::days(2);
+        tracked.update_event(&home.id, moved.clone());
+        tracked.update_calendar(&home.id, "Family", "", true);
+        let work = Calendar::new("Work", "", true);
+        tracked.add_calendar(work.clone());
+        tracked.add_event(&work.id, Event::new("Meeting", "", ""));
+        tracked.delete_calendar(&work.id);
+        let log = tracked.take_logs().0;
+
+        // From scratch.
+        let (account, report) = rebuild(&log);
+        assert_eq!(&account, tracked.account());
+        assert_eq!((report.applied, report.duplicates), (log.len(), 0));
+
+        // Applying the entries again changes nothing.
+        let mut replay = Replay::new();
+        replay.apply_all(&log);
+        let report = replay.apply_all(&log);
+        assert_eq!((report.applied, report.duplicates), (0, log.len()));
+        assert_eq!(replay.account(), tracked.account());
+
+        // Incrementally from a checkpoint.
+        let mut first = Replay::new();
+        first.replay_log(&log[..3]).unwrap();
+        let mut rest = Replay::from_checkpoint(first.account().clone(), first.position().clone());
+        assert_eq!(rest.replay_log(&log).unwrap().applied, log.len() - 3);
+        assert_eq!(rest.account(), tracked.account());
+        assert_eq!(rest.position(), &LogPosition::end_of(&log));
+        match rest.replay_log(&log[..2]) {
+            Err(StorageError::NotFound(_)) => (),
+            r => panic!("Expected missing checkpoint, got {:?}", r),
+        }
+
+        // Entries that don't fit the state are reported.
+        let orphan = EventData { calendar: work.id.clone(), event: Event::new("Lost", "", "") };
+        let bad = vec![EventLogEntry::new(EntryType::Create, &orphan.event.id, &json::encode(&orphan).unwrap()),
+                       EventLogEntry::new(EntryType::Update, &work.id, &json::encode(&work).unwrap()),
+                       EventLogEntry::new(EntryType::Update, "1", "garbage"),
+                       EventLogEntry::new(EntryType::Delete, &work.id, "")];
+        let report = rest.apply_all(&bad);
+        assert_eq!(report.applied, 1);
+        assert_eq!(report.rejected.iter().map(|r| r.1.clone()).collect::<Vec<_>>(),
+                   vec![ReplayError::NoCalendar(work.id.clone()), ReplayError::NotFound,
+                        ReplayError::Undecodable]);
+        assert_eq!(rest.account(), tracked.account());
+    }
+
+    #[test]
+    fn test_sync_cursor() {
+        let mut cm = CryptoManager::new();
+        let mut shared = MemoryBackend::new();
+        let mut local = MemoryBackend::new();
+
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+        sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        save_cursor(&mut local, &mut cm, sync_a.cursor()).unwrap();
+        let mut b = TrackedAccount::new(Account::new(), HybridClock::new("device-b"));
+        let mut sync_b = Synchronizer::new("device-b");
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+
+        // Every device publishes how far it got.
+        let cursors = read_cursors(&mut shared, &cm).unwrap();
+        assert_eq!(cursors.iter().map(|c| c.device.clone()).collect::<Vec<_>>(),
+                   vec!["device-a", "device-b"]);
+        assert_eq!(&cursors[0], sync_a.cursor());
+        assert_eq!(cursors[1].position, LogPosition::end_of(&read_log(&mut shared, &cm).unwrap()));
+
+        // After a restart a continues with the entries after its cursor, and its clock doesn't
+        // go back.
+        let cursor = load_cursor(&mut local, &cm, "device-a").unwrap().unwrap();
+        assert_eq!(load_cursor(&mut local, &cm, "device-c").unwrap(), None);
+        let mut a = TrackedAccount::new(a.account().clone(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::from_cursor(cursor.clone());
+        b.add_event(&cal.id, Event::new("Meeting", "", ""));
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        let mut handle = shared.clone();
+        let summary = sync_a.sync(&mut a, &mut handle, &mut cm).unwrap();
+        assert_eq!(summary.received, 1);
+        // Only the segments from the one in the cursor on are read.
+        assert!(cursor.segment > 0);
+        assert!(handle.reads()
+                      .iter()
+                      .filter(|n| n.starts_with(LOG_PREFIX))
+                      .all(|n| n[LOG_PREFIX.len()..].parse::<usize>().unwrap() >= cursor.segment));
+        assert!(handle.reads().iter().all(|n| !n.starts_with(BACKUP_PREFIX)));
+        assert_eq!(event_names(a.account(), &cal.id), event_names(b.account(), &cal.id));
+        assert!(sync_a.cursor().clock > cursor.clock);
+        assert!(Some(a.clock().now()) > cursor.clock);
+
+        // A cursor that has been moved to the backup log is found there.
+        let cursor = sync_a.cursor().clone();
+        b.add_event(&cal.id, Event::new("Lunch", "", ""));
+        b.add_event(&cal.id, Event::new("Review", "", ""));
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        let mut sanitizer = Sanitizer::new();
+        sanitizer.set_keep(1);
+        sanitizer.sanitize(&mut shared, &mut cm, &mut RemoteLock::new("device-c", Duration::minutes(5)))
+                 .unwrap()
+                 .unwrap();
+        let mut sync_a = Synchronizer::from_cursor(cursor);
+        assert_eq!(sync_a.sync(&mut a, &mut shared, &mut cm).unwrap().received, 2);
+        assert_eq!(event_names(a.account(), &cal.id), event_names(b.account(), &cal.id));
+    }
+
+    /// Devices a and b change the name and the location of the same event, b later but
+    /// synchronizing first, then a synchronizes after a restart with policy and b again.
+    /// Returns the events of both devices and the summary of a.
+    fn concurrent_edit(policy: ConflictPolicy) -> (Vec<Event>, Vec<Event>, SyncSummary) {
+        let mut cm = CryptoManager::new();
+        let mut shared = MemoryBackend::new();
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+        let e = Event::new("Meeting", "", "Office");
+        a.add_event(&cal.id, e.clone());
+        sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        let mut b = TrackedAccount::new(Account::new(), HybridClock::new("device-b"));
+        let mut sync_b = Synchronizer::new("device-b");
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+
+        let mut ours = e.clone();
+        ours.name = "Standup".to_string();
+        a.update_event(&cal.id, ours);
+        ::std::thread::sleep(::std::time::Duration::from_millis(5));
+        let mut theirs = e.clone();
+        theirs.location = "Kitchen".to_string();
+        b.update_event(&cal.id, theirs);
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        // The base of the conflict is read from the log again.
+        let mut sync_a = Synchronizer::from_cursor(sync_a.cursor().clone());
+        sync_a.set_policy(policy);
+        let summary = sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+
+        let events = |t: &TrackedAccount| {
+            let mut events = t.account().items[0].get_events().into_iter().cloned().collect::<Vec<_>>();
+            events.sort_by(|x, y| (&x.name, &x.location).cmp(&(&y.name, &y.location)));
+            events
+        };
+        (events(&a), events(&b), summary)
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let fields = |events: &[Event]| {
+            events.iter().map(|e| (e.name.clone(), e.location.clone())).collect::<Vec<_>>()
+        };
+        let pair = |name: &str, location: &str| (name.to_string(), location.to_string());
+
+        let (a, b, _) = concurrent_edit(ConflictPolicy::LastWriterWins);
+        assert_eq!(fields(&a), vec![pair("Meeting", "Kitchen")]);
+        assert_eq!(a, b);
+
+        let (a, b, _) = concurrent_edit(ConflictPolicy::MergeFields);
+        assert_eq!(fields(&a), vec![pair("Standup", "Kitchen")]);
+        assert_eq!(a, b);
+
+        let (a, b, _) = concurrent_edit(ConflictPolicy::KeepBoth);
+        assert_eq!(fields(&a), vec![pair("Meeting", "Kitchen"), pair("Standup", "Office")]);
+        assert!(a[0].id != a[1].id);
+        assert_eq!(a, b);
+
+        let (a, b, _) = concurrent_edit(ConflictPolicy::Ask(Box::new(|c| {
+            let mut data = c.theirs.clone();
+            data.event.name = format!("{} in the {}", c.ours.event.name, c.theirs.event.location);
+            Some(data)
+        })));
+        assert_eq!(fields(&a), vec![pair("Standup in the Kitchen", "Kitchen")]);
+        assert_eq!(a, b);
+
+        // Conflicts left to the user wait in the queue, the later change applies meanwhile.
+        let (a, b, summary) = concurrent_edit(ConflictPolicy::Ask(Box::new(|_| None)));
+        assert_eq!(fields(&a), vec![pair("Meeting", "Kitchen")]);
+        assert_eq!(a, b);
+        assert_eq!(summary.unresolved.len(), 1);
+        let conflict = summary.unresolved[0].clone();
+        assert_eq!((conflict.ours.event.name.as_ref(), conflict.theirs.event.location.as_ref()),
+                   ("Standup", "Kitchen"));
+        assert_eq!(conflict.base.as_ref().unwrap().event.name, "Meeting");
+        assert!(!conflict.ours_newer());
+
+        let mut cm = CryptoManager::new();
+        let mut local = MemoryBackend::new();
+        let mut queue = ConflictQueue::load(&mut local, &cm).unwrap();
+        queue.extend(summary.unresolved);
+        queue.save(&mut local, &mut cm).unwrap();
+        let mut queue = ConflictQueue::load(&mut local, &cm).unwrap();
+        assert_eq!(queue.pending(), &[conflict.clone()]);
+        assert_eq!(queue.resolve(&conflict.id), Some(conflict));
+        assert!(queue.pending().is_empty());
+    }
+
+    #[test]
+    fn test_replicated_backend_restart() {
+        let (a, b, c) = (MemoryBackend::new(), MemoryBackend::new(), MemoryBackend::new());
+        let cm = Arc::new(Mutex::new(CryptoManager::new()));
+        let replicated = || {
+            let mut rep = ReplicatedBackend::new(cm.clone());
+            rep.add("a", a.clone());
+            rep.add("b", b.clone());
+            rep.add("c", c.clone());
+            rep
+        };
+        let mut rep = replicated();
+        rep.put("old", b"old").unwrap();
+        c.set_offline(true);
+        rep.delete("old").unwrap();
+        drop(rep);
+
+        // c missed the delete, after a restart the object doesn't come back from it.
+        c.set_offline(false);
+        let mut rep = replicated();
+        assert_eq!(rep.list("").unwrap(), Vec::<String>::new());
+        assert!(rep.get("old").is_err());
+        assert_eq!(c.raw("old"), None);
+        assert_eq!(rep.status()[2].pending, 0);
+        assert_eq!(replicated().repair().unwrap(), 0);
+
+        // A replica that missed a delete doesn't make a conditional write conflict.
+        rep.put("old", b"old").unwrap();
+        c.set_offline(true);
+        rep.delete("old").unwrap();
+        let pending = a.raw(PENDING_NAME).unwrap();
+        assert!(cm.lock().unwrap().decrypt_bytes(pending).is_some());
+        c.set_offline(false);
+        let mut rep = replicated();
+        rep.put_if("old", b"again", None).unwrap();
+        assert_eq!(rep.status()[2].pending, 0);
+        assert_eq!(c.raw("old"), a.raw("old"));
+    }
+
+    #[test]
+    fn test_scrub_after_sanitize() {
+        let mut cm = CryptoManager::new();
+        let mut b = MemoryBackend::new();
+        let entries = vec![EventLogEntry::new(EntryType::Create, "a", "{}"),
+                           EventLogEntry::new(EntryType::Create, "b", "{}"),
+                           EventLogEntry::new(EntryType::Update, "a", "{}"),
+                           EventLogEntry::new(EntryType::Delete, "b", ""),
+                           EventLogEntry::new(EntryType::Update, "a", "{}")];
+        let position = append_log(&mut b, &mut cm, &LogPosition::end_of(&[]), &entries[..2]).unwrap();
+        Snapshots::new("device-a").create(&mut b, &mut cm, &Account::new(), position).unwrap();
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&entries[..2]), &entries[2..3]).unwrap();
+        append_log(&mut b, &mut cm, &LogPosition::end_of(&entries[..3]), &entries[3..]).unwrap();
+        let mut sanitizer = Sanitizer::new();
+        sanitizer.set_keep(2);
+        let mut lock = RemoteLock::new("device-a", Duration::minutes(5));
+        let segment = sanitizer.sanitize(&mut b, &mut cm, &mut lock).unwrap().unwrap();
+
+        // The archived Creates and the snapshot position are found in the backup log.
+        let report = scrub(&mut b, &cm).unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.entries, 2);
+
+        b.delete(&format!("backup/{}", segment.id)).unwrap();
+        let report = scrub(&mut b, &cm).unwrap();
+        let missing = report.with_problem(Problem::Missing);
+        assert_eq!(missing[0].name, format!("backup/{}", segment.id));
+        assert_eq!(missing[0].repair, Repair::Reupload);
+    }
+
+    #[test]
+    fn test_sync_reads_only_new_segments() {
+        let mut cm = CryptoManager::new();
+        let mut shared = MemoryBackend::new();
+        let log_reads = |b: &MemoryBackend| {
+            b.reads().iter().filter(|n| n.starts_with(LOG_PREFIX)).cloned().collect::<Vec<_>>()
+        };
+
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+        sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        let mut b = TrackedAccount::new(Account::new(), HybridClock::new("device-b"));
+        let mut sync_b = Synchronizer::new("device-b");
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        for i in 0..3 {
+            b.add_event(&cal.id, Event::new(&i.to_string(), "", ""));
+            sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        }
+
+        // a only downloads the segments b added and looks for the next one.
+        let mut handle = shared.clone();
+        a.add_event(&cal.id, Event::new("Meeting", "", ""));
+        assert_eq!(sync_a.sync(&mut a, &mut handle, &mut cm).unwrap().received, 3);
+        assert_eq!(log_reads(&handle), vec!["log/1", "log/2", "log/3", "log/4"]);
+
+        let mut handle = shared.clone();
+        b.add_event(&cal.id, Event::new("Lunch", "", ""));
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        sync_a.sync(&mut a, &mut handle, &mut cm).unwrap();
+        assert_eq!(event_names(a.account(), &cal.id), event_names(b.account(), &cal.id));
+        assert_eq!(log_reads(&handle), vec!["log/5", "log/6"]);
+
+        // Nothing new: only the end of the log is checked.
+        let mut handle = shared.clone();
+        sync_a.sync(&mut a, &mut handle, &mut cm).unwrap();
+        assert_eq!(log_reads(&handle), vec!["log/6", "log/5"]);
+
+        // Once the segment in the cursor was archived, only the backup segments archived since
+        // the last synchronization are read.
+        let mut sanitizer = Sanitizer::new();
+        sanitizer.set_keep(1);
+        let mut lock = RemoteLock::new("device-c", Duration::minutes(5));
+        let older = sanitizer.sanitize(&mut shared, &mut cm, &mut lock).unwrap().unwrap();
+        sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        for name in &["Dinner", "Call"] {
+            b.add_event(&cal.id, Event::new(name, "", ""));
+            sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        }
+        let newer = sanitizer.sanitize(&mut shared, &mut cm, &mut lock).unwrap().unwrap();
+        let mut handle = shared.clone();
+        assert_eq!(sync_a.sync(&mut a, &mut handle, &mut cm).unwrap().received, 2);
+        assert_eq!(event_names(a.account(), &cal.id), event_names(b.account(), &cal.id));
+        let backup_reads = handle.reads()
+                                 .iter()
+                                 .filter(|n| n.starts_with(BACKUP_PREFIX))
+                                 .cloned()
+                                 .collect::<Vec<_>>();
+        assert!(backup_reads.contains(&segment_name(&newer.id)));
+        assert!(!backup_reads.contains(&segment_name(&older.id)));
+    }
+
+    #[test]
+    fn test_sync_async() {
+        let mut cm = CryptoManager::new();
+        let mut shared = MemoryBackend::new();
+        let pooled = Pooled::new(shared.clone(), CpuPool::new(2));
+
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+        a.add_event(&cal.id, Event::new("Meeting", "", ""));
+        assert_eq!(sync_a.sync_async(&mut a, &pooled, &mut cm).wait().unwrap().sent, 2);
+
+        // Devices synchronizing asynchronously and blocking see each other's changes.
+        let mut b = TrackedAccount::new(Account::new(), HybridClock::new("device-b"));
+        let mut sync_b = Synchronizer::new("device-b");
+        assert_eq!(sync_b.sync(&mut b, &mut shared, &mut cm).unwrap().received, 2);
+        b.add_event(&cal.id, Event::new("Lunch", "", ""));
+        sync_b.sync(&mut b, &mut shared, &mut cm).unwrap();
+        assert_eq!(sync_a.sync_async(&mut a, &pooled, &mut cm).wait().unwrap().received, 1);
+        assert_eq!(a.account(), b.account());
+
+        // If the lock is held, the logs are kept for the next try.
+        let mut lock = RemoteLock::new("device-b", Duration::minutes(5));
+        lock.acquire(&mut shared).unwrap();
+        a.add_event(&cal.id, Event::new("Review", "", ""));
+        match sync_a.sync_async(&mut a, &pooled, &mut cm).wait() {
+            Err(SyncError::Lock(LockError::Held(_))) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(a.local_log().len(), 1);
+        lock.release(&mut shared).unwrap();
+        assert_eq!(sync_a.sync_async(&mut a, &pooled, &mut cm).wait().unwrap().sent, 1);
+        assert!(a.local_log().is_empty());
+
+        // Backends that aren't Send are synchronized with as well.
+        let mut replicated = ReplicatedBackend::new(Arc::new(Mutex::new(CryptoManager::new())));
+        replicated.add("memory", MemoryBackend::new());
+        let mut c = TrackedAccount::new(Account::new(), HybridClock::new("device-c"));
+        c.add_calendar(Calendar::new("Home", "", true));
+        assert_eq!(Synchronizer::new("device-c").sync(&mut c, &mut replicated, &mut cm).unwrap().sent, 1);
+    }
+
+
+    #[test]
+    fn test_sync_release_failure() {
+        // Fails every change of the lock after it was acquired.
+        struct StuckLock {
+            backend: MemoryBackend,
+            lock_writes: usize,
+        }
+
+        impl StuckLock {
+            fn write_lock(&mut self, name: &str) -> Result<(), StorageError> {
+                if name == "lock" {
+                    self.lock_writes += 1;
+                    if self.lock_writes > 1 {
+                        return Err(StorageError::Unavailable("lock is stuck".to_string()));
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        impl Backend for StuckLock {
+            fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+                self.backend.get_versioned(name)
+            }
+
+            fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+                try!(self.write_lock(name));
+                self.backend.put(name, data)
+            }
+
+            fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+                      -> Result<Version, StorageError> {
+                try!(self.write_lock(name));
+                self.backend.put_if(name, data, expected)
+            }
+
+            fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+                try!(self.write_lock(name));
+                self.backend.delete(name)
+            }
+
+            fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+                self.backend.list(prefix)
+            }
+        }
+
+        let mut cm = CryptoManager::new();
+        let mut shared = StuckLock {
+            backend: MemoryBackend::new(),
+            lock_writes: 0,
+        };
+        let mut a = TrackedAccount::new(Account::new(), HybridClock::new("device-a"));
+        let mut sync_a = Synchronizer::new("device-a");
+        let cal = Calendar::new("Work", "", true);
+        a.add_calendar(cal.clone());
+
+        // The changes were sent, so the sync succeeds and only reports the lock.
+        let summary = sync_a.sync(&mut a, &mut shared, &mut cm).unwrap();
+        assert_eq!(summary.sent, 1);
+        assert!(summary.release_error.is_some());
+        assert!(a.local_log().is_empty());
+        assert_eq!(read_log(&mut shared.backend, &cm).unwrap().len(), 1);
+    }
 }
@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use rustc_serialize::json;
+use domain::{Account, Calendar, EntryType, EventData, EventLogEntry};
+use storage::backend::StorageError;
+use storage::log::LogPosition;
+
+/// Reasons a log entry can't be applied to the state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    /// The data is neither a Calendar nor an EventData.
+    Undecodable,
+    /// The event belongs to a calendar that doesn't exist. Contains the id of the calendar.
+    NoCalendar(String),
+    /// The entry updates an object that doesn't exist.
+    NotFound,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::Undecodable => write!(f, "Entry data does not decode"),
+            ReplayError::NoCalendar(ref id) => write!(f, "Calendar {} does not exist", id),
+            ReplayError::NotFound => write!(f, "Object does not exist"),
+        }
+    }
+}
+
+impl Error for ReplayError {
+    fn description(&self) -> &str {
+        match *self {
+            ReplayError::Undecodable => "entry data does not decode",
+            ReplayError::NoCalendar(_) => "calendar does not exist",
+            ReplayError::NotFound => "object does not exist",
+        }
+    }
+}
+
+/// What replaying entries did.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplayReport {
+    pub applied: usize,
+    /// Entries that had been applied before and were skipped.
+    pub duplicates: usize,
+    /// Entries that couldn't be applied, with the reason.
+    pub rejected: Vec<(EventLogEntry, ReplayError)>,
+}
+
+/// Applies log entries to an Account, step 10 of the synchronization.
+///
+/// Entries about a calendar have the Calendar without its events as data, entries about an
+/// event an EventData, see TrackedAccount. A Create of a calendar adds it with the events in
+/// its data, which only entries written before events had entries of their own contain, an
+/// Update only changes its properties. Deleting an object
+/// that doesn't exist does nothing, so applying an entry twice gives the same state.
+pub struct Replay {
+    account: Account,
+    position: LogPosition,
+    applied: HashSet<String>,
+}
+
+impl Replay {
+    /// Starts with an empty account at the start of the log, to rebuild the state from
+    /// scratch.
+    pub fn new() -> Replay {
+        Replay::from_checkpoint(Account::new(), LogPosition::end_of(&[]))
+    }
+
+    /// Starts with account, which is the state after the log entries up to position, e.g. from
+    /// a snapshot.
+    pub fn from_checkpoint(account: Account, position: LogPosition) -> Replay {
+        Replay {
+            account: account,
+            position: position,
+            applied: HashSet::new(),
+        }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn into_account(self) -> Account {
+        self.account
+    }
+
+    /// Position in the log the state corresponds to, after replay_log.
+    pub fn position(&self) -> &LogPosition {
+        &self.position
+    }
+
+    /// Applies the entries of log after the position and moves the position to the end of
+    /// log. Fails if the position isn't in log.
+    pub fn replay_log(&mut self, log: &[EventLogEntry]) -> Result<ReplayReport, StorageError> {
+        let start = match self.position.last_entry {
+            Some(ref id) => {
+                match log.iter().position(|e| &e.id == id) {
+                    Some(i) => i + 1,
+                    None => return Err(StorageError::NotFound(format!("Log entry {}", id))),
+                }
+            }
+            None => 0,
+        };
+        let report = self.apply_all(&log[start..]);
+        self.position = LogPosition::end_of(log);
+        Ok(report)
+    }
+
+    /// Applies entries in order.
+    pub fn apply_all(&mut self, entries: &[EventLogEntry]) -> ReplayReport {
+        let mut report = ReplayReport::default();
+        for entry in entries {
+            if self.applied.contains(&entry.id) {
+                report.duplicates += 1;
+                continue;
+            }
+            match self.apply(entry) {
+                Ok(()) => report.applied += 1,
+                Err(e) => report.rejected.push((entry.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Applies a single entry. Entries with an id that has been applied before are ignored.
+    pub fn apply(&mut self, entry: &EventLogEntry) -> Result<(), ReplayError> {
+        if self.applied.contains(&entry.id) {
+            return Ok(());
+        }
+        try!(apply_entry(&mut self.account, entry));
+        self.applied.insert(entry.id.clone());
+        Ok(())
+    }
+}
+
+/// Rebuilds the state from the whole log.
+pub fn rebuild(log: &[EventLogEntry]) -> (Account, ReplayReport) {
+    let mut replay = Replay::new();
+    let report = replay.apply_all(log);
+    (replay.into_account(), report)
+}
+
+fn apply_entry(account: &mut Account, entry: &EventLogEntry) -> Result<(), ReplayError> {
+    if let EntryType::Delete = entry.entry_type {
+        account.items.retain(|c| c.id != entry.obj_id);
+        remove_event(account, &entry.obj_id);
+        return Ok(());
+    }
+
+    if let Ok(data) = json::decode::<EventData>(&entry.data) {
+        if !account.items.iter().any(|c| c.id == data.calendar) {
+            return Err(ReplayError::NoCalendar(data.calendar));
+        }
+        let exists = remove_event(account, &entry.obj_id);
+        if let EntryType::Update = entry.entry_type {
+            if !exists {
+                return Err(ReplayError::NotFound);
+            }
+        }
+        let cal = account.items.iter_mut().find(|c| c.id == data.calendar).unwrap();
+        cal.add_event(data.event);
+        return Ok(());
+    }
+
+    let cal = match json::decode::<Calendar>(&entry.data) {
+        Ok(c) => c,
+        Err(_) => return Err(ReplayError::Undecodable),
+    };
+    let index = account.items.iter().position(|c| c.id == entry.obj_id);
+    match (&entry.entry_type, index) {
+        (&EntryType::Create, Some(i)) => account.items[i] = cal,
+        (&EntryType::Create, None) => account.items.push(cal),
+        (_, Some(i)) => {
+            let existing = &mut account.items[i];
+            existing.name = cal.name;
+            existing.desc = cal.desc;
+            existing.sync = cal.sync;
+        }
+        (_, None) => return Err(ReplayError::NotFound),
+    }
+    Ok(())
+}
+
+/// Removes the event with the given id from whatever calendar it is in. Returns false if there
+/// is none.
+fn remove_event(account: &mut Account, id: &str) -> bool {
+    for cal in account.items.iter_mut() {
+        if let Some(e) = cal.get_event(id).cloned() {
+            cal.delete_event(&e);
+            return true;
+        }
+    }
+    false
+}
@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use futures::Future;
+use futures::future::{self, Loop};
+use futures_cpupool::CpuPool;
+use rustc_serialize::{Decodable, Encodable};
+use crypto::CryptoManager;
+use domain::EventLogEntry;
+use storage::{open_object, seal_object};
+use storage::backend::{Backend, StorageError, Version};
+use storage::log::{decode_log, encode_log, log_segment_name, position_after, segment_numbers, LogPosition,
+                   LOG_PREFIX, RETRIES};
+
+/// Result of an asynchronous storage operation. It is Send, so it can be run on another
+/// thread, e.g. spawned on a CpuPool.
+pub type StorageFuture<T> = Box<Future<Item = T, Error = StorageError> + Send>;
+
+/// The CryptoManager as shared by asynchronous operations, which outlive any borrow.
+pub type SharedCrypto = Arc<Mutex<CryptoManager>>;
+
+/// Asynchronous version of the Backend trait. The operations return immediately and the
+/// futures complete once the provider answered.
+pub trait AsyncBackend {
+    /// See Backend::get_versioned.
+    fn get_versioned(&self, name: &str) -> StorageFuture<(Vec<u8>, Version)>;
+
+    /// See Backend::get.
+    fn get(&self, name: &str) -> StorageFuture<Vec<u8>> {
+        Box::new(self.get_versioned(name).map(|r| r.0))
+    }
+
+    /// See Backend::version.
+    fn version(&self, name: &str) -> StorageFuture<Version> {
+        Box::new(self.get_versioned(name).map(|r| r.1))
+    }
+
+    /// See Backend::put.
+    fn put(&self, name: &str, data: Vec<u8>) -> StorageFuture<()>;
+
+    /// See Backend::put_if.
+    fn put_if(&self, name: &str, data: Vec<u8>, expected: Option<Version>) -> StorageFuture<Version>;
+
+    /// See Backend::delete.
+    fn delete(&self, name: &str) -> StorageFuture<()>;
+
+    /// See Backend::list.
+    fn list(&self, prefix: &str) -> StorageFuture<Vec<String>>;
+}
+
+/// Runs a blocking Backend on a thread pool, so it can be used asynchronously. Clones share
+/// the backend and the pool. Operations on the same backend still happen one after another.
+pub struct Pooled<B> {
+    backend: Arc<Mutex<B>>,
+    pool: CpuPool,
+}
+
+impl<B> Clone for Pooled<B> {
+    fn clone(&self) -> Pooled<B> {
+        Pooled {
+            backend: self.backend.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<B: Backend + Send + 'static> Pooled<B> {
+    /// Runs the operations of backend on pool.
+    pub fn new(backend: B, pool: CpuPool) -> Pooled<B> {
+        Pooled {
+            backend: Arc::new(Mutex::new(backend)),
+            pool: pool,
+        }
+    }
+
+    /// Runs f with the backend on the pool. This is how blocking routines working on a Backend,
+    /// like RemoteLock, are used asynchronously.
+    pub fn with_backend<T, F>(&self, f: F) -> StorageFuture<T>
+        where T: Send + 'static,
+              F: FnOnce(&mut B) -> Result<T, StorageError> + Send + 'static
+    {
+        let backend = self.backend.clone();
+        Box::new(self.pool.spawn_fn(move || f(&mut *backend.lock().unwrap())))
+    }
+}
+
+impl<B: Backend + Send + 'static> AsyncBackend for Pooled<B> {
+    fn get_versioned(&self, name: &str) -> StorageFuture<(Vec<u8>, Version)> {
+        let name = name.to_string();
+        self.with_backend(move |b| b.get_versioned(&name))
+    }
+
+    fn version(&self, name: &str) -> StorageFuture<Version> {
+        let name = name.to_string();
+        self.with_backend(move |b| b.version(&name))
+    }
+
+    fn put(&self, name: &str, data: Vec<u8>) -> StorageFuture<()> {
+        let name = name.to_string();
+        self.with_backend(move |b| b.put(&name, &data))
+    }
+
+    fn put_if(&self, name: &str, data: Vec<u8>, expected: Option<Version>) -> StorageFuture<Version> {
+        let name = name.to_string();
+        self.with_backend(move |b| b.put_if(&name, &data, expected.as_ref()))
+    }
+
+    fn delete(&self, name: &str) -> StorageFuture<()> {
+        let name = name.to_string();
+        self.with_backend(move |b| b.delete(&name))
+    }
+
+    fn list(&self, prefix: &str) -> StorageFuture<Vec<String>> {
+        let prefix = prefix.to_string();
+        self.with_backend(move |b| b.list(&prefix))
+    }
+}
+
+/// Blocking Backend waiting for the futures of an AsyncBackend. Must not be used on a thread
+/// of the runtime the futures need to complete.
+pub struct Blocking<A: AsyncBackend> {
+    inner: A,
+}
+
+impl<A: AsyncBackend> Blocking<A> {
+    pub fn new(inner: A) -> Blocking<A> {
+        Blocking { inner: inner }
+    }
+
+    /// Returns the asynchronous backend.
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+impl<A: AsyncBackend> Backend for Blocking<A> {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        self.inner.get_versioned(name).wait()
+    }
+
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        self.inner.version(name).wait()
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.inner.put(name, data.to_vec()).wait()
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        self.inner.put_if(name, data.to_vec(), expected.cloned()).wait()
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        self.inner.delete(name).wait()
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.list(prefix).wait()
+    }
+}
+
+/// AsyncBackend running the operations of a blocking Backend right away on the calling
+/// thread, so the futures are complete when they are returned. This is how the asynchronous
+/// routines, like Synchronizer::sync_async, are used with a blocking Backend.
+///
+/// The backend doesn't have to be Send, only the results are handed to the futures.
+pub struct Immediate<'a, B: 'a> {
+    backend: RefCell<&'a mut B>,
+}
+
+impl<'a, B: Backend> Immediate<'a, B> {
+    pub fn new(backend: &'a mut B) -> Immediate<'a, B> {
+        Immediate { backend: RefCell::new(backend) }
+    }
+
+    fn run<T, F>(&self, f: F) -> StorageFuture<T>
+        where T: Send + 'static,
+              F: FnOnce(&mut B) -> Result<T, StorageError>
+    {
+        Box::new(future::result(f(&mut **self.backend.borrow_mut())))
+    }
+}
+
+impl<'a, B: Backend> AsyncBackend for Immediate<'a, B> {
+    fn get_versioned(&self, name: &str) -> StorageFuture<(Vec<u8>, Version)> {
+        self.run(|b| b.get_versioned(name))
+    }
+
+    fn version(&self, name: &str) -> StorageFuture<Version> {
+        self.run(|b| b.version(name))
+    }
+
+    fn put(&self, name: &str, data: Vec<u8>) -> StorageFuture<()> {
+        self.run(|b| b.put(name, &data))
+    }
+
+    fn put_if(&self, name: &str, data: Vec<u8>, expected: Option<Version>) -> StorageFuture<Version> {
+        self.run(|b| b.put_if(name, &data, expected.as_ref()))
+    }
+
+    fn delete(&self, name: &str) -> StorageFuture<()> {
+        self.run(|b| b.delete(name))
+    }
+
+    fn list(&self, prefix: &str) -> StorageFuture<Vec<String>> {
+        self.run(|b| b.list(prefix))
+    }
+}
+
+/// Asynchronous save_to. s is encoded and encrypted right away, only the upload happens
+/// later.
+pub fn save_async<A, S>(b: &A, name: &str, c: &SharedCrypto, s: &S) -> StorageFuture<()>
+    where A: AsyncBackend,
+          S: Encodable
+{
+    match seal_object(name, &mut c.lock().unwrap(), s) {
+        Ok(enc) => b.put(name, enc),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+/// Asynchronous load_from.
+pub fn load_async<A, D>(b: &A, name: &str, c: SharedCrypto) -> StorageFuture<D>
+    where A: AsyncBackend,
+          D: Decodable + Send + 'static
+{
+    let name = name.to_string();
+    Box::new(b.get(&name).and_then(move |enc| open_object(&name, enc, &c.lock().unwrap())))
+}
+
+/// Asynchronous read_log.
+pub fn read_log_async<A>(b: &A, c: SharedCrypto) -> StorageFuture<Vec<EventLogEntry>>
+    where A: AsyncBackend + Clone + Send + 'static
+{
+    Box::new(read_segments_async(b, c).map(|(log, _)| log))
+}
+
+/// The entries of all segments of the shared log and the number of the next segment. The
+/// segments are downloaded at the same time.
+fn read_segments_async<A>(b: &A, c: SharedCrypto) -> StorageFuture<(Vec<EventLogEntry>, usize)>
+    where A: AsyncBackend + Clone + Send + 'static
+{
+    let reader = b.clone();
+    Box::new(b.list(LOG_PREFIX).and_then(move |names| {
+        let numbers = segment_numbers(&names);
+        let next = numbers.last().map_or(0, |n| n + 1);
+        let segments = numbers.into_iter().map(|n| {
+            reader.get(&log_segment_name(n)).then(|r| {
+                match r {
+                    Ok(data) => Ok(Some(data)),
+                    // Moved into the backup log in the meantime.
+                    Err(StorageError::NotFound(_)) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+        });
+        future::join_all(segments.collect::<Vec<_>>()).and_then(move |segments| {
+            let mut log = Vec::new();
+            for data in segments.into_iter().filter_map(|d| d) {
+                log.extend(try!(decode_log(data, &c.lock().unwrap())));
+            }
+            Ok((log, next))
+        })
+    }))
+}
+
+/// Asynchronous append_log, retrying like it if another device added a segment meanwhile.
+pub fn append_log_async<A>(b: &A, c: SharedCrypto, after: &LogPosition, entries: Vec<EventLogEntry>)
+                           -> StorageFuture<LogPosition>
+    where A: AsyncBackend + Clone + Send + 'static
+{
+    let position = position_after(after, &entries);
+    if entries.is_empty() {
+        return Box::new(future::ok(position));
+    }
+    let enc = match encode_log(&entries, &mut c.lock().unwrap()) {
+        Ok(e) => e,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let b = b.clone();
+    Box::new(future::loop_fn(0, move |attempt| {
+        let (writer, enc, position) = (b.clone(), enc.clone(), position.clone());
+        b.list(LOG_PREFIX).and_then(move |names| {
+            let next = segment_numbers(&names).last().map_or(0, |n| n + 1);
+            writer.put_if(&log_segment_name(next), enc, None).then(move |r| {
+                match r {
+                    Ok(_) => Ok(Loop::Break(position)),
+                    Err(StorageError::Conflict(_)) if attempt < RETRIES => {
+                        Ok(Loop::Continue(attempt + 1))
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+        })
+    }))
+}
@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use rustc_serialize::hex::ToHex;
+use sodiumoxide::crypto::hash::sha256;
+
+/// Errors that can occur while talking to a storage provider.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The requested object does not exist on the provider.
+    NotFound(String),
+    /// The object was changed by someone else in the meantime.
+    Conflict(String),
+    /// The provider could not be reached or refused to serve us right now.
+    Unavailable(String),
+    /// The provider answered with something we don't understand.
+    Protocol(String),
+    /// The object was found but could not be decrypted or decoded.
+    Corrupt(String),
+    /// Error of the underlying io.
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::NotFound(ref s) => write!(f, "Object not found: {}", s),
+            StorageError::Conflict(ref s) => write!(f, "Conflicting change: {}", s),
+            StorageError::Unavailable(ref s) => write!(f, "Storage unavailable: {}", s),
+            StorageError::Protocol(ref s) => write!(f, "Protocol error: {}", s),
+            StorageError::Corrupt(ref s) => write!(f, "Corrupt object: {}", s),
+            StorageError::Io(ref e) => write!(f, "Io error: {}", e),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn description(&self) -> &str {
+        match *self {
+            StorageError::NotFound(_) => "object not found",
+            StorageError::Conflict(_) => "conflicting change",
+            StorageError::Unavailable(_) => "storage unavailable",
+            StorageError::Protocol(_) => "protocol error",
+            StorageError::Corrupt(_) => "corrupt object",
+            StorageError::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl StorageError {
+    /// True for errors that may go away if the operation is simply tried again, like timeouts
+    /// or an overloaded provider. Everything else fails the same way again.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            StorageError::Unavailable(_) => true,
+            StorageError::Io(ref e) => {
+                match e.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::Interrupted |
+                    io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionReset |
+                    io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionRefused |
+                    io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected |
+                    io::ErrorKind::UnexpectedEof => true,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> StorageError {
+        StorageError::Io(e)
+    }
+}
+
+/// Opaque token identifying one version of an object, e.g. an ETag or revision of the provider.
+/// It changes whenever the object changes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub struct Version(pub String);
+
+/// A Backend is a place where encrypted objects can be stored, e.g. a folder at some file
+/// hosting provider. Objects are addressed by a name which may contain `/` to group them.
+///
+/// Backends only ever see data that has already been encrypted by the CryptoManager, so they
+/// don't have to care about confidentiality.
+pub trait Backend {
+    /// Reads the whole object stored under the given name.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.get_versioned(name).map(|(data, _)| data)
+    }
+
+    /// Reads the whole object together with its current version.
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError>;
+
+    /// Returns the current version of the object. Backends that can ask for it without
+    /// downloading the object should do so.
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        self.get_versioned(name).map(|(_, version)| version)
+    }
+
+    /// Stores data under the given name, replacing the object if it already exists.
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Stores data under the given name only if the object is still at the expected version.
+    /// None means the object must not exist yet. Fails with StorageError::Conflict if the
+    /// object changed and returns the new version otherwise.
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError>;
+
+    /// Removes the object with the given name.
+    fn delete(&mut self, name: &str) -> Result<(), StorageError>;
+
+    /// Returns the names of all objects starting with prefix.
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError>;
+}
+
+impl<'a, B: Backend + ?Sized> Backend for &'a mut B {
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, StorageError> {
+        (**self).get(name)
+    }
+
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        (**self).get_versioned(name)
+    }
+
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        (**self).version(name)
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        (**self).put(name, data)
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        (**self).put_if(name, data, expected)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        (**self).delete(name)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        (**self).list(prefix)
+    }
+}
+
+/// State of a resumable upload. It can be saved, so an upload interrupted by a crash or a lost
+/// connection is continued later instead of starting over.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct UploadSession {
+    /// Name of the object being uploaded.
+    pub name: String,
+    /// Id of the upload at the provider.
+    pub id: String,
+    /// Number of bytes the provider has received.
+    pub offset: u64,
+}
+
+/// Backends that can upload an object in several parts, so a failed request only loses one
+/// part instead of the whole upload.
+pub trait Resumable: Backend {
+    /// Starts uploading an object with the given name.
+    fn start_upload(&mut self, name: &str) -> Result<UploadSession, StorageError>;
+
+    /// Sends data as the part starting at session.offset and advances the offset. If the
+    /// provider already has more data than the session says, e.g. because the answer to the
+    /// last part got lost, nothing is sent and the offset is corrected instead.
+    fn upload_part(&mut self, session: &mut UploadSession, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Stores everything received as the object, replacing the current one.
+    fn finish_upload(&mut self, session: &UploadSession) -> Result<Version, StorageError>;
+}
+
+/// Version derived from the content of an object, for backends without versions of their own.
+pub fn content_version(data: &[u8]) -> Version {
+    let sha256::Digest(d) = sha256::hash(data);
+    Version(d.to_hex())
+}
+
+/// Checks that an object name can safely be used as a relative path: not empty, no absolute
+/// paths, no `.` or `..` components and nothing starting with a dot at the top.
+pub fn check_name(name: &str) -> Result<(), StorageError> {
+    if name.is_empty() || name.starts_with('/') || name.starts_with('.') ||
+       name.split('/').any(|p| p == ".." || p == "." || p.is_empty()) {
+        return Err(StorageError::Protocol(format!("Invalid object name: {}", name)));
+    }
+    Ok(())
+}
+
+/// Optimistically replaces an object: reads it, lets f compute the new content from the
+/// current one (None if it doesn't exist) and writes it back if nobody changed it in the
+/// meantime. On a conflict this is retried up to retries times.
+pub fn update<B, F>(b: &mut B, name: &str, retries: usize, mut f: F) -> Result<Version, StorageError>
+    where B: Backend + ?Sized,
+          F: FnMut(Option<&[u8]>) -> Result<Vec<u8>, StorageError>
+{
+    let mut attempt = 0;
+    loop {
+        let current = match b.get_versioned(name) {
+            Ok((data, version)) => Some((data, version)),
+            Err(StorageError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+
+        let data = try!(f(current.as_ref().map(|c| &c.0[..])));
+        match b.put_if(name, &data, current.as_ref().map(|c| &c.1)) {
+            Err(StorageError::Conflict(_)) if attempt < retries => attempt += 1,
+            r => return r,
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use chrono::{DateTime, Duration, UTC};
+use uuid::Uuid;
+use crypto::CryptoManager;
+use domain::EventLogEntry;
+use storage::{load_from, save_to};
+use storage::backend::{Backend, StorageError};
+use storage::lock::{LockError, RemoteLock};
+use storage::log::{log_segment_name, log_segments, read_log_segment};
+
+/// Prefix of the backup log objects in the backend.
+pub const BACKUP_PREFIX: &'static str = "backup/";
+
+/// Name of the index of the backup log.
+pub const BACKUP_INDEX: &'static str = "backup/index";
+
+/// Entries moved from the front of the shared log into the backup log at once.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct BackupSegment {
+    pub id: String,
+    /// When the entries were moved out of the shared log.
+    pub archived: DateTime<UTC>,
+    /// Number of entries archived before this segment.
+    pub start: usize,
+    pub count: usize,
+    pub last_entry: Option<String>,
+}
+
+/// The segments of the backup log, oldest first.
+#[derive(Debug, Clone, PartialEq, Default, RustcEncodable, RustcDecodable)]
+pub struct BackupIndex {
+    pub segments: Vec<BackupSegment>,
+}
+
+impl BackupIndex {
+    /// Time of the latest sanitation. A device that synchronized before has to read the
+    /// backup log, entries it hasn't seen may have been moved there.
+    pub fn boundary(&self) -> Option<DateTime<UTC>> {
+        self.segments.last().map(|s| s.archived)
+    }
+
+    /// Number of entries in the backup log.
+    pub fn archived(&self) -> usize {
+        self.segments.last().map_or(0, |s| s.start + s.count)
+    }
+
+    /// The segments a device that last synchronized at last_sync has to read, as entries it
+    /// hasn't seen may have been moved there. All of them if it never synchronized.
+    pub fn archived_since(&self, last_sync: Option<DateTime<UTC>>) -> Vec<BackupSegment> {
+        self.segments
+            .iter()
+            .filter(|s| last_sync.map_or(true, |t| s.archived > t))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Keeps the shared log small by regularly moving all but its newest entries into encrypted
+/// segments of the backup log. Only the master device sanitizes, taking the remote lock, so no
+/// device reads the log while segments are moved.
+pub struct Sanitizer {
+    interval: Duration,
+    keep: usize,
+}
+
+impl Sanitizer {
+    /// Sanitizes once a week, keeping the newest 1000 entries in the shared log.
+    pub fn new() -> Sanitizer {
+        Sanitizer {
+            interval: Duration::weeks(1),
+            keep: 1000,
+        }
+    }
+
+    /// Sets how long after the last sanitation sanitize_if_due sanitizes again.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Sets how many entries at least stay in the shared log.
+    pub fn set_keep(&mut self, keep: usize) {
+        self.keep = keep;
+    }
+
+    /// Moves the oldest segments of the shared log into a new backup segment, keeping the
+    /// newest segments that hold at least the number of entries to keep. Returns None if the
+    /// shared log is small enough.
+    ///
+    /// The segment is recorded in the index before the segments of the shared log are
+    /// removed, so a failure in between leaves entries in both places instead of losing them.
+    /// Readers skip the copies in the shared log.
+    ///
+    /// lock is acquired for the sanitation and released afterwards.
+    pub fn sanitize<B: Backend>(&self, b: &mut B, c: &mut CryptoManager, lock: &mut RemoteLock)
+                                -> Result<Option<BackupSegment>, LockError> {
+        try!(lock.acquire(b));
+        let result = self.archive(b, c);
+        let released = lock.release(b);
+        let segment = try!(result);
+        try!(released);
+        Ok(segment)
+    }
+
+    /// sanitize while holding the lock.
+    fn archive<B: Backend>(&self, b: &mut B, c: &mut CryptoManager)
+                           -> Result<Option<BackupSegment>, StorageError> {
+        let mut segments = Vec::new();
+        for number in try!(log_segments(b)) {
+            segments.push((number, try!(read_log_segment(b, c, number))));
+        }
+        // The newest segment always stays, it tells devices where to append.
+        let mut kept = 0;
+        let mut split = segments.len();
+        while split > 0 && (split == segments.len() || kept < self.keep) {
+            split -= 1;
+            kept += segments[split].1.len();
+        }
+        if split == 0 {
+            return Ok(None);
+        }
+
+        let log = segments[..split].iter().flat_map(|s| s.1.iter().cloned()).collect::<Vec<_>>();
+        let mut index = try!(read_index(b, c));
+        let segment = BackupSegment {
+            id: Uuid::new_v4().to_string(),
+            archived: UTC::now(),
+            start: index.archived(),
+            count: log.len(),
+            last_entry: log.last().map(|e| e.id.clone()),
+        };
+        try!(save_to(b, &segment_name(&segment.id), c, &log));
+        index.segments.push(segment.clone());
+        try!(save_to(b, BACKUP_INDEX, c, &index));
+
+        for &(number, _) in segments[..split].iter() {
+            match b.delete(&log_segment_name(number)) {
+                Ok(()) | Err(StorageError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(segment))
+    }
+
+    /// Sanitizes if the last sanitation is older than the interval.
+    pub fn sanitize_if_due<B: Backend>(&self, b: &mut B, c: &mut CryptoManager,
+                                       lock: &mut RemoteLock)
+                                       -> Result<Option<BackupSegment>, LockError> {
+        let due = match try!(read_index(b, c)).boundary() {
+            Some(t) => t + self.interval <= UTC::now(),
+            None => true,
+        };
+        if due {
+            self.sanitize(b, c, lock)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Reads the index of the backup log. Without one the backup log is empty.
+pub fn read_index<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<BackupIndex, StorageError> {
+    match load_from(b, BACKUP_INDEX, c) {
+        Err(StorageError::NotFound(_)) => Ok(BackupIndex::default()),
+        r => r,
+    }
+}
+
+/// Reads the entries of a backup segment.
+pub fn read_segment<B: Backend>(b: &mut B, c: &CryptoManager, segment: &BackupSegment)
+                                -> Result<Vec<EventLogEntry>, StorageError> {
+    load_from(b, &segment_name(&segment.id), c)
+}
+
+/// The entries of the backup log followed by those of the shared log that aren't in it. Entries
+/// are in both while a sanitation is running or if it failed before removing them.
+pub fn join_log(mut archived: Vec<EventLogEntry>, log: &[EventLogEntry]) -> Vec<EventLogEntry> {
+    let ids = archived.iter().map(|e| e.id.clone()).collect::<HashSet<_>>();
+    archived.extend(log.iter().filter(|e| !ids.contains(&e.id)).cloned());
+    archived
+}
+
+/// Name of the object a backup segment is stored in.
+pub fn segment_name(id: &str) -> String {
+    format!("{}{}", BACKUP_PREFIX, id)
+}
@@ -0,0 +1,434 @@
+use std::collections::BTreeMap;
+use storage::asynchronous::SharedCrypto;
+use storage::backend::{content_version, Backend, StorageError, Version};
+use storage::{open_object, seal_object};
+
+/// Name of the index object in the local backend.
+const INDEX_NAME: &'static str = "index";
+
+/// Prefix of the cached objects in the local backend.
+const DATA_PREFIX: &'static str = "objects/";
+
+/// A write that couldn't be sent to the remote yet, with the version of the remote object it
+/// was based on, None if the object didn't exist.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum QueuedWrite {
+    Put(String, Option<Version>),
+    Delete(String, Option<Version>),
+}
+
+impl QueuedWrite {
+    fn name(&self) -> &str {
+        match *self {
+            QueuedWrite::Put(ref n, _) | QueuedWrite::Delete(ref n, _) => n,
+        }
+    }
+
+    fn base(&self) -> Option<&Version> {
+        match *self {
+            QueuedWrite::Put(_, ref v) | QueuedWrite::Delete(_, ref v) => v.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct Entry {
+    /// Version of the remote object, None if it isn't known.
+    version: Option<Version>,
+    size: u64,
+    /// Tick of the last access, for evicting the least recently used objects.
+    used: u64,
+    /// Changed locally and not yet written to the remote. Dirty objects are never evicted.
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct CacheIndex {
+    entries: BTreeMap<String, Entry>,
+    queue: Vec<QueuedWrite>,
+    tick: u64,
+}
+
+/// Errors after which the remote is considered offline.
+fn is_outage(e: &StorageError) -> bool {
+    match *e {
+        StorageError::Unavailable(_) | StorageError::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// Backend keeping a local copy of the objects of a remote backend, so reading works offline.
+///
+/// Every read asks the remote for the current version of the object first and only downloads
+/// it if the cached copy is outdated. If the remote can't be reached the cached copy is used.
+/// Writes that fail because the remote is offline are queued and sent with the next operation
+/// that reaches the remote again. They are only sent if the remote object didn't change in the
+/// meantime.
+///
+/// The objects are cached exactly as stored remotely, so they stay encrypted. The local
+/// backend additionally holds an index with names and versions of the cached objects, which
+/// is encrypted as well.
+pub struct CachedBackend<R: Backend, L: Backend> {
+    remote: R,
+    local: L,
+    crypto: SharedCrypto,
+    index: CacheIndex,
+    max_bytes: u64,
+    online: bool,
+}
+
+impl<R: Backend, L: Backend> CachedBackend<R, L> {
+    /// Creates a cache for remote, keeping its data in local. A cache that was already stored
+    /// in local is picked up again, including writes that were still queued. The index is
+    /// encrypted with c.
+    pub fn open(remote: R, mut local: L, c: SharedCrypto) -> Result<CachedBackend<R, L>, StorageError> {
+        let index = match local.get(INDEX_NAME) {
+            Ok(data) => try!(open_object(INDEX_NAME, data, &c.lock().unwrap())),
+            Err(StorageError::NotFound(_)) => {
+                CacheIndex {
+                    entries: BTreeMap::new(),
+                    queue: Vec::new(),
+                    tick: 0,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(CachedBackend {
+            remote: remote,
+            local: local,
+            crypto: c,
+            index: index,
+            max_bytes: 64 * 1024 * 1024,
+            online: true,
+        })
+    }
+
+    /// Limits the size of the cached objects. Objects that haven't been written to the remote
+    /// yet are kept even if they exceed the limit.
+    pub fn set_max_bytes(&mut self, max: u64) -> Result<(), StorageError> {
+        self.max_bytes = max;
+        self.evict()
+    }
+
+    /// Returns the size of all cached objects.
+    pub fn cached_bytes(&self) -> u64 {
+        self.index.entries.values().map(|e| e.size).fold(0, |a, b| a + b)
+    }
+
+    /// True unless the last attempt to reach the remote failed.
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
+    /// Returns the writes that are waiting for the remote.
+    pub fn queued(&self) -> &[QueuedWrite] {
+        &self.index.queue
+    }
+
+    /// Sends all queued writes to the remote, in the order they were made. Returns the number
+    /// of writes sent. Stops at the first failure and keeps the rest queued.
+    ///
+    /// Fails with StorageError::Conflict if the remote object changed since the write was
+    /// made. The write stays queued until it is discarded.
+    pub fn flush(&mut self) -> Result<usize, StorageError> {
+        let mut flushed = 0;
+        let mut result = Ok(());
+
+        while !self.index.queue.is_empty() {
+            let write = self.index.queue[0].clone();
+            let r = match write {
+                QueuedWrite::Put(ref name, ref base) => {
+                    match self.local.get(&data_name(name)) {
+                        Ok(data) => self.remote.put_if(name, &data, base.as_ref()).map(Some),
+                        Err(e) => Err(e),
+                    }
+                }
+                // Backends can't delete conditionally, so the version is compared first.
+                QueuedWrite::Delete(ref name, ref base) => {
+                    match self.remote.version(name) {
+                        Ok(ref v) if Some(v) != base.as_ref() => Err(StorageError::Conflict(name.clone())),
+                        Ok(_) => {
+                            match self.remote.delete(name) {
+                                Ok(()) | Err(StorageError::NotFound(_)) => Ok(None),
+                                Err(e) => Err(e),
+                            }
+                        }
+                        Err(StorageError::NotFound(_)) => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+
+            let version = match r {
+                Ok(v) => v,
+                Err(e) => {
+                    if is_outage(&e) {
+                        self.online = false;
+                    }
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            self.online = true;
+            if let QueuedWrite::Put(ref name, _) = write {
+                if let Some(entry) = self.index.entries.get_mut(name) {
+                    entry.dirty = false;
+                    entry.version = version;
+                }
+            }
+            self.index.queue.remove(0);
+            flushed += 1;
+        }
+
+        if flushed > 0 {
+            try!(self.save_index());
+        }
+        result.map(|_| flushed)
+    }
+
+    /// Gives up the queued write of the object, e.g. after flush failed with a Conflict. The
+    /// cached copy is dropped as well, so the object is read from the remote again.
+    pub fn discard(&mut self, name: &str) -> Result<(), StorageError> {
+        self.index.queue.retain(|w| w.name() != name);
+        try!(self.forget(name));
+        self.save_index()
+    }
+
+    /// Tries to send queued writes before talking to the remote.
+    fn try_flush(&mut self) {
+        if !self.index.queue.is_empty() {
+            let _ = self.flush();
+        }
+    }
+
+    /// The version of the remote object a write is based on: that of the first queued write if
+    /// there is one, as the remote hasn't seen the others yet.
+    fn base(&self, name: &str) -> Option<Version> {
+        match self.index.queue.iter().find(|w| w.name() == name) {
+            Some(w) => w.base().cloned(),
+            None => self.index.entries.get(name).and_then(|e| e.version.clone()),
+        }
+    }
+
+    fn queue(&mut self, write: QueuedWrite) -> Result<(), StorageError> {
+        self.index.queue.retain(|w| w.name() != write.name());
+        self.index.queue.push(write);
+        self.save_index()
+    }
+
+    /// Handles the result of a remote operation, returning None if the remote is offline.
+    fn remote_result<T>(&mut self, r: Result<T, StorageError>) -> Result<Option<T>, StorageError> {
+        match r {
+            Ok(t) => {
+                self.online = true;
+                Ok(Some(t))
+            }
+            Err(ref e) if is_outage(e) => {
+                self.online = false;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn cached(&mut self, name: &str) -> Result<Option<(Vec<u8>, Option<Version>)>, StorageError> {
+        let version = match self.index.entries.get(name) {
+            Some(e) => e.version.clone(),
+            None => return Ok(None),
+        };
+        let data = match self.local.get(&data_name(name)) {
+            Ok(d) => d,
+            Err(StorageError::NotFound(_)) => {
+                self.index.entries.remove(name);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        self.index.tick += 1;
+        let tick = self.index.tick;
+        if let Some(entry) = self.index.entries.get_mut(name) {
+            entry.used = tick;
+        }
+        Ok(Some((data, version)))
+    }
+
+    fn store(&mut self, name: &str, data: &[u8], version: Option<Version>, dirty: bool)
+             -> Result<(), StorageError> {
+        try!(self.local.put(&data_name(name), data));
+        self.index.tick += 1;
+        self.index.entries.insert(name.to_string(),
+                                  Entry {
+                                      version: version,
+                                      size: data.len() as u64,
+                                      used: self.index.tick,
+                                      dirty: dirty,
+                                  });
+        try!(self.evict());
+        self.save_index()
+    }
+
+    fn forget(&mut self, name: &str) -> Result<(), StorageError> {
+        if self.index.entries.remove(name).is_some() {
+            match self.local.delete(&data_name(name)) {
+                Ok(()) | Err(StorageError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+            try!(self.save_index());
+        }
+        Ok(())
+    }
+
+    /// Removes the least recently used objects until the cache fits into max_bytes.
+    fn evict(&mut self) -> Result<(), StorageError> {
+        while self.cached_bytes() > self.max_bytes {
+            let oldest = self.index
+                             .entries
+                             .iter()
+                             .filter(|&(_, e)| !e.dirty)
+                             .min_by_key(|&(_, e)| e.used)
+                             .map(|(n, _)| n.clone());
+            match oldest {
+                Some(name) => try!(self.forget(&name)),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn save_index(&mut self) -> Result<(), StorageError> {
+        let data = try!(seal_object(INDEX_NAME, &mut self.crypto.lock().unwrap(), &self.index));
+        self.local.put(INDEX_NAME, &data)
+    }
+}
+
+fn data_name(name: &str) -> String {
+    format!("{}{}", DATA_PREFIX, name)
+}
+
+impl<R: Backend, L: Backend> Backend for CachedBackend<R, L> {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        self.try_flush();
+        let cached = try!(self.cached(name));
+
+        // Local changes are newer than anything on the remote.
+        if self.index.entries.get(name).map(|e| e.dirty) == Some(true) {
+            if let Some((data, _)) = cached {
+                let version = content_version(&data);
+                return Ok((data, version));
+            }
+        }
+
+        let r = self.remote.version(name);
+        let remote_version = match self.remote_result(r) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return match cached {
+                    Some((data, Some(version))) => Ok((data, version)),
+                    Some((data, None)) => {
+                        let version = content_version(&data);
+                        Ok((data, version))
+                    }
+                    None => Err(StorageError::Unavailable(format!("{} is not cached", name))),
+                }
+            }
+            Err(StorageError::NotFound(n)) => {
+                try!(self.forget(name));
+                return Err(StorageError::NotFound(n));
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some((data, Some(version))) = cached {
+            if version == remote_version {
+                try!(self.save_index());
+                return Ok((data, version));
+            }
+        }
+
+        let r = self.remote.get_versioned(name);
+        match try!(self.remote_result(r)) {
+            Some((data, version)) => {
+                try!(self.store(name, &data, Some(version.clone()), false));
+                Ok((data, version))
+            }
+            None => Err(StorageError::Unavailable(format!("{} is not cached", name))),
+        }
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.try_flush();
+        if self.index.queue.is_empty() {
+            let r = self.remote.put(name, data);
+            if let Some(()) = try!(self.remote_result(r)) {
+                // Writes queued later are based on it.
+                let version = self.remote.version(name).ok();
+                return self.store(name, data, version, false);
+            }
+        }
+
+        let base = self.base(name);
+        try!(self.store(name, data, None, true));
+        self.queue(QueuedWrite::Put(name.to_string(), base))
+    }
+
+    /// Needs the remote, conditional writes can't be queued.
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        self.try_flush();
+        if !self.index.queue.is_empty() {
+            return Err(StorageError::Unavailable("Writes are still queued".to_string()));
+        }
+
+        let version = try!(self.remote.put_if(name, data, expected));
+        try!(self.store(name, data, Some(version.clone()), false));
+        Ok(version)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        self.try_flush();
+        if self.index.queue.is_empty() {
+            let r = self.remote.delete(name);
+            if let Some(()) = try!(self.remote_result(r)) {
+                return self.forget(name);
+            }
+        }
+
+        let base = self.base(name);
+        try!(self.forget(name));
+        self.queue(QueuedWrite::Delete(name.to_string(), base))
+    }
+
+    /// While offline only the cached objects are listed.
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.try_flush();
+        let r = self.remote.list(prefix);
+        let mut names = match try!(self.remote_result(r)) {
+            Some(names) => names,
+            None => {
+                self.index
+                    .entries
+                    .keys()
+                    .filter(|n| n.starts_with(prefix))
+                    .cloned()
+                    .collect()
+            }
+        };
+
+        for write in self.index.queue.iter() {
+            match *write {
+                QueuedWrite::Put(ref n, _) if n.starts_with(prefix) => {
+                    if !names.contains(n) {
+                        names.push(n.clone());
+                    }
+                }
+                QueuedWrite::Delete(ref n, _) => names.retain(|x| x != n),
+                _ => (),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
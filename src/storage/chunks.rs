@@ -0,0 +1,269 @@
+use std::collections::{BTreeSet, HashSet};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use sodiumoxide::crypto::auth;
+use crypto::CryptoManager;
+use storage::backend::{Backend, StorageError};
+
+/// Prefix of the chunk objects in the backend.
+pub const CHUNK_PREFIX: &'static str = "chunks/";
+
+/// Prefix of the manifest objects in the backend.
+pub const MANIFEST_PREFIX: &'static str = "manifests/";
+
+/// The list of chunks an object is made of. Stored encrypted like the chunks themselves.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunks: Vec<String>,
+}
+
+/// What a ChunkStore::put had to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PutStats {
+    /// Number of chunks the object consists of.
+    pub chunks: usize,
+    /// Number of chunks that weren't stored yet and had to be uploaded.
+    pub uploaded: usize,
+    /// Size of the uploaded chunks before encryption.
+    pub uploaded_bytes: u64,
+}
+
+/// Store splitting objects into chunks, so that changing a few bytes of a large object only
+/// uploads the chunks around the change, and chunks shared by several objects are only stored
+/// once.
+///
+/// The chunk boundaries are found with a rolling hash over the content, so inserting data
+/// doesn't shift all following chunks. Chunks are named by a keyed hash of their content and
+/// the rolling hash is derived from the key as well, so neither the names nor the sizes of the
+/// chunks tell anything about the content to someone without the key.
+pub struct ChunkStore<B: Backend> {
+    backend: B,
+    id_key: auth::Key,
+    gear: Vec<u64>,
+    min_size: usize,
+    mask: u64,
+    max_size: usize,
+}
+
+impl<B: Backend> ChunkStore<B> {
+    /// Creates a store on the backend, with keys derived from the key of c. Chunks are between
+    /// 2 KiB and 64 KiB, 8 KiB on average.
+    pub fn new(backend: B, c: &CryptoManager) -> ChunkStore<B> {
+        let id_key = id_key(c);
+
+        // Splitmix64 seeded from the key, to fill the table of the rolling hash.
+        let auth::Tag(seed) = auth::authenticate(b"cryptocontent chunk boundaries", &id_key);
+        let mut state = seed[..8].iter().fold(0u64, |s, &b| (s << 8) | b as u64);
+        let mut gear = Vec::with_capacity(256);
+        for _ in 0..256 {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            gear.push(z ^ (z >> 31));
+        }
+
+        ChunkStore {
+            backend: backend,
+            id_key: id_key,
+            gear: gear,
+            min_size: 2 * 1024,
+            mask: 8 * 1024 - 1,
+            max_size: 64 * 1024,
+        }
+    }
+
+    /// Changes the chunk sizes. average has to be a power of two. Objects stored with other
+    /// sizes can still be read, but won't share chunks with new ones.
+    pub fn set_chunk_sizes(&mut self, min: usize, average: usize, max: usize) {
+        assert!(average.is_power_of_two() && min <= average && average <= max);
+        self.min_size = min;
+        self.mask = average as u64 - 1;
+        self.max_size = max;
+    }
+
+    /// Returns the backend the chunks are stored in.
+    pub fn backend(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Stores data under name, uploading only the chunks that aren't stored yet.
+    pub fn put(&mut self, name: &str, data: &[u8], c: &mut CryptoManager)
+               -> Result<PutStats, StorageError> {
+        let stored = try!(self.stored_chunks());
+        let mut stats = PutStats {
+            chunks: 0,
+            uploaded: 0,
+            uploaded_bytes: 0,
+        };
+        let mut manifest = Manifest {
+            size: data.len() as u64,
+            chunks: Vec::new(),
+        };
+
+        let mut start = 0;
+        for end in self.boundaries(data) {
+            let chunk = &data[start..end];
+            start = end;
+            let id = self.chunk_id(chunk);
+            stats.chunks += 1;
+
+            if !stored.contains(&id) && !manifest.chunks.contains(&id) {
+                let enc = try!(encrypt(c, chunk, &id));
+                try!(self.backend.put(&chunk_name(&id), &enc));
+                stats.uploaded += 1;
+                stats.uploaded_bytes += chunk.len() as u64;
+            }
+            manifest.chunks.push(id);
+        }
+
+        // The manifest goes last, so it never references missing chunks.
+        let enc = try!(encrypt(c, json::encode(&manifest).unwrap().as_bytes(), name));
+        try!(self.backend.put(&manifest_name(name), &enc));
+        Ok(stats)
+    }
+
+    /// Reads the manifest of the object with the given name.
+    pub fn manifest(&mut self, name: &str, c: &CryptoManager) -> Result<Manifest, StorageError> {
+        let data = try!(self.backend.get(&manifest_name(name)));
+        let plain = try!(decrypt(c, data, name));
+        let plain = String::from_utf8_lossy(&plain).into_owned();
+        json::decode(&plain).map_err(|e| {
+            StorageError::Corrupt(format!("Manifest of {} does not decode: {}", name, e))
+        })
+    }
+
+    /// Loads the object with the given name and checks every chunk against its id.
+    pub fn get(&mut self, name: &str, c: &CryptoManager) -> Result<Vec<u8>, StorageError> {
+        let manifest = try!(self.manifest(name, c));
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        for id in manifest.chunks.iter() {
+            let enc = match self.backend.get(&chunk_name(id)) {
+                Ok(e) => e,
+                Err(StorageError::NotFound(_)) => {
+                    return Err(StorageError::Corrupt(format!("Chunk {} of {} is missing", id, name)))
+                }
+                Err(e) => return Err(e),
+            };
+            let chunk = try!(decrypt(c, enc, id));
+            if &self.chunk_id(&chunk) != id {
+                return Err(StorageError::Corrupt(format!("Chunk {} of {} does not match", id, name)));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        if data.len() as u64 != manifest.size {
+            return Err(StorageError::Corrupt(format!("{} has the wrong size", name)));
+        }
+        Ok(data)
+    }
+
+    /// Removes the object. Its chunks stay until gc is called.
+    pub fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        self.backend.delete(&manifest_name(name))
+    }
+
+    /// Returns the names of all stored objects.
+    pub fn list(&mut self) -> Result<Vec<String>, StorageError> {
+        let names = try!(self.backend.list(MANIFEST_PREFIX));
+        Ok(names.into_iter().map(|n| n[MANIFEST_PREFIX.len()..].to_string()).collect())
+    }
+
+    /// Removes all chunks no manifest refers to and returns how many were removed.
+    ///
+    /// A chunk uploaded by a put of another device is unreferenced until that put stored its
+    /// manifest, so gc must only run while holding the RemoteLock.
+    pub fn gc(&mut self, c: &CryptoManager) -> Result<usize, StorageError> {
+        let mut referenced = HashSet::new();
+        for name in try!(self.list()) {
+            let manifest = match self.manifest(&name, c) {
+                Ok(m) => m,
+                Err(StorageError::NotFound(_)) => continue,
+                // Better keep garbage than delete chunks of an object we can't read.
+                Err(e) => return Err(e),
+            };
+            referenced.extend(manifest.chunks);
+        }
+
+        let mut removed = 0;
+        for id in try!(self.stored_chunks()) {
+            if referenced.contains(&id) {
+                continue;
+            }
+            match self.backend.delete(&chunk_name(&id)) {
+                Ok(()) => removed += 1,
+                Err(StorageError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn stored_chunks(&mut self) -> Result<BTreeSet<String>, StorageError> {
+        let names = try!(self.backend.list(CHUNK_PREFIX));
+        Ok(names.into_iter().map(|n| n[CHUNK_PREFIX.len()..].to_string()).collect())
+    }
+
+    fn chunk_id(&self, chunk: &[u8]) -> String {
+        keyed_id(chunk, &self.id_key)
+    }
+
+    /// Returns the end of every chunk. A chunk ends where the rolling hash has all bits of the
+    /// mask cleared, but never before min_size and at max_size at the latest.
+    fn boundaries(&self, data: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut start = 0;
+        let mut hash = 0u64;
+        for (i, &b) in data.iter().enumerate() {
+            hash = (hash << 1).wrapping_add(self.gear[b as usize]);
+            let len = i + 1 - start;
+            if (len >= self.min_size && hash & self.mask == 0) || len >= self.max_size {
+                ends.push(i + 1);
+                start = i + 1;
+                hash = 0;
+            }
+        }
+        if start < data.len() || data.is_empty() {
+            ends.push(data.len());
+        }
+        ends
+    }
+}
+
+/// Returns the id a chunk with the given content is stored under.
+pub fn chunk_id(c: &CryptoManager, chunk: &[u8]) -> String {
+    keyed_id(chunk, &id_key(c))
+}
+
+fn id_key(c: &CryptoManager) -> auth::Key {
+    let auth::Tag(k) = auth::authenticate(b"cryptocontent chunk id", &auth::Key(c.symkey.0));
+    auth::Key(k)
+}
+
+fn keyed_id(chunk: &[u8], key: &auth::Key) -> String {
+    let auth::Tag(t) = auth::authenticate(chunk, key);
+    t.to_hex()
+}
+
+fn chunk_name(id: &str) -> String {
+    format!("{}{}", CHUNK_PREFIX, id)
+}
+
+fn manifest_name(name: &str) -> String {
+    format!("{}{}", MANIFEST_PREFIX, name)
+}
+
+fn encrypt(c: &mut CryptoManager, data: &[u8], name: &str) -> Result<Vec<u8>, StorageError> {
+    match c.encrypt_bytes(data) {
+        Some(e) => Ok(e),
+        None => Err(StorageError::Protocol(format!("Can't encrypt {}", name))),
+    }
+}
+
+fn decrypt(c: &CryptoManager, data: Vec<u8>, name: &str) -> Result<Vec<u8>, StorageError> {
+    match c.decrypt_bytes(data) {
+        Some(p) => Ok(p),
+        None => Err(StorageError::Corrupt(format!("{} does not decrypt", name))),
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, UTC};
+use clock::Timestamp;
+use crypto::CryptoManager;
+use storage::{load_from, save_to};
+use storage::backend::{Backend, StorageError};
+use storage::log::LogPosition;
+
+/// Prefix of the published cursors in the backend.
+pub const CURSOR_PREFIX: &'static str = "cursors/";
+
+/// How far a device got with synchronizing: TS_S of the example in the module documentation.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct SyncCursor {
+    pub device: String,
+    /// Position in the shared log after the last synchronization.
+    pub position: LogPosition,
+    /// Number of the next segment of the shared log to read.
+    pub segment: usize,
+    /// The clock of the device after the last synchronization, so it doesn't go back after a
+    /// restart.
+    pub clock: Option<Timestamp>,
+    pub synced: Option<DateTime<UTC>>,
+    /// What the log up to the position says about the objects. None until the log was read,
+    /// the next synchronization reads the whole log then.
+    pub seen: Option<SeenLog>,
+}
+
+/// What the shared log up to a cursor says about every object: the latest timestamp, to skip
+/// outdated changes, and the id of the last entry, the base of conflicting changes.
+#[derive(Debug, Clone, PartialEq, Default, RustcEncodable, RustcDecodable)]
+pub struct SeenLog {
+    pub latest: HashMap<String, Timestamp>,
+    pub last: HashMap<String, String>,
+}
+
+impl SyncCursor {
+    /// The cursor of a device that hasn't synchronized yet.
+    pub fn new(device: &str) -> SyncCursor {
+        SyncCursor {
+            device: device.to_string(),
+            position: LogPosition::end_of(&[]),
+            segment: 0,
+            clock: None,
+            synced: None,
+            seen: None,
+        }
+    }
+}
+
+/// Stores cursor under the name of its device. Devices save their cursor locally, to continue
+/// where they left off, and publish it in the repository, so the others can see how far it has
+/// caught up.
+pub fn save_cursor<B: Backend>(b: &mut B, c: &mut CryptoManager, cursor: &SyncCursor)
+                               -> Result<(), StorageError> {
+    save_to(b, &cursor_name(&cursor.device), c, cursor)
+}
+
+/// Loads the cursor of a device, None if it hasn't saved one.
+pub fn load_cursor<B: Backend>(b: &mut B, c: &CryptoManager, device: &str)
+                               -> Result<Option<SyncCursor>, StorageError> {
+    match load_from(b, &cursor_name(device), c) {
+        Ok(cursor) => Ok(Some(cursor)),
+        Err(StorageError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the cursors of all devices, ordered by device.
+pub fn read_cursors<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<Vec<SyncCursor>, StorageError> {
+    let mut cursors = Vec::new();
+    for name in try!(b.list(CURSOR_PREFIX)) {
+        if let Some(cursor) = try!(load_cursor(b, c, &name[CURSOR_PREFIX.len()..])) {
+            cursors.push(cursor);
+        }
+    }
+    cursors.sort_by(|a: &SyncCursor, b: &SyncCursor| a.device.cmp(&b.device));
+    Ok(cursors)
+}
+
+/// Name of the object the cursor of device is stored under.
+pub fn cursor_name(device: &str) -> String {
+    format!("{}{}", CURSOR_PREFIX, device)
+}
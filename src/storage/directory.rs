@@ -0,0 +1,120 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use storage::backend::{check_name, content_version, Backend, StorageError, Version};
+
+/// Backend storing objects as files below a local directory, e.g. a folder that is synchronized
+/// by some other program or the local cache of a CachedBackend.
+pub struct DirectoryBackend {
+    root: PathBuf,
+}
+
+impl DirectoryBackend {
+    /// Uses the directory at root, creating it if it doesn't exist.
+    pub fn new(root: &Path) -> Result<DirectoryBackend, StorageError> {
+        try!(fs::create_dir_all(root));
+        Ok(DirectoryBackend { root: root.to_path_buf() })
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        try!(check_name(name));
+        let mut file = match File::open(self.root.join(name)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(StorageError::NotFound(name.to_string()))
+            }
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+        Ok(data)
+    }
+
+    /// Writes to a temporary file first, so readers never see half written objects.
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        try!(check_name(name));
+        let path = self.root.join(name);
+        let parent = path.parent().unwrap().to_path_buf();
+        try!(fs::create_dir_all(&parent));
+
+        let tmp = parent.join(format!(".tmp-{}", Uuid::new_v4()));
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(data));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, &path));
+        Ok(())
+    }
+
+    fn walk(&self, dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<(), StorageError> {
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let name = if prefix.is_empty() {
+                file_name
+            } else {
+                format!("{}/{}", prefix, file_name)
+            };
+            if try!(entry.file_type()).is_dir() {
+                try!(self.walk(&entry.path(), &name, names));
+            } else {
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for DirectoryBackend {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        let data = try!(self.read(name));
+        let version = content_version(&data);
+        Ok((data, version))
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.write(name, data)
+    }
+
+    /// Not atomic with respect to other processes writing the same directory.
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        let current = match self.read(name) {
+            Ok(d) => Some(content_version(&d)),
+            Err(StorageError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if current.as_ref() != expected {
+            return Err(StorageError::Conflict(name.to_string()));
+        }
+        try!(self.write(name, data));
+        Ok(content_version(data))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        try!(check_name(name));
+        match fs::remove_file(self.root.join(name)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(StorageError::NotFound(name.to_string()))
+            }
+            Err(e) => Err(StorageError::Io(e)),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut names = Vec::new();
+        let root = self.root.clone();
+        try!(self.walk(&root, "", &mut names));
+        names.retain(|n| n.starts_with(prefix));
+        names.sort();
+        Ok(names)
+    }
+}
@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::UTC;
+use rustc_serialize::json::Json;
+use storage::backend::{Backend, Resumable, StorageError, UploadSession, Version};
+
+const API_URL: &'static str = "https://api.dropboxapi.com/2";
+const CONTENT_URL: &'static str = "https://content.dropboxapi.com/2";
+const TOKEN_URL: &'static str = "https://api.dropboxapi.com/oauth2/token";
+
+/// Objects bigger than this are uploaded with an upload session in several requests.
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// A single HTTP request as it is handed to a Transport.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The answer of the server to a HttpRequest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl HttpResponse {
+    /// Creates a response with the given status and body and no headers.
+    pub fn new(status: u16, body: &[u8]) -> HttpResponse {
+        HttpResponse {
+            status: status,
+            headers: Vec::new(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// Returns the value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    headers.iter().find(|h| h.0.to_lowercase() == name).map(|h| &h.1[..])
+}
+
+/// Everything that can send a HttpRequest over the wire. The DropboxBackend doesn't bring its
+/// own HTTP client, so applications can plug in whatever they already use and tests can replay
+/// recorded responses.
+pub trait Transport {
+    fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse, StorageError>;
+}
+
+impl<'a, T: Transport + ?Sized> Transport for &'a mut T {
+    fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse, StorageError> {
+        (**self).send(request)
+    }
+}
+
+/// OAuth2 credentials of a Dropbox app. The access token is short lived and refreshed with the
+/// refresh token when it has expired or Dropbox rejects it.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct DropboxCredentials {
+    pub app_key: String,
+    pub app_secret: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_at: Option<DateTime<UTC>>,
+}
+
+/// Backend storing objects as files in a folder of a Dropbox, using the Dropbox v2 HTTP API.
+///
+/// The backend remembers the revision of every file it has seen, which is used by put_if_rev
+/// to only overwrite a file if nobody else changed it in the meantime.
+pub struct DropboxBackend<T: Transport> {
+    transport: T,
+    credentials: DropboxCredentials,
+    root: String,
+    revs: HashMap<String, String>,
+    chunk_size: usize,
+}
+
+impl<T: Transport> DropboxBackend<T> {
+    /// Creates a new backend storing all objects below root, e.g. "/cryptocontent". An empty
+    /// root uses the top folder of the app.
+    pub fn new(transport: T, credentials: DropboxCredentials, root: &str) -> DropboxBackend<T> {
+        DropboxBackend {
+            transport: transport,
+            credentials: credentials,
+            root: root.trim_right_matches('/').to_string(),
+            revs: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Sets the size of the parts used for upload sessions.
+    pub fn set_chunk_size(&mut self, size: usize) {
+        assert!(size > 0);
+        self.chunk_size = size;
+    }
+
+    /// Returns the current credentials. They change whenever the access token gets refreshed,
+    /// so they should be saved again after using the backend.
+    pub fn credentials(&self) -> &DropboxCredentials {
+        &self.credentials
+    }
+
+    /// Returns the last known revision of an object, if there is one.
+    pub fn rev(&self, name: &str) -> Option<&str> {
+        self.revs.get(name).map(|r| &r[..])
+    }
+
+    /// Uploads the object only if its current revision on Dropbox is rev. None means the
+    /// object must not exist yet. Fails with StorageError::Conflict otherwise and returns the
+    /// new revision on success.
+    pub fn put_if_rev(&mut self, name: &str, data: &[u8], rev: Option<&str>)
+                      -> Result<String, StorageError> {
+        let mode = match rev {
+            Some(r) => {
+                let mut m = BTreeMap::new();
+                m.insert(".tag".to_string(), Json::String("update".to_string()));
+                m.insert("update".to_string(), Json::String(r.to_string()));
+                Json::Object(m)
+            }
+            None => Json::String("add".to_string()),
+        };
+        self.upload(name, data, mode)
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.root, name)
+    }
+
+    fn refresh(&mut self) -> Result<(), StorageError> {
+        let body = format!("grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}",
+                           form_encode(&self.credentials.refresh_token),
+                           form_encode(&self.credentials.app_key),
+                           form_encode(&self.credentials.app_secret));
+        let request = HttpRequest {
+            method: "POST".to_string(),
+            url: TOKEN_URL.to_string(),
+            headers: vec![("Content-Type".to_string(),
+                           "application/x-www-form-urlencoded".to_string())],
+            body: body.into_bytes(),
+        };
+        let response = try!(self.transport.send(&request));
+        if response.status != 200 {
+            return Err(StorageError::Unavailable(format!("Token refresh failed with HTTP {}",
+                                                         response.status)));
+        }
+
+        let json = try!(parse_json(&response.body));
+        let token = match json.find("access_token").and_then(|t| t.as_string()) {
+            Some(t) => t.to_string(),
+            None => return Err(StorageError::Protocol("Token response without access_token".to_string())),
+        };
+        self.credentials.access_token = token;
+        self.credentials.expires_at = json.find("expires_in")
+                                          .and_then(|e| e.as_i64())
+                                          .map(|e| UTC::now() + Duration::seconds(e - 60));
+        Ok(())
+    }
+
+    /// Sends a request to Dropbox and fails unless it succeeded.
+    fn call(&mut self, url: &str, headers: Vec<(String, String)>, body: Vec<u8>)
+            -> Result<HttpResponse, StorageError> {
+        let response = try!(self.send(url, headers, body));
+        check_status(response)
+    }
+
+    /// Sends a request to Dropbox, refreshing the access token if it expired.
+    fn send(&mut self, url: &str, mut headers: Vec<(String, String)>, body: Vec<u8>)
+            -> Result<HttpResponse, StorageError> {
+        let expired = match self.credentials.expires_at {
+            Some(t) => t <= UTC::now(),
+            None => false,
+        };
+        if expired {
+            try!(self.refresh());
+        }
+
+        headers.push(("Authorization".to_string(), String::new()));
+        let mut request = HttpRequest {
+            method: "POST".to_string(),
+            url: url.to_string(),
+            headers: headers,
+            body: body,
+        };
+
+        let mut response = None;
+        for attempt in 0..2 {
+            if attempt > 0 {
+                try!(self.refresh());
+            }
+            request.headers.last_mut().unwrap().1 = format!("Bearer {}",
+                                                            self.credentials.access_token);
+            let r = try!(self.transport.send(&request));
+            if r.status != 401 {
+                response = Some(r);
+                break;
+            }
+        }
+
+        match response {
+            Some(r) => Ok(r),
+            None => Err(StorageError::Unavailable("Dropbox rejected the access token".to_string())),
+        }
+    }
+
+    /// Calls an RPC endpoint taking and returning JSON.
+    fn rpc(&mut self, endpoint: &str, arg: Json) -> Result<Json, StorageError> {
+        let url = format!("{}{}", API_URL, endpoint);
+        let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        let response = try!(self.call(&url, headers, arg.to_string().into_bytes()));
+        parse_json(&response.body)
+    }
+
+    /// Calls a content endpoint, which takes its argument in the Dropbox-API-Arg header.
+    fn content(&mut self, endpoint: &str, arg: Json, body: Vec<u8>)
+               -> Result<HttpResponse, StorageError> {
+        let response = try!(self.send_content(endpoint, arg, body));
+        check_status(response)
+    }
+
+    fn send_content(&mut self, endpoint: &str, arg: Json, body: Vec<u8>)
+                    -> Result<HttpResponse, StorageError> {
+        let url = format!("{}{}", CONTENT_URL, endpoint);
+        let headers = vec![("Dropbox-API-Arg".to_string(), header_safe(&arg.to_string())),
+                           ("Content-Type".to_string(), "application/octet-stream".to_string())];
+        self.send(&url, headers, body)
+    }
+
+    fn commit_info(&self, name: &str, mode: Json) -> Json {
+        json_object(vec![("path", Json::String(self.path(name))),
+                         ("mode", mode),
+                         ("autorename", Json::Boolean(false)),
+                         ("mute", Json::Boolean(true))])
+    }
+
+    /// Takes the revision from the metadata Dropbox returns for an upload.
+    fn uploaded(&mut self, name: &str, response: &HttpResponse) -> Result<String, StorageError> {
+        let meta = try!(parse_json(&response.body));
+        let rev = match meta.find("rev").and_then(|r| r.as_string()) {
+            Some(r) => r.to_string(),
+            None => return Err(StorageError::Protocol("Upload result without rev".to_string())),
+        };
+        self.revs.insert(name.to_string(), rev.clone());
+        Ok(rev)
+    }
+
+    fn upload(&mut self, name: &str, data: &[u8], mode: Json) -> Result<String, StorageError> {
+        let commit = self.commit_info(name, mode);
+        let response = if data.len() <= self.chunk_size {
+            try!(self.content("/files/upload", commit, data.to_vec()))
+        } else {
+            try!(self.upload_session(data, commit))
+        };
+        self.uploaded(name, &response)
+    }
+
+    /// Uploads data in parts of chunk_size and commits it at the end.
+    fn upload_session(&mut self, data: &[u8], commit: Json) -> Result<HttpResponse, StorageError> {
+        let mut chunks = data.chunks(self.chunk_size);
+        let first = chunks.next().unwrap_or(&[]);
+
+        let start = try!(self.content("/files/upload_session/start",
+                                      json_object(vec![("close", Json::Boolean(false))]),
+                                      first.to_vec()));
+        let session = try!(parse_json(&start.body));
+        let session_id = match session.find("session_id").and_then(|s| s.as_string()) {
+            Some(s) => s.to_string(),
+            None => return Err(StorageError::Protocol("Upload session without id".to_string())),
+        };
+
+        let mut offset = first.len();
+        let cursor = |offset: usize| {
+            json_object(vec![("session_id", Json::String(session_id.clone())),
+                             ("offset", Json::U64(offset as u64))])
+        };
+
+        for chunk in chunks {
+            let arg = json_object(vec![("cursor", cursor(offset)),
+                                       ("close", Json::Boolean(false))]);
+            try!(self.content("/files/upload_session/append_v2", arg, chunk.to_vec()));
+            offset += chunk.len();
+        }
+
+        let arg = json_object(vec![("cursor", cursor(offset)), ("commit", commit)]);
+        self.content("/files/upload_session/finish", arg, Vec::new())
+    }
+
+    /// Stores the revisions of the file entries in a list_folder result and returns their names.
+    fn collect_entries(&mut self, json: &Json, prefix: &str, names: &mut Vec<String>) {
+        let entries = match json.find("entries").and_then(|e| e.as_array()) {
+            Some(e) => e,
+            None => return,
+        };
+
+        for entry in entries {
+            if entry.find(".tag").and_then(|t| t.as_string()) != Some("file") {
+                continue;
+            }
+            let path = match entry.find("path_display").and_then(|p| p.as_string()) {
+                Some(p) => p,
+                None => continue,
+            };
+            if path.len() <= self.root.len() + 1 {
+                continue;
+            }
+            let name = path[self.root.len() + 1..].to_string();
+            if !name.starts_with(prefix) {
+                continue;
+            }
+            if let Some(rev) = entry.find("rev").and_then(|r| r.as_string()) {
+                self.revs.insert(name.clone(), rev.to_string());
+            }
+            names.push(name);
+        }
+    }
+}
+
+impl<T: Transport> Backend for DropboxBackend<T> {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        let arg = json_object(vec![("path", Json::String(self.path(name)))]);
+        let response = try!(self.content("/files/download", arg, Vec::new()));
+
+        let rev = response.header("Dropbox-API-Result")
+                          .and_then(|m| Json::from_str(m).ok())
+                          .and_then(|m| m.find("rev").and_then(|r| r.as_string()).map(|r| r.to_string()));
+        let rev = match rev {
+            Some(r) => r,
+            None => return Err(StorageError::Protocol("Download without rev".to_string())),
+        };
+        self.revs.insert(name.to_string(), rev.clone());
+        Ok((response.body, Version(rev)))
+    }
+
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        let arg = json_object(vec![("path", Json::String(self.path(name)))]);
+        let meta = try!(self.rpc("/files/get_metadata", arg));
+        let rev = match meta.find("rev").and_then(|r| r.as_string()) {
+            Some(r) => r.to_string(),
+            // Folders have no revision.
+            None => return Err(StorageError::NotFound(name.to_string())),
+        };
+        self.revs.insert(name.to_string(), rev.clone());
+        Ok(Version(rev))
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        try!(self.upload(name, data, Json::String("overwrite".to_string())));
+        Ok(())
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        let rev = try!(self.put_if_rev(name, data, expected.map(|v| &v.0[..])));
+        Ok(Version(rev))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        let arg = json_object(vec![("path", Json::String(self.path(name)))]);
+        try!(self.rpc("/files/delete_v2", arg));
+        self.revs.remove(name);
+        Ok(())
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let arg = json_object(vec![("path", Json::String(self.root.clone())),
+                                   ("recursive", Json::Boolean(true))]);
+        let mut json = match self.rpc("/files/list_folder", arg) {
+            Ok(j) => j,
+            // The root folder is only created with the first upload.
+            Err(StorageError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut names = Vec::new();
+        loop {
+            self.collect_entries(&json, prefix, &mut names);
+
+            if json.find("has_more").and_then(|h| h.as_boolean()) != Some(true) {
+                break;
+            }
+            let cursor = match json.find("cursor").and_then(|c| c.as_string()) {
+                Some(c) => c.to_string(),
+                None => return Err(StorageError::Protocol("Listing without cursor".to_string())),
+            };
+            json = try!(self.rpc("/files/list_folder/continue",
+                                 json_object(vec![("cursor", Json::String(cursor))])));
+        }
+
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Uses Dropbox upload sessions, which are kept for a week.
+impl<T: Transport> Resumable for DropboxBackend<T> {
+    fn start_upload(&mut self, name: &str) -> Result<UploadSession, StorageError> {
+        let start = try!(self.content("/files/upload_session/start",
+                                      json_object(vec![("close", Json::Boolean(false))]),
+                                      Vec::new()));
+        let session = try!(parse_json(&start.body));
+        match session.find("session_id").and_then(|s| s.as_string()) {
+            Some(id) => {
+                Ok(UploadSession {
+                    name: name.to_string(),
+                    id: id.to_string(),
+                    offset: 0,
+                })
+            }
+            None => Err(StorageError::Protocol("Upload session without id".to_string())),
+        }
+    }
+
+    fn upload_part(&mut self, session: &mut UploadSession, data: &[u8]) -> Result<(), StorageError> {
+        let arg = json_object(vec![("cursor", cursor(session)), ("close", Json::Boolean(false))]);
+        let response = try!(self.send_content("/files/upload_session/append_v2", arg, data.to_vec()));
+        if response.status == 409 {
+            let correct = parse_json(&response.body)
+                              .ok()
+                              .and_then(|j| j.find_path(&["error", "correct_offset"]).and_then(|o| o.as_u64()));
+            if let Some(offset) = correct {
+                session.offset = offset;
+                return Ok(());
+            }
+        }
+        try!(check_status(response));
+        session.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn finish_upload(&mut self, session: &UploadSession) -> Result<Version, StorageError> {
+        let commit = self.commit_info(&session.name, Json::String("overwrite".to_string()));
+        let arg = json_object(vec![("cursor", cursor(session)), ("commit", commit)]);
+        let response = try!(self.content("/files/upload_session/finish", arg, Vec::new()));
+        let rev = try!(self.uploaded(&session.name, &response));
+        Ok(Version(rev))
+    }
+}
+
+fn cursor(session: &UploadSession) -> Json {
+    json_object(vec![("session_id", Json::String(session.id.clone())),
+                     ("offset", Json::U64(session.offset))])
+}
+
+/// Turns unsuccessful answers into errors.
+fn check_status(response: HttpResponse) -> Result<HttpResponse, StorageError> {
+    match response.status {
+        200 => Ok(response),
+        409 => Err(api_error(&response)),
+        s if s == 429 || s >= 500 => {
+            Err(StorageError::Unavailable(format!("Dropbox answered with HTTP {}", s)))
+        }
+        s => {
+            Err(StorageError::Protocol(format!("Unexpected HTTP {}: {}",
+                                               s,
+                                               String::from_utf8_lossy(&response.body))))
+        }
+    }
+}
+
+fn json_object(fields: Vec<(&str, Json)>) -> Json {
+    let mut m = BTreeMap::new();
+    for (k, v) in fields {
+        m.insert(k.to_string(), v);
+    }
+    Json::Object(m)
+}
+
+fn parse_json(body: &[u8]) -> Result<Json, StorageError> {
+    let s = match ::std::str::from_utf8(body) {
+        Ok(s) => s,
+        Err(_) => return Err(StorageError::Protocol("Response is not valid utf8".to_string())),
+    };
+    Json::from_str(s).map_err(|e| StorageError::Protocol(format!("Invalid JSON: {}", e)))
+}
+
+/// Turns an endpoint specific error (HTTP 409) into a StorageError.
+fn api_error(response: &HttpResponse) -> StorageError {
+    let summary = parse_json(&response.body)
+                      .ok()
+                      .and_then(|j| j.find("error_summary").and_then(|s| s.as_string()).map(|s| s.to_string()))
+                      .unwrap_or_else(|| String::from_utf8_lossy(&response.body).into_owned());
+
+    if summary.contains("not_found") {
+        StorageError::NotFound(summary)
+    } else if summary.contains("conflict") {
+        StorageError::Conflict(summary)
+    } else {
+        StorageError::Protocol(summary)
+    }
+}
+
+/// HTTP headers may only contain ASCII, so everything else in the JSON argument has to be
+/// escaped.
+fn header_safe(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        if (c as u32) < 0x7f {
+            out.push(c);
+            continue;
+        }
+        let n = c as u32;
+        if n < 0x10000 {
+            out.push_str(&format!("\\u{:04x}", n));
+        } else {
+            let n = n - 0x10000;
+            out.push_str(&format!("\\u{:04x}\\u{:04x}", 0xd800 + (n >> 10), 0xdc00 + (n & 0x3ff)));
+        }
+    }
+    out
+}
+
+fn form_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
@@ -0,0 +1,271 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use chrono::{DateTime, TimeZone, UTC};
+use storage::backend::{check_name, content_version, Backend, StorageError, Version};
+
+/// A single commit touching an object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revision {
+    pub id: String,
+    pub time: DateTime<UTC>,
+    pub message: String,
+}
+
+/// Backend storing objects as files in a git repository which is synchronized with a remote.
+///
+/// put and delete only change the local working copy. Other devices see the changes once they
+/// have been committed and pushed with commit, which is meant to happen once per
+/// synchronization. The git command line tool has to be installed.
+pub struct GitBackend {
+    workdir: PathBuf,
+    branch: String,
+}
+
+impl GitBackend {
+    /// Opens the working copy at workdir, cloning it from remote if it doesn't exist yet. The
+    /// remote may be empty, e.g. a freshly created bare repository.
+    pub fn open(workdir: &Path, remote: &str) -> Result<GitBackend, StorageError> {
+        if !workdir.join(".git").exists() {
+            let output = try!(Command::new("git")
+                                  .arg("clone")
+                                  .arg("--quiet")
+                                  .arg(remote)
+                                  .arg(workdir)
+                                  .env("GIT_TERMINAL_PROMPT", "0")
+                                  .output());
+            try!(check(output));
+        }
+
+        Ok(GitBackend {
+            workdir: workdir.to_path_buf(),
+            branch: "master".to_string(),
+        })
+    }
+
+    /// Sets the branch of the remote that is used, master by default.
+    pub fn set_branch(&mut self, branch: &str) {
+        self.branch = branch.to_string();
+    }
+
+    /// Fetches the remote and fast forwards the working copy to it. Fails with
+    /// StorageError::Conflict if there are local commits that aren't on the remote.
+    pub fn pull(&mut self) -> Result<(), StorageError> {
+        try!(self.git(&["fetch", "--quiet", "origin"]));
+        let remote = format!("origin/{}", self.branch);
+        if !try!(self.has_ref(&remote)) {
+            // Nothing has been pushed to the remote yet.
+            return Ok(());
+        }
+
+        let output = try!(self.run(&["merge", "--ff-only", "--quiet", &remote]));
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(StorageError::Conflict(format!("Can't fast forward to {}: {}",
+                                               remote,
+                                               String::from_utf8_lossy(&output.stderr))))
+        }
+    }
+
+    /// Commits all changes as one commit and pushes it. Returns the id of the new commit, or
+    /// None if nothing changed.
+    ///
+    /// If somebody else pushed in the meantime the push is rejected with
+    /// StorageError::Conflict. The local commit stays in place, so the caller can either
+    /// reset_to_remote and redo its changes or merge them itself.
+    pub fn commit(&mut self, message: &str) -> Result<Option<String>, StorageError> {
+        let status = try!(self.git(&["status", "--porcelain"]));
+        if status.stdout.is_empty() {
+            return Ok(None);
+        }
+
+        try!(self.git(&["add", "--all"]));
+        try!(self.git(&["-c",
+                        "user.name=cryptocontent",
+                        "-c",
+                        "user.email=cryptocontent@localhost",
+                        "commit",
+                        "--quiet",
+                        "-m",
+                        message]));
+        let id = try!(self.head());
+
+        let refspec = format!("HEAD:refs/heads/{}", self.branch);
+        let output = try!(self.run(&["push", "--quiet", "--porcelain", "origin", &refspec]));
+        if output.status.success() {
+            return Ok(Some(id));
+        }
+
+        let out = format!("{}{}",
+                          String::from_utf8_lossy(&output.stdout),
+                          String::from_utf8_lossy(&output.stderr));
+        if out.contains("[rejected]") || out.contains("non-fast-forward") ||
+           out.contains("fetch first") {
+            Err(StorageError::Conflict(format!("Push of {} rejected: {}", id, out.trim())))
+        } else {
+            Err(StorageError::Unavailable(format!("Push failed: {}", out.trim())))
+        }
+    }
+
+    /// Throws away all local commits and changes and moves to the state of the remote.
+    pub fn reset_to_remote(&mut self) -> Result<(), StorageError> {
+        try!(self.git(&["fetch", "--quiet", "origin"]));
+        let remote = format!("origin/{}", self.branch);
+        if try!(self.has_ref(&remote)) {
+            try!(self.git(&["reset", "--quiet", "--hard", &remote]));
+        } else {
+            try!(self.git(&["reset", "--quiet", "--hard"]));
+        }
+        try!(self.git(&["clean", "--quiet", "-f", "-d"]));
+        Ok(())
+    }
+
+    /// Returns all commits that changed the object, the newest first.
+    pub fn history(&mut self, name: &str) -> Result<Vec<Revision>, StorageError> {
+        try!(check_name(name));
+        if !try!(self.has_ref("HEAD")) {
+            return Ok(Vec::new());
+        }
+
+        let output = try!(self.git(&["log", "--format=%H%x00%ct%x00%s", "--", name]));
+        let mut revisions = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let fields: Vec<&str> = line.splitn(3, '\0').collect();
+            if fields.len() != 3 {
+                continue;
+            }
+            let time = match fields[1].parse::<i64>() {
+                Ok(t) => UTC.timestamp(t, 0),
+                Err(_) => return Err(StorageError::Protocol(format!("Invalid git log: {}", line))),
+            };
+            revisions.push(Revision {
+                id: fields[0].to_string(),
+                time: time,
+                message: fields[2].to_string(),
+            });
+        }
+        Ok(revisions)
+    }
+
+    /// Reads the object as it was in the given commit.
+    pub fn get_at(&mut self, name: &str, revision: &str) -> Result<Vec<u8>, StorageError> {
+        try!(check_name(name));
+        let spec = format!("{}:{}", revision, name);
+        let output = try!(self.run(&["show", &spec]));
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(StorageError::NotFound(spec))
+        }
+    }
+
+    fn head(&self) -> Result<String, StorageError> {
+        let output = try!(self.git(&["rev-parse", "HEAD"]));
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn has_ref(&self, reference: &str) -> Result<bool, StorageError> {
+        let output = try!(self.run(&["rev-parse", "--verify", "--quiet", reference]));
+        Ok(output.status.success())
+    }
+
+    /// Runs git in the working copy, no matter if it succeeds.
+    fn run(&self, args: &[&str]) -> Result<Output, StorageError> {
+        let output = try!(Command::new("git")
+                              .args(args)
+                              .current_dir(&self.workdir)
+                              .env("GIT_TERMINAL_PROMPT", "0")
+                              .output());
+        Ok(output)
+    }
+
+    /// Runs git in the working copy and fails if it doesn't succeed.
+    fn git(&self, args: &[&str]) -> Result<Output, StorageError> {
+        check(try!(self.run(args)))
+    }
+}
+
+impl Backend for GitBackend {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        try!(check_name(name));
+        let mut file = match File::open(self.workdir.join(name)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(StorageError::NotFound(name.to_string()))
+            }
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+        let version = content_version(&data);
+        Ok((data, version))
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        try!(check_name(name));
+        let path = self.workdir.join(name);
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        let mut file = try!(File::create(path));
+        try!(file.write_all(data));
+        Ok(())
+    }
+
+    /// Only checks the working copy. Concurrent changes of other devices are detected when
+    /// pushing in commit.
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        let current = match self.get_versioned(name) {
+            Ok((_, v)) => Some(v),
+            Err(StorageError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if current.as_ref() != expected {
+            return Err(StorageError::Conflict(name.to_string()));
+        }
+        try!(self.put(name, data));
+        Ok(content_version(data))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        try!(check_name(name));
+        match fs::remove_file(self.workdir.join(name)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(StorageError::NotFound(name.to_string()))
+            }
+            Err(e) => Err(StorageError::Io(e)),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        // Untracked files are included, they are part of the next commit.
+        let output = try!(self.git(&["ls-files", "-z", "--cached", "--others", "--exclude-standard"]));
+        let mut names = Vec::new();
+        for name in String::from_utf8_lossy(&output.stdout).split('\0') {
+            if name.is_empty() || !name.starts_with(prefix) {
+                continue;
+            }
+            // Deleted files are still in the index until the next commit.
+            if self.workdir.join(name).exists() && !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn check(output: Output) -> Result<Output, StorageError> {
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(StorageError::Unavailable(format!("git failed: {}",
+                                              String::from_utf8_lossy(&output.stderr).trim())))
+    }
+}
@@ -0,0 +1,519 @@
+use std::cmp;
+use std::io::{Read, Write};
+use rustc_serialize::base64::{FromBase64, ToBase64, MIME};
+use uuid::Uuid;
+use storage::backend::{check_name, Backend, StorageError, Version};
+
+const OBJECT_HEADER: &'static str = "X-Cryptocontent-Object";
+const REPLACES_HEADER: &'static str = "X-Cryptocontent-Replaces";
+const TOKEN_HEADER: &'static str = "X-Cryptocontent-Token";
+
+/// How often put and a read racing with a replacement are tried before giving up.
+const ATTEMPTS: usize = 5;
+
+/// A message of the folder, as far as the backend is concerned.
+#[derive(Debug, Clone)]
+struct Message {
+    uid: u64,
+    name: String,
+    /// UID of the message this one replaces, 0 if it was created.
+    replaces: u64,
+    token: String,
+    deleted: bool,
+}
+
+/// Part of a response of the server.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Atom(String),
+    /// A quoted string or a literal.
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+}
+
+/// Backend storing every object as a message in a folder of an IMAP mailbox, so objects can be
+/// synchronized over an email account.
+///
+/// IMAP has no way to replace a message, so a new version is appended with a header naming
+/// the UID of the message it replaces, then the old one is flagged \Deleted and expunged. The
+/// UIDs the server hands out decide races: of two messages replacing the same one, the one with
+/// the lower UID wins, the other device removes its message again and gets a conflict. The
+/// version of an object is the UID of its message.
+///
+/// Like DropboxBackend, the backend doesn't bring its own TLS. It talks IMAP over any stream,
+/// which should be encrypted unless the server is local. Every operation lists the folder, so
+/// it is meant for the few objects of a synchronization, not for big repositories.
+pub struct ImapBackend<S: Read + Write> {
+    stream: S,
+    buffer: Vec<u8>,
+    folder: String,
+    tag: usize,
+    uidvalidity: u64,
+}
+
+impl<S: Read + Write> ImapBackend<S> {
+    /// Logs in on stream, which has to be connected to the server, and selects folder,
+    /// creating it if it doesn't exist.
+    pub fn connect(stream: S, user: &str, password: &str, folder: &str)
+                   -> Result<ImapBackend<S>, StorageError> {
+        let mut b = ImapBackend {
+            stream: stream,
+            buffer: Vec::new(),
+            folder: folder.to_string(),
+            tag: 0,
+            uidvalidity: 0,
+        };
+        let greeting = try!(b.read_response());
+        if !greeting.starts_with(b"* OK") && !greeting.starts_with(b"* PREAUTH") {
+            return Err(StorageError::Unavailable(format!("IMAP server refused the connection: {}",
+                                                         String::from_utf8_lossy(&greeting))));
+        }
+        try!(b.command(&format!("LOGIN {} {}", quote(user), quote(password))));
+        if b.select().is_err() {
+            try!(b.command(&format!("CREATE {}", quote(folder))));
+            try!(b.select());
+        }
+        Ok(b)
+    }
+
+    /// Logs out and returns the stream.
+    pub fn logout(mut self) -> Result<S, StorageError> {
+        let tag = try!(self.send("LOGOUT"));
+        // The server says BYE before completing the command, and may close right after.
+        loop {
+            match self.read_response() {
+                Ok(ref r) if r.starts_with(format!("{} ", tag).as_bytes()) => return Ok(self.stream),
+                Ok(_) => (),
+                Err(StorageError::Unavailable(_)) => return Ok(self.stream),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn select(&mut self) -> Result<(), StorageError> {
+        let responses = try!(self.command(&format!("SELECT {}", quote(&self.folder))));
+        for r in responses.iter() {
+            let text = String::from_utf8_lossy(r);
+            if let Some(i) = text.find("[UIDVALIDITY ") {
+                let rest = &text[i + 13..];
+                let end = rest.find(']').unwrap_or(rest.len());
+                self.uidvalidity = rest[..end].trim().parse().unwrap_or(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a command and returns the untagged responses, failing unless it completed with OK.
+    fn command(&mut self, command: &str) -> Result<Vec<Vec<u8>>, StorageError> {
+        let tag = try!(self.send(command));
+        self.finish(&tag)
+    }
+
+    fn send(&mut self, command: &str) -> Result<String, StorageError> {
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        try!(write!(self.stream, "{} {}\r\n", tag, command));
+        try!(self.stream.flush());
+        Ok(tag)
+    }
+
+    /// Reads the responses to the command with the given tag.
+    fn finish(&mut self, tag: &str) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut untagged = Vec::new();
+        loop {
+            let response = try!(self.read_response());
+            if response.starts_with(b"* BYE") {
+                return Err(StorageError::Unavailable("IMAP server closed the connection".to_string()));
+            }
+            if !response.starts_with(format!("{} ", tag).as_bytes()) {
+                untagged.push(response);
+                continue;
+            }
+
+            let status = String::from_utf8_lossy(&response[tag.len() + 1..]).into_owned();
+            if status.starts_with("OK") {
+                return Ok(untagged);
+            }
+            return Err(StorageError::Protocol(format!("IMAP command failed: {}", status)));
+        }
+    }
+
+    /// Reads a whole response, including the literals in it.
+    fn read_response(&mut self) -> Result<Vec<u8>, StorageError> {
+        let mut response = Vec::new();
+        loop {
+            let line = try!(self.read_line());
+            let literal = literal_size(&line);
+            response.extend(line);
+            match literal {
+                Some(n) => {
+                    let data = try!(self.read_exact(n));
+                    response.extend(data);
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// Reads a line, including the CRLF at its end.
+    fn read_line(&mut self) -> Result<Vec<u8>, StorageError> {
+        loop {
+            if let Some(i) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let rest = self.buffer.split_off(i + 2);
+                return Ok(::std::mem::replace(&mut self.buffer, rest));
+            }
+            try!(self.fill());
+        }
+    }
+
+    fn read_exact(&mut self, n: usize) -> Result<Vec<u8>, StorageError> {
+        while self.buffer.len() < n {
+            try!(self.fill());
+        }
+        let rest = self.buffer.split_off(n);
+        Ok(::std::mem::replace(&mut self.buffer, rest))
+    }
+
+    fn fill(&mut self) -> Result<(), StorageError> {
+        let mut chunk = [0; 4096];
+        let n = try!(self.stream.read(&mut chunk));
+        if n == 0 {
+            return Err(StorageError::Unavailable("IMAP server closed the connection".to_string()));
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// Lists the messages of the folder, ordered by UID.
+    fn messages(&mut self) -> Result<Vec<Message>, StorageError> {
+        let command = format!("UID FETCH 1:* (UID FLAGS BODY.PEEK[HEADER.FIELDS ({} {} {})])",
+                              OBJECT_HEADER,
+                              REPLACES_HEADER,
+                              TOKEN_HEADER);
+        let responses = try!(self.command(&command));
+        let mut messages = Vec::new();
+        for r in responses {
+            let items = match fetch_items(&r) {
+                Some(items) => items,
+                None => continue,
+            };
+            let mut message = Message {
+                uid: 0,
+                name: String::new(),
+                replaces: 0,
+                token: String::new(),
+                deleted: false,
+            };
+            for (key, value) in items {
+                match (&key[..], value) {
+                    ("UID", Value::Atom(uid)) => message.uid = uid.parse().unwrap_or(0),
+                    ("FLAGS", Value::List(flags)) => {
+                        message.deleted = flags.iter()
+                                               .any(|f| *f == Value::Atom("\\Deleted".to_string()));
+                    }
+                    (k, Value::Bytes(header)) if k.starts_with("BODY[") => {
+                        let header = String::from_utf8_lossy(&header).into_owned();
+                        message.name = header_value(&header, OBJECT_HEADER).unwrap_or(String::new());
+                        message.replaces = header_value(&header, REPLACES_HEADER).and_then(|r| r.parse().ok())
+                                                                                 .unwrap_or(0);
+                        message.token = header_value(&header, TOKEN_HEADER).unwrap_or(String::new());
+                    }
+                    _ => (),
+                }
+            }
+            // Other mail in the folder is left alone.
+            if message.uid != 0 && !message.name.is_empty() {
+                messages.push(message);
+            }
+        }
+        messages.sort_by(|a, b| a.uid.cmp(&b.uid));
+        Ok(messages)
+    }
+
+    /// Fetches the content of the message with the given UID, None if it is gone.
+    fn fetch(&mut self, uid: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        let responses = try!(self.command(&format!("UID FETCH {} (BODY.PEEK[TEXT])", uid)));
+        for r in responses {
+            for (key, value) in fetch_items(&r).unwrap_or(Vec::new()) {
+                if let ("BODY[TEXT]", Value::Bytes(text)) = (&key[..], value) {
+                    return match text.from_base64() {
+                        Ok(data) => Ok(Some(data)),
+                        Err(_) => Err(StorageError::Corrupt(format!("Message {} is no base64", uid))),
+                    };
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn append(&mut self, name: &str, replaces: u64, token: &str, data: &[u8]) -> Result<(), StorageError> {
+        let message = format!("From: cryptocontent\r\nSubject: {}\r\n{}: {}\r\n{}: {}\r\n{}: {}\r\n\
+                               MIME-Version: 1.0\r\nContent-Type: application/octet-stream\r\n\
+                               Content-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+                              name,
+                              OBJECT_HEADER,
+                              name,
+                              REPLACES_HEADER,
+                              replaces,
+                              TOKEN_HEADER,
+                              token,
+                              data.to_base64(MIME));
+        let folder = quote(&self.folder);
+        let tag = try!(self.send(&format!("APPEND {} (\\Seen) {{{}}}", folder, message.len())));
+        let ready = try!(self.read_response());
+        if !ready.starts_with(b"+") {
+            return Err(StorageError::Protocol(format!("IMAP server refused the message: {}",
+                                                      String::from_utf8_lossy(&ready))));
+        }
+        try!(self.stream.write_all(message.as_bytes()));
+        try!(self.stream.write_all(b"\r\n"));
+        try!(self.stream.flush());
+        try!(self.finish(&tag));
+        Ok(())
+    }
+
+    /// Flags the messages \Deleted and expunges them.
+    fn remove(&mut self, uids: &[u64]) -> Result<(), StorageError> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        let set = uids.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(",");
+        try!(self.command(&format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", set)));
+        try!(self.command("EXPUNGE"));
+        Ok(())
+    }
+
+    /// Appends data as the new version of name. expected is checked like for put_if if given.
+    fn replace(&mut self, name: &str, data: &[u8], expected: Option<Option<&Version>>)
+               -> Result<Version, StorageError> {
+        try!(check_name(name));
+        if name.chars().any(|c| c.is_control()) {
+            return Err(StorageError::Protocol(format!("Invalid object name: {}", name)));
+        }
+
+        for _ in 0..ATTEMPTS {
+            let messages = try!(self.messages());
+            let replaced = current(&messages, name).map(|m| m.uid);
+            if let Some(expected) = expected {
+                if expected.cloned() != replaced.map(|u| self.uid_version(u)) {
+                    return Err(StorageError::Conflict(name.to_string()));
+                }
+            }
+
+            let token = Uuid::new_v4().to_string();
+            try!(self.append(name, replaced.unwrap_or(0), &token, data));
+            let messages = try!(self.messages());
+            let ours = match messages.iter().find(|m| m.token == token) {
+                Some(m) => m.uid,
+                None => return Err(StorageError::Protocol(format!("Appended message for {} vanished", name))),
+            };
+
+            if current(&messages, name).map(|m| m.uid) != Some(ours) {
+                // Another device replaced the same version first.
+                try!(self.remove(&[ours]));
+                if expected.is_some() {
+                    return Err(StorageError::Conflict(name.to_string()));
+                }
+                continue;
+            }
+
+            // Everything else of name, but not what already replaces ours.
+            let old = messages.iter()
+                              .filter(|m| m.name == name && m.uid != ours && m.replaces != ours)
+                              .map(|m| m.uid)
+                              .collect::<Vec<_>>();
+            try!(self.remove(&old));
+            return Ok(self.uid_version(ours));
+        }
+        Err(StorageError::Conflict(name.to_string()))
+    }
+
+    fn uid_version(&self, uid: u64) -> Version {
+        Version(format!("{}.{}", self.uidvalidity, uid))
+    }
+}
+
+impl<S: Read + Write> Backend for ImapBackend<S> {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        for _ in 0..ATTEMPTS {
+            let messages = try!(self.messages());
+            let uid = match current(&messages, name) {
+                Some(m) => m.uid,
+                None => return Err(StorageError::NotFound(name.to_string())),
+            };
+            // The message may have been replaced and expunged meanwhile.
+            if let Some(data) = try!(self.fetch(uid)) {
+                return Ok((data, self.uid_version(uid)));
+            }
+        }
+        Err(StorageError::Unavailable(format!("{} keeps changing", name)))
+    }
+
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        let messages = try!(self.messages());
+        match current(&messages, name) {
+            Some(m) => Ok(self.uid_version(m.uid)),
+            None => Err(StorageError::NotFound(name.to_string())),
+        }
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.replace(name, data, None).map(|_| ())
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        self.replace(name, data, Some(expected))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        let messages = try!(self.messages());
+        let uids = messages.iter()
+                           .filter(|m| m.name == name && !m.deleted)
+                           .map(|m| m.uid)
+                           .collect::<Vec<_>>();
+        if uids.is_empty() {
+            return Err(StorageError::NotFound(name.to_string()));
+        }
+        self.remove(&uids)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let messages = try!(self.messages());
+        let mut names = messages.iter()
+                                .filter(|m| !m.deleted && m.name.starts_with(prefix))
+                                .map(|m| m.name.clone())
+                                .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// The current message of name: starting with the oldest message, the first message replacing
+/// it, then the first one replacing that and so on.
+fn current<'a>(messages: &'a [Message], name: &str) -> Option<&'a Message> {
+    let live = messages.iter().filter(|m| m.name == name && !m.deleted).collect::<Vec<_>>();
+    let mut current = match live.first() {
+        Some(m) => *m,
+        None => return None,
+    };
+    while let Some(next) = live.iter().find(|m| m.replaces == current.uid) {
+        current = *next;
+    }
+    Some(current)
+}
+
+/// Quotes a string for a command.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The size of the literal a line announces at its end.
+fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = &line[..line.len() - 2];
+    if !line.ends_with(b"}") {
+        return None;
+    }
+    let start = match line.iter().rposition(|&b| b == b'{') {
+        Some(i) => i,
+        None => return None,
+    };
+    String::from_utf8_lossy(&line[start + 1..line.len() - 1]).parse().ok()
+}
+
+/// Returns the data items of a FETCH response, as pairs of name and value.
+fn fetch_items(response: &[u8]) -> Option<Vec<(String, Value)>> {
+    let values = parse(response);
+    let items = match (values.get(2), values.get(3)) {
+        (Some(&Value::Atom(ref fetch)), Some(&Value::List(ref items))) if fetch == "FETCH" => items,
+        _ => return None,
+    };
+    let mut pairs = Vec::new();
+    for pair in items.chunks(2) {
+        if let (&Value::Atom(ref key), Some(value)) = (&pair[0], pair.get(1)) {
+            pairs.push((key.to_uppercase(), value.clone()));
+        }
+    }
+    Some(pairs)
+}
+
+/// Splits a response into its values.
+fn parse(response: &[u8]) -> Vec<Value> {
+    let mut stack = vec![Vec::new()];
+    let mut i = 0;
+    while i < response.len() {
+        match response[i] {
+            b' ' | b'\r' | b'\n' => i += 1,
+            b'(' => {
+                stack.push(Vec::new());
+                i += 1;
+            }
+            b')' => {
+                if stack.len() > 1 {
+                    let list = stack.pop().unwrap();
+                    stack.last_mut().unwrap().push(Value::List(list));
+                }
+                i += 1;
+            }
+            b'"' => {
+                let mut s = Vec::new();
+                i += 1;
+                while i < response.len() && response[i] != b'"' {
+                    if response[i] == b'\\' {
+                        i += 1;
+                    }
+                    if i < response.len() {
+                        s.push(response[i]);
+                    }
+                    i += 1;
+                }
+                stack.last_mut().unwrap().push(Value::Bytes(s));
+                i += 1;
+            }
+            b'{' => {
+                let end = response[i..].iter().position(|&b| b == b'}').map_or(response.len(), |e| i + e);
+                let n = String::from_utf8_lossy(&response[i + 1..end]).parse().unwrap_or(0);
+                let start = cmp::min(end + 3, response.len());
+                let stop = cmp::min(start + n, response.len());
+                stack.last_mut().unwrap().push(Value::Bytes(response[start..stop].to_vec()));
+                i = stop;
+            }
+            _ => {
+                // Atoms include sections like BODY[HEADER.FIELDS (A B)] with their spaces.
+                let start = i;
+                let mut depth = 0;
+                while i < response.len() {
+                    match response[i] {
+                        b'[' => depth += 1,
+                        b']' => depth -= 1,
+                        b' ' | b'(' | b')' | b'\r' if depth == 0 => break,
+                        _ => (),
+                    }
+                    i += 1;
+                }
+                let atom = String::from_utf8_lossy(&response[start..i]).into_owned();
+                stack.last_mut().unwrap().push(Value::Atom(atom));
+            }
+        }
+    }
+    while stack.len() > 1 {
+        let list = stack.pop().unwrap();
+        stack.last_mut().unwrap().push(Value::List(list));
+    }
+    stack.pop().unwrap()
+}
+
+/// Returns the value of a header field, ignoring the case of its name.
+fn header_value(header: &str, name: &str) -> Option<String> {
+    let name = name.to_lowercase();
+    header.lines()
+          .filter_map(|l| {
+              let mut parts = l.splitn(2, ':');
+              match (parts.next(), parts.next()) {
+                  (Some(k), Some(v)) if k.trim().to_lowercase() == name => Some(v.trim().to_string()),
+                  _ => None,
+              }
+          })
+          .next()
+}
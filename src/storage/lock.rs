@@ -0,0 +1,289 @@
+use std::error::Error;
+use std::fmt;
+use chrono::{DateTime, Duration, UTC};
+use rustc_serialize::json;
+use uuid::Uuid;
+use storage::backend::{Backend, StorageError, Version};
+
+/// Name of the lock object in the backend.
+pub const LOCK_NAME: &'static str = "lock";
+
+/// Content of the lock object once the lock is released. Replacing the lock with it instead of
+/// deleting it lets release use put_if, so a lock taken over in the meantime is left alone.
+pub const RELEASED: &'static [u8] = b"";
+
+/// The content of the lock object. It contains no user data and is stored unencrypted, so
+/// every device can tell who is holding the lock.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LockInfo {
+    /// Id of the device holding the lock.
+    pub holder: String,
+    /// Random token of this particular acquisition.
+    pub token: String,
+    pub acquired: DateTime<UTC>,
+    pub expires: DateTime<UTC>,
+}
+
+impl LockInfo {
+    /// A lock is stale once it is expired for longer than grace. The grace period covers clock
+    /// differences between devices.
+    pub fn is_stale(&self, now: DateTime<UTC>, grace: Duration) -> bool {
+        self.expires + grace < now
+    }
+}
+
+/// Errors when working with a RemoteLock.
+#[derive(Debug)]
+pub enum LockError {
+    /// The lock is held by another device.
+    Held(LockInfo),
+    /// Our lock has been taken over or removed by someone else. Contains the current holder.
+    Lost(Option<LockInfo>),
+    /// Error of the backend.
+    Storage(StorageError),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Held(ref i) => write!(f, "Lock held by {} until {}", i.holder, i.expires),
+            LockError::Lost(Some(ref i)) => write!(f, "Lock lost to {}", i.holder),
+            LockError::Lost(None) => write!(f, "Lock lost"),
+            LockError::Storage(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for LockError {
+    fn description(&self) -> &str {
+        match *self {
+            LockError::Held(_) => "lock held by another device",
+            LockError::Lost(_) => "lock lost",
+            LockError::Storage(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<StorageError> for LockError {
+    fn from(e: StorageError) -> LockError {
+        LockError::Storage(e)
+    }
+}
+
+/// The timed lock of steps 3 and 9 of the synchronization. The lock is a lease: it expires
+/// unless it is renewed, so a crashed device can't block the others forever.
+///
+/// The lock object is only replaced with put_if, so two devices can't take it at the same
+/// time as long as the backend implements put_if atomically.
+pub struct RemoteLock {
+    name: String,
+    device: String,
+    lease: Duration,
+    grace: Duration,
+    held: Option<LockInfo>,
+    broken: Option<LockInfo>,
+}
+
+impl RemoteLock {
+    /// Creates a lock for the given device which is held for lease after each acquire or
+    /// renew.
+    pub fn new(device: &str, lease: Duration) -> RemoteLock {
+        RemoteLock {
+            name: LOCK_NAME.to_string(),
+            device: device.to_string(),
+            lease: lease,
+            grace: Duration::minutes(1),
+            held: None,
+            broken: None,
+        }
+    }
+
+    /// Sets how long an expired lock of another device is respected before it is broken.
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Returns our current lock, if we hold it.
+    pub fn held(&self) -> Option<&LockInfo> {
+        self.held.as_ref()
+    }
+
+    /// Returns the stale lock of another device that was broken by the last acquire.
+    pub fn broken(&self) -> Option<&LockInfo> {
+        self.broken.as_ref()
+    }
+
+    /// True if less than half of the lease is left, so renew should be called.
+    pub fn needs_renewal(&self) -> bool {
+        match self.held {
+            Some(ref i) => i.expires - UTC::now() < self.lease / 2,
+            None => false,
+        }
+    }
+
+    /// Name of the lock object in the backend.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns who currently holds the lock in the backend, if anyone.
+    pub fn holder<B: Backend>(&self, b: &mut B) -> Result<Option<LockInfo>, StorageError> {
+        match try!(self.read(b)) {
+            Some((ref data, _)) if data != RELEASED => self.decode(data).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Takes the lock. Fails with LockError::Held if another device holds it and it isn't
+    /// stale yet. Stale locks and old locks of this device are broken.
+    pub fn acquire<B: Backend>(&mut self, b: &mut B) -> Result<LockInfo, LockError> {
+        let current = try!(self.read(b));
+        let (info, data) = try!(self.begin_acquire(current.as_ref().map(|c| &c.0[..])));
+        let current = try!(self.write(b, &data, current.as_ref().map(|c| &c.1)));
+        self.finish_acquire(info, current.as_ref().map(|c| &c.0[..]))
+    }
+
+    /// First half of acquire, for backends acquire can't be used with, like asynchronous
+    /// ones. Decides from the current lock object, None if there is none, whether the lock may
+    /// be taken and returns our lock and its object. The object has to be stored with put_if in
+    /// place of the current one, then read back for finish_acquire.
+    pub fn begin_acquire(&mut self, current: Option<&[u8]>) -> Result<(LockInfo, Vec<u8>), LockError> {
+        self.broken = None;
+        let now = UTC::now();
+
+        // A lock that can't be read can only come from a crashed device and is broken as well.
+        if let Some(current) = held_lock(current).and_then(|c| self.decode(c).ok()) {
+            let ours = current.holder == self.device;
+            if !ours && !current.is_stale(now, self.grace) {
+                return Err(LockError::Held(current));
+            }
+            if !ours {
+                self.broken = Some(current);
+            }
+        }
+
+        let info = LockInfo {
+            holder: self.device.clone(),
+            token: Uuid::new_v4().to_string(),
+            acquired: now,
+            expires: now + self.lease,
+        };
+        let data = json::encode(&info).unwrap().into_bytes();
+        Ok((info, data))
+    }
+
+    /// Second half of acquire: checks that the lock object read back after storing ours is
+    /// ours, otherwise another device was faster.
+    pub fn finish_acquire(&mut self, info: LockInfo, current: Option<&[u8]>) -> Result<LockInfo, LockError> {
+        match self.confirm(&info, current) {
+            Ok(()) => Ok(info),
+            Err(LockError::Lost(Some(other))) => Err(LockError::Held(other)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Extends the lease of our lock. Fails with LockError::Lost if somebody else took it.
+    pub fn renew<B: Backend>(&mut self, b: &mut B) -> Result<LockInfo, LockError> {
+        let current = try!(self.read(b));
+        let (info, data) = try!(self.begin_renew(current.as_ref().map(|c| &c.0[..])));
+        let current = try!(self.write(b, &data, current.as_ref().map(|c| &c.1)));
+        self.finish_renew(info, current.as_ref().map(|c| &c.0[..]))
+    }
+
+    /// First half of renew, see begin_acquire: checks that the current lock object is still
+    /// ours and returns the renewed lock and its object.
+    pub fn begin_renew(&mut self, current: Option<&[u8]>) -> Result<(LockInfo, Vec<u8>), LockError> {
+        let mut info = try!(self.check_held(current));
+        info.expires = UTC::now() + self.lease;
+        let data = json::encode(&info).unwrap().into_bytes();
+        Ok((info, data))
+    }
+
+    /// Second half of renew, see finish_acquire.
+    pub fn finish_renew(&mut self, info: LockInfo, current: Option<&[u8]>) -> Result<LockInfo, LockError> {
+        try!(self.confirm(&info, current));
+        Ok(info)
+    }
+
+    /// Releases our lock in the backend. Fails with LockError::Lost if somebody else took it
+    /// in the meantime, in which case their lock is left alone.
+    pub fn release<B: Backend>(&mut self, b: &mut B) -> Result<(), LockError> {
+        let current = try!(self.read(b));
+        try!(self.begin_release(current.as_ref().map(|c| &c.0[..])));
+        match b.put_if(&self.name, RELEASED, current.as_ref().map(|c| &c.1)) {
+            Ok(_) => Ok(()),
+            Err(StorageError::Conflict(_)) => Err(LockError::Lost(self.holder(b).unwrap_or(None))),
+            Err(e) => Err(LockError::Storage(e)),
+        }
+    }
+
+    /// First half of release, see begin_acquire: checks that the current lock object is still
+    /// ours. If so it has to be replaced with RELEASED using put_if at the version read, a
+    /// Conflict means the lock was lost.
+    pub fn begin_release(&mut self, current: Option<&[u8]>) -> Result<(), LockError> {
+        try!(self.check_held(current));
+        self.held = None;
+        Ok(())
+    }
+
+    /// Reads the lock object and its version.
+    fn read<B: Backend>(&self, b: &mut B) -> Result<Option<(Vec<u8>, Version)>, StorageError> {
+        match b.get_versioned(&self.name) {
+            Ok(d) => Ok(Some(d)),
+            Err(StorageError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the lock object if it is still at the expected version and reads it back, in
+    /// case the backend silently lost the write.
+    fn write<B: Backend>(&self, b: &mut B, data: &[u8], expected: Option<&Version>)
+                         -> Result<Option<(Vec<u8>, Version)>, LockError> {
+        match b.put_if(&self.name, data, expected) {
+            Ok(_) | Err(StorageError::Conflict(_)) => (),
+            Err(e) => return Err(LockError::Storage(e)),
+        }
+        self.read(b).map_err(LockError::Storage)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<LockInfo, StorageError> {
+        let info = ::std::str::from_utf8(data).ok().and_then(|d| json::decode(d).ok());
+        info.ok_or_else(|| StorageError::Corrupt(self.name.clone()))
+    }
+
+    /// Makes sure the current lock object is still our lock and returns it.
+    fn check_held(&mut self, current: Option<&[u8]>) -> Result<LockInfo, LockError> {
+        let held = match self.held.clone() {
+            Some(h) => h,
+            None => return Err(LockError::Lost(None)),
+        };
+        let current = held_lock(current).and_then(|c| self.decode(c).ok());
+        match current {
+            Some(ref current) if current.token == held.token => Ok(held),
+            current => {
+                self.held = None;
+                Err(LockError::Lost(current))
+            }
+        }
+    }
+
+    /// Checks that the lock object read back after storing info is ours, otherwise another
+    /// device was faster or the write got lost.
+    fn confirm(&mut self, info: &LockInfo, current: Option<&[u8]>) -> Result<(), LockError> {
+        let current = match held_lock(current) {
+            Some(c) => Some(try!(self.decode(c))),
+            None => None,
+        };
+        if current.as_ref().map(|c| &c.token) != Some(&info.token) {
+            self.held = None;
+            return Err(LockError::Lost(current));
+        }
+        self.held = Some(info.clone());
+        Ok(())
+    }
+}
+
+/// The lock object, None if there is none or it was released.
+fn held_lock(current: Option<&[u8]>) -> Option<&[u8]> {
+    current.and_then(|c| if c == RELEASED { None } else { Some(c) })
+}
@@ -0,0 +1,142 @@
+use crypto::CryptoManager;
+use domain::EventLogEntry;
+use storage::{open_object, seal_object};
+use storage::backend::{Backend, StorageError};
+
+/// Name of the shared log, used in errors and reports.
+pub const LOG_NAME: &'static str = "log";
+
+/// Prefix of the segments of the shared log in the backend.
+pub const LOG_PREFIX: &'static str = "log/";
+
+/// How often appending to the shared log is retried if another device changed it.
+pub const RETRIES: usize = 5;
+
+/// A position in the shared log: the number of entries up to it and the id of the last of
+/// them, so a position still makes sense if entries before it are moved elsewhere.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LogPosition {
+    pub index: usize,
+    pub last_entry: Option<String>,
+}
+
+impl LogPosition {
+    /// The position after all of the given entries.
+    pub fn end_of(entries: &[EventLogEntry]) -> LogPosition {
+        LogPosition {
+            index: entries.len(),
+            last_entry: entries.last().map(|e| e.id.clone()),
+        }
+    }
+}
+
+/// Name of the segment of the shared log with the given number.
+///
+/// The shared log is a sequence of segments numbered from 0, every append adds the next one.
+/// Segments are never changed, so a device only downloads the segments added since it last
+/// read the log. The Sanitizer moves the oldest segments into the backup log, but always
+/// keeps the newest one, so the end of the log can be found from the last segment read.
+pub fn log_segment_name(number: usize) -> String {
+    format!("{}{}", LOG_PREFIX, number)
+}
+
+/// Returns the numbers of the segments of the shared log, oldest first.
+pub fn log_segments<B: Backend>(b: &mut B) -> Result<Vec<usize>, StorageError> {
+    b.list(LOG_PREFIX).map(|names| segment_numbers(&names))
+}
+
+/// Returns the numbers of the segments among the names listed with LOG_PREFIX, sorted.
+pub fn segment_numbers(names: &[String]) -> Vec<usize> {
+    let mut numbers = names.iter()
+                           .filter_map(|n| n[LOG_PREFIX.len()..].parse().ok())
+                           .collect::<Vec<usize>>();
+    numbers.sort();
+    numbers
+}
+
+/// Reads and decrypts a single segment of the shared log.
+pub fn read_log_segment<B: Backend>(b: &mut B, c: &CryptoManager, number: usize)
+                                    -> Result<Vec<EventLogEntry>, StorageError> {
+    let data = try!(b.get(&log_segment_name(number)));
+    decode_log(data, c)
+}
+
+/// Reads the segments from first on, up to the first one that doesn't exist. Returns their
+/// entries and the number of that segment, which the next append will add.
+pub fn read_log_from<B: Backend>(b: &mut B, c: &CryptoManager, first: usize)
+                                -> Result<(Vec<EventLogEntry>, usize), StorageError> {
+    let mut entries = Vec::new();
+    let mut number = first;
+    loop {
+        match read_log_segment(b, c, number) {
+            Ok(e) => entries.extend(e),
+            Err(StorageError::NotFound(_)) => return Ok((entries, number)),
+            Err(e) => return Err(e),
+        }
+        number += 1;
+    }
+}
+
+/// Stores entries as the segment with the given number. Fails with a conflict if another
+/// device added it first.
+pub fn write_log_segment<B: Backend>(b: &mut B, c: &mut CryptoManager, number: usize,
+                                     entries: &[EventLogEntry])
+                                     -> Result<(), StorageError> {
+    let data = try!(encode_log(entries, c));
+    b.put_if(&log_segment_name(number), &data, None).map(|_| ())
+}
+
+/// Reads and decrypts the shared log. A log that doesn't exist yet is empty.
+pub fn read_log<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<Vec<EventLogEntry>, StorageError> {
+    let mut log = Vec::new();
+    for number in try!(log_segments(b)) {
+        match read_log_segment(b, c, number) {
+            Ok(entries) => log.extend(entries),
+            // Moved into the backup log in the meantime.
+            Err(StorageError::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(log)
+}
+
+/// Appends entries to the shared log as a new segment and returns the position after them.
+/// Changes of other devices in the meantime are kept, the entries are added after them.
+///
+/// Nothing is downloaded: the position is computed from after, the end of the log as the
+/// caller knows it. Its index doesn't count segments other devices added meanwhile, but its
+/// last entry is the last of entries.
+pub fn append_log<B: Backend>(b: &mut B, c: &mut CryptoManager, after: &LogPosition,
+                              entries: &[EventLogEntry])
+                              -> Result<LogPosition, StorageError> {
+    if entries.is_empty() {
+        return Ok(after.clone());
+    }
+    for _ in 0..RETRIES + 1 {
+        let next = try!(log_segments(b)).last().map_or(0, |n| n + 1);
+        match write_log_segment(b, c, next, entries) {
+            Ok(()) => return Ok(position_after(after, entries)),
+            Err(StorageError::Conflict(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(StorageError::Conflict("Shared log kept changing while appending".to_string()))
+}
+
+/// The position after entries appended at after.
+pub fn position_after(after: &LogPosition, entries: &[EventLogEntry]) -> LogPosition {
+    LogPosition {
+        index: after.index + entries.len(),
+        last_entry: entries.last().map(|e| e.id.clone()).or_else(|| after.last_entry.clone()),
+    }
+}
+
+/// Decrypts and decodes the content of a segment of the shared log.
+pub fn decode_log(data: Vec<u8>, c: &CryptoManager) -> Result<Vec<EventLogEntry>, StorageError> {
+    open_object(LOG_NAME, data, c)
+}
+
+/// Encodes and encrypts entries as content of a segment of the shared log.
+pub fn encode_log(log: &[EventLogEntry], c: &mut CryptoManager) -> Result<Vec<u8>, StorageError> {
+    seal_object(LOG_NAME, c, &log)
+}
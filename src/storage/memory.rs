@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use storage::backend::{Backend, Resumable, StorageError, UploadSession, Version};
+
+/// Misbehaviour a MemoryBackend can show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The operation fails with StorageError::Unavailable and has no effect.
+    Transient,
+    /// A put only stores the first half of the data but reports success.
+    PartialWrite,
+    /// A get returns the version of the object before the last change.
+    StaleRead,
+    /// A put reports success but nothing is stored.
+    LostUpdate,
+    /// A list returns the names in random order.
+    ReorderedListing,
+}
+
+/// Probabilities (between 0 and 1) of the faults happening on each operation they apply to.
+/// The same seed always gives the same sequence of faults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub seed: u64,
+    pub latency: Duration,
+    pub transient: f64,
+    pub partial_write: f64,
+    pub stale_read: f64,
+    pub lost_update: f64,
+    pub reordered_listing: f64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> FaultConfig {
+        FaultConfig {
+            seed: 1,
+            latency: Duration::new(0, 0),
+            transient: 0.0,
+            partial_write: 0.0,
+            stale_read: 0.0,
+            lost_update: 0.0,
+            reordered_listing: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Get,
+    Put,
+    Delete,
+    List,
+}
+
+impl Fault {
+    fn applies_to(&self, op: Op) -> bool {
+        match *self {
+            Fault::Transient => true,
+            Fault::PartialWrite | Fault::LostUpdate => op == Op::Put,
+            Fault::StaleRead => op == Op::Get,
+            Fault::ReorderedListing => op == Op::List,
+        }
+    }
+}
+
+/// All versions of an object, None marks a deletion.
+type Versions = Vec<Option<Vec<u8>>>;
+
+/// Backend keeping everything in memory, meant for testing.
+///
+/// Faults can be injected either randomly with a FaultConfig or one by one with inject, to
+/// reproduce the misbehaviour of real providers deterministically. Clones share the stored
+/// objects, but each has its own faults, like several devices using the same provider.
+pub struct MemoryBackend {
+    objects: Arc<Mutex<BTreeMap<String, Versions>>>,
+    uploads: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    offline: Arc<AtomicBool>,
+    config: FaultConfig,
+    rng: u64,
+    scripted: Vec<Fault>,
+    injected: Vec<Fault>,
+    reads: Vec<String>,
+}
+
+impl MemoryBackend {
+    /// Creates an empty backend that behaves well.
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::with_faults(FaultConfig::default())
+    }
+
+    /// Creates an empty backend that randomly misbehaves as configured.
+    pub fn with_faults(config: FaultConfig) -> MemoryBackend {
+        MemoryBackend {
+            objects: Arc::new(Mutex::new(BTreeMap::new())),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            offline: Arc::new(AtomicBool::new(false)),
+            rng: config.seed | 1,
+            config: config,
+            scripted: Vec::new(),
+            injected: Vec::new(),
+            reads: Vec::new(),
+        }
+    }
+
+    /// Changes the random faults of this handle.
+    pub fn set_faults(&mut self, config: FaultConfig) {
+        self.rng = config.seed | 1;
+        self.config = config;
+    }
+
+    /// Makes the next operation the fault applies to misbehave, regardless of the
+    /// FaultConfig. Several injected faults are used in order.
+    pub fn inject(&mut self, fault: Fault) {
+        self.scripted.push(fault);
+    }
+
+    /// Takes the whole provider down or brings it back. While it is down every operation of
+    /// every clone fails like with Fault::Transient.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst);
+    }
+
+    /// Returns all faults that happened so far on this handle.
+    pub fn injected(&self) -> &[Fault] {
+        &self.injected
+    }
+
+    /// Returns the names of all objects read so far through this handle, in order.
+    pub fn reads(&self) -> &[String] {
+        &self.reads
+    }
+
+    /// Returns the stored object without any faults, to check what really got stored.
+    pub fn raw(&self, name: &str) -> Option<Vec<u8>> {
+        let objects = self.objects.lock().unwrap();
+        objects.get(name).and_then(|v| v.last().cloned()).and_then(|o| o)
+    }
+
+    /// Xorshift, good enough for picking faults.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        (self.next_random() % 1000000) as f64 / 1000000.0 < p
+    }
+
+    /// Decides which fault, if any, hits the operation.
+    fn fault(&mut self, op: Op) -> Option<Fault> {
+        if self.config.latency > Duration::new(0, 0) {
+            thread::sleep(self.config.latency);
+        }
+
+        let fault = match self.scripted.iter().position(|f| f.applies_to(op)) {
+            _ if self.offline.load(Ordering::SeqCst) => Some(Fault::Transient),
+            Some(i) => Some(self.scripted.remove(i)),
+            None => {
+                let candidates = [(Fault::Transient, self.config.transient),
+                                  (Fault::PartialWrite, self.config.partial_write),
+                                  (Fault::StaleRead, self.config.stale_read),
+                                  (Fault::LostUpdate, self.config.lost_update),
+                                  (Fault::ReorderedListing, self.config.reordered_listing)];
+                let mut fault = None;
+                for &(f, p) in candidates.iter() {
+                    if f.applies_to(op) && self.chance(p) {
+                        fault = Some(f);
+                        break;
+                    }
+                }
+                fault
+            }
+        };
+
+        if let Some(f) = fault {
+            self.injected.push(f);
+        }
+        fault
+    }
+}
+
+impl Clone for MemoryBackend {
+    /// Returns a new well behaving handle to the same objects.
+    fn clone(&self) -> MemoryBackend {
+        MemoryBackend {
+            objects: self.objects.clone(),
+            uploads: self.uploads.clone(),
+            offline: self.offline.clone(),
+            rng: 1,
+            config: FaultConfig::default(),
+            scripted: Vec::new(),
+            injected: Vec::new(),
+            reads: Vec::new(),
+        }
+    }
+}
+
+/// Versions are simply numbered.
+fn version(index: usize) -> Version {
+    Version(index.to_string())
+}
+
+fn transient(op: &str, name: &str) -> StorageError {
+    StorageError::Unavailable(format!("Injected fault on {} {}", op, name))
+}
+
+impl Backend for MemoryBackend {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        self.reads.push(name.to_string());
+        let fault = self.fault(Op::Get);
+        if fault == Some(Fault::Transient) {
+            return Err(transient("get", name));
+        }
+
+        let objects = self.objects.lock().unwrap();
+        let versions = match objects.get(name) {
+            Some(v) if !v.is_empty() => v,
+            _ => return Err(StorageError::NotFound(name.to_string())),
+        };
+        let index = if fault == Some(Fault::StaleRead) && versions.len() > 1 {
+            versions.len() - 2
+        } else {
+            versions.len() - 1
+        };
+
+        match versions[index] {
+            Some(ref data) => Ok((data.clone(), version(index))),
+            None => Err(StorageError::NotFound(name.to_string())),
+        }
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        let data = match self.fault(Op::Put) {
+            Some(Fault::Transient) => return Err(transient("put", name)),
+            Some(Fault::LostUpdate) => return Ok(()),
+            Some(Fault::PartialWrite) => &data[..data.len() / 2],
+            _ => data,
+        };
+
+        let mut objects = self.objects.lock().unwrap();
+        objects.entry(name.to_string()).or_insert(Vec::new()).push(Some(data.to_vec()));
+        Ok(())
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        let fault = self.fault(Op::Put);
+        if fault == Some(Fault::Transient) {
+            return Err(transient("put", name));
+        }
+
+        let mut objects = self.objects.lock().unwrap();
+        let stored = objects.get(name).map_or(0, |v| v.len());
+        let current = match objects.get(name).and_then(|v| v.last()) {
+            Some(&Some(_)) => Some(version(stored - 1)),
+            _ => None,
+        };
+        if current.as_ref() != expected {
+            return Err(StorageError::Conflict(name.to_string()));
+        }
+
+        let data = match fault {
+            // The provider claims to have stored the next version.
+            Some(Fault::LostUpdate) => return Ok(version(stored)),
+            Some(Fault::PartialWrite) => data[..data.len() / 2].to_vec(),
+            _ => data.to_vec(),
+        };
+        objects.entry(name.to_string()).or_insert(Vec::new()).push(Some(data));
+        Ok(version(stored))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        if self.fault(Op::Delete) == Some(Fault::Transient) {
+            return Err(transient("delete", name));
+        }
+
+        let mut objects = self.objects.lock().unwrap();
+        match objects.get_mut(name) {
+            Some(ref mut v) if v.last().map(|o| o.is_some()) == Some(true) => {
+                v.push(None);
+                Ok(())
+            }
+            _ => Err(StorageError::NotFound(name.to_string())),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let fault = self.fault(Op::List);
+        if fault == Some(Fault::Transient) {
+            return Err(transient("list", prefix));
+        }
+
+        let mut names = {
+            let objects = self.objects.lock().unwrap();
+            objects.iter()
+                   .filter(|&(k, v)| k.starts_with(prefix) && v.last().map(|o| o.is_some()) == Some(true))
+                   .map(|(k, _)| k.clone())
+                   .collect::<Vec<_>>()
+        };
+
+        if fault == Some(Fault::ReorderedListing) {
+            for i in (1..names.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                names.swap(i, j);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Only the parts of an upload are hit by the faults of puts: with Fault::PartialWrite half of
+/// the part arrives and with Fault::LostUpdate all of it, but both times the answer gets lost.
+impl Resumable for MemoryBackend {
+    fn start_upload(&mut self, name: &str) -> Result<UploadSession, StorageError> {
+        if self.offline.load(Ordering::SeqCst) {
+            return Err(transient("upload", name));
+        }
+        let id = Uuid::new_v4().to_string();
+        self.uploads.lock().unwrap().insert(id.clone(), Vec::new());
+        Ok(UploadSession {
+            name: name.to_string(),
+            id: id,
+            offset: 0,
+        })
+    }
+
+    fn upload_part(&mut self, session: &mut UploadSession, data: &[u8]) -> Result<(), StorageError> {
+        let uploads = self.uploads.clone();
+        let mut uploads = uploads.lock().unwrap();
+        let staged = match uploads.get_mut(&session.id) {
+            Some(s) => s,
+            None => return Err(StorageError::NotFound(format!("Upload {}", session.id))),
+        };
+        if session.offset > staged.len() as u64 {
+            return Err(StorageError::Protocol(format!("Upload {} has a gap", session.id)));
+        }
+        // Like an offset error of the provider, which isn't hit by faults.
+        if session.offset < staged.len() as u64 {
+            session.offset = staged.len() as u64;
+            return Ok(());
+        }
+
+        match self.fault(Op::Put) {
+            Some(Fault::Transient) => Err(transient("upload", &session.name)),
+            Some(Fault::PartialWrite) => {
+                staged.extend_from_slice(&data[..data.len() / 2]);
+                Err(transient("upload", &session.name))
+            }
+            Some(Fault::LostUpdate) => {
+                staged.extend_from_slice(data);
+                Err(transient("upload", &session.name))
+            }
+            _ => {
+                staged.extend_from_slice(data);
+                session.offset += data.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish_upload(&mut self, session: &UploadSession) -> Result<Version, StorageError> {
+        if self.offline.load(Ordering::SeqCst) {
+            return Err(transient("upload", &session.name));
+        }
+        let mut uploads = self.uploads.lock().unwrap();
+        match uploads.get(&session.id) {
+            Some(d) if d.len() as u64 == session.offset => (),
+            Some(_) => return Err(StorageError::Protocol(format!("Upload {} is incomplete", session.id))),
+            None => return Err(StorageError::NotFound(format!("Upload {}", session.id))),
+        }
+        let data = uploads.remove(&session.id).unwrap();
+
+        let mut objects = self.objects.lock().unwrap();
+        let versions = objects.entry(session.name.clone()).or_insert(Vec::new());
+        versions.push(Some(data));
+        Ok(version(versions.len() - 1))
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, UTC};
+use crypto::CryptoManager;
+use storage::{load_from, save_to};
+use storage::backend::{Backend, StorageError};
+use storage::lock::LOCK_NAME;
+use storage::log::{decode_log, LOG_PREFIX};
+use storage::repository::{decode_manifest, DEVICES_NAME, KEYS_PREFIX, REPOSITORY_NAME};
+
+/// Name of the redirect record left at the old location of a migrated repository.
+pub const REDIRECT_NAME: &'static str = "redirect";
+
+/// Tells devices that the repository moved. It is encrypted like every other object, so the
+/// new location isn't revealed to the old provider.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Redirect {
+    /// The new location, in whatever form the devices configure their backends with.
+    pub location: String,
+    pub migrated: DateTime<UTC>,
+}
+
+/// What a migration copied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub copied: usize,
+    pub bytes: u64,
+    /// Number of copies that were decrypted and found equal to the original.
+    pub decrypted: usize,
+    /// Number of key wraps and device lists. Only the devices they are meant for can open
+    /// them, so their copies are compared byte for byte.
+    pub keys: usize,
+    pub redirect: Redirect,
+}
+
+/// Copies the whole repository from one backend to another, verifies the copy and leaves a
+/// Redirect to location in the old one, so the other devices switch over the next time they
+/// open it. Every copy is read back and decrypted and decoded on its own, key wraps and the
+/// device list are compared as they are.
+///
+/// Nothing may change the repository meanwhile, the caller should hold its RemoteLock. The
+/// lock itself isn't copied. The old objects are kept, only the redirect is added to them, and
+/// if the copy doesn't verify no redirect is written.
+pub fn migrate<S, D>(from: &mut S, to: &mut D, c: &mut CryptoManager, location: &str)
+                     -> Result<MigrationReport, StorageError>
+    where S: Backend,
+          D: Backend
+{
+    if try!(read_redirect(from, c)).is_some() {
+        return Err(StorageError::Protocol("The repository was already migrated".to_string()));
+    }
+
+    let names = try!(from.list(""))
+                    .into_iter()
+                    .filter(|n| n != LOCK_NAME)
+                    .collect::<Vec<_>>();
+    let mut bytes = 0;
+    for name in names.iter() {
+        let data = try!(from.get(name));
+        bytes += data.len() as u64;
+        try!(to.put(name, &data));
+    }
+
+    let devices = match from.get(REPOSITORY_NAME) {
+        Ok(data) => decode_manifest(&data, c).map(|m| m.devices).unwrap_or_else(|_| DEVICES_NAME.to_string()),
+        Err(StorageError::NotFound(_)) => DEVICES_NAME.to_string(),
+        Err(e) => return Err(e),
+    };
+    let mut decrypted = 0;
+    let mut keys = 0;
+    for name in names.iter() {
+        let original = try!(from.get(name));
+        let copy = match to.get(name) {
+            Ok(copy) => copy,
+            Err(StorageError::NotFound(_)) => {
+                return Err(StorageError::Corrupt(format!("{} is missing in the copy", name)))
+            }
+            Err(e) => return Err(e),
+        };
+        match try!(verify(name, original, copy, c, &devices)) {
+            Verified::Decrypted => decrypted += 1,
+            Verified::Key => keys += 1,
+            Verified::Bytes => (),
+        }
+    }
+
+    let redirect = Redirect {
+        location: location.to_string(),
+        migrated: UTC::now(),
+    };
+    try!(save_to(from, REDIRECT_NAME, c, &redirect));
+    Ok(MigrationReport {
+        copied: names.len(),
+        bytes: bytes,
+        decrypted: decrypted,
+        keys: keys,
+        redirect: redirect,
+    })
+}
+
+/// How the copy of an object was found equal to the original.
+enum Verified {
+    Decrypted,
+    Key,
+    Bytes,
+}
+
+/// Checks the copy of an object on its own: it has to decrypt and decode to the same values as
+/// the original, unless the object isn't encrypted with the key of c.
+fn verify(name: &str, original: Vec<u8>, copy: Vec<u8>, c: &CryptoManager, devices: &str)
+          -> Result<Verified, StorageError> {
+    let differs = || StorageError::Corrupt(format!("{} differs in the copy", name));
+    let segment = name.starts_with(LOG_PREFIX) && name[LOG_PREFIX.len()..].parse::<usize>().is_ok();
+
+    if segment {
+        let copied = try!(decode_log(copy, c).map_err(|_| differs()));
+        if copied != try!(decode_log(original, c)) {
+            return Err(differs());
+        }
+        Ok(Verified::Decrypted)
+    } else if name == REPOSITORY_NAME {
+        // Signed, not encrypted. A manifest of a newer format can only be compared as is.
+        match decode_manifest(&original, c) {
+            Ok(manifest) => {
+                if decode_manifest(&copy, c).ok() != Some(manifest) {
+                    return Err(differs());
+                }
+            }
+            Err(_) if copy != original => return Err(differs()),
+            Err(_) => (),
+        }
+        Ok(Verified::Bytes)
+    } else if name == devices || name.starts_with(KEYS_PREFIX) {
+        if copy != original {
+            return Err(differs());
+        }
+        Ok(Verified::Key)
+    } else {
+        match c.decrypt_bytes(original.clone()) {
+            Some(plain) => {
+                if c.decrypt_bytes(copy) != Some(plain) {
+                    return Err(StorageError::Corrupt(format!("{} does not decrypt in the copy", name)));
+                }
+                Ok(Verified::Decrypted)
+            }
+            // Streams and the like have formats of their own, comparing the bytes has to do.
+            None if copy != original => Err(differs()),
+            None => Ok(Verified::Bytes),
+        }
+    }
+}
+
+/// Returns where the repository moved to, None if it wasn't migrated.
+pub fn read_redirect<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<Option<Redirect>, StorageError> {
+    match load_from(b, REDIRECT_NAME, c) {
+        Ok(r) => Ok(Some(r)),
+        Err(StorageError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -44,6 +44,9 @@
 //!
 //! #### Shared Log
 //! The shared log is pulled from the file server or hosting provider that is used.
+//! It is stored as a sequence of segments `log/0`, `log/1`, ..., each synchronization
+//! adds a segment and segments are never changed, so a device only downloads the
+//! segments added since its last synchronization, see the log module.
 //!
 //! ### Basic Synchronization
 //! 1. Clean local log to hold just the create events for the objects with all
@@ -52,16 +55,24 @@
 //! 2. Merge update events in the remote log and/or remove all update events for
 //! objects that have been deleted at a later point in the session.
 //! 3. Try to get a timed lock on the server
-//! 4. Download shared log
+//! 4. Download the segments of the shared log added since the last synchronization
 //! 5. Search backwards in shared log for timestamp of last saved synchronization
-//! event.
-//! 6. Insert remote log events line by line in the correct order, so the shared log
-//! is correctly ordered by the timestamp. *Is this useful?*
+//! event. Every device keeps this cursor and publishes it in the repository, see the
+//! cursor module.
+//! 6. Merge remote log events with the new events of the shared log by timestamp, which
+//! is the order they take effect in. The events are appended to the shared log rather
+//! than inserted, as other devices may already have read past the place they belong.
+//! Instead every device skips events older than the last one of their object that
+//! took effect.
+//! Changes of the same event by two devices that didn't see each other's change are
+//! resolved first, see the conflict module.
 //! 7. Add all events from local log to the bottom of the shared log.
-//! 8. Upload shared log
+//! 8. Upload the new events as the next segment of the shared log
 //! 9. Remove timed lock
 //! 10. Update data structure according to shared log locally.
 //!
+//! The steps are implemented by the Synchronizer of the sync module.
+//!
 //! #### Example
 //! In this example we create 2 objects locally and make updates on 3 remote objects.
 //!
@@ -134,6 +145,8 @@
 //! | | | *TS_6* U 1515:changed params|
 //! | | | *TS_4* U 2236:changed params |
 //!
+//! In the uploaded shared log TS_2, TS_3 and TS_4 follow TS_6, see step 6.
+//!
 //! ##### Merge Local Log and Shared Log
 //! Timestamp order: TS_2 < TS_3 < TS_5 < TS_6 < TS_4 < TS_7
 //!
@@ -149,17 +162,84 @@
 //!
 //! ### Sanitation
 //! To keep the shared log as small as possible, the master device will regularly
-//! sanitize the shared log. This means that a certain number of lines will be
+//! sanitize the shared log. This means that the oldest segments will be
 //! transferred from the shared log to a backup log at certain time intervals. If
 //! a device has not been synchronized for a long period of time it can check if it
 //! needs to read from the backup log first by comparing its saved timestamp from
-//! its last update with the timestamp of the backup log.
+//! its last update with the timestamp of the backup log. This is implemented by
+//! the backup module.
 
 use std::io::Write;
 use std::io::Read;
 use crypto::CryptoManager;
 use rustc_serialize::{Encodable, Decodable, json};
 
+pub use self::backend::{Backend, Resumable, StorageError, UploadSession, Version};
+
+/// The Backend trait every storage provider implements.
+pub mod backend;
+
+/// Backend for the Dropbox v2 HTTP API.
+pub mod dropbox;
+
+/// Backend keeping the objects in a git repository.
+pub mod git;
+
+/// Backend keeping the objects as messages in an IMAP folder.
+pub mod imap;
+
+/// Backend keeping the objects in memory, with fault injection for testing.
+pub mod memory;
+
+/// Timed lock on a backend, used while synchronizing.
+pub mod lock;
+
+/// Backend replicating objects to several other backends.
+pub mod replicated;
+
+/// Backend keeping the objects in a local directory.
+pub mod directory;
+
+/// Offline-first local cache in front of a remote backend.
+pub mod cache;
+
+/// Content-addressed store splitting large objects into deduplicated chunks.
+pub mod chunks;
+
+/// The shared log in the backend.
+pub mod log;
+
+/// Snapshots of the whole account and restoring them.
+pub mod snapshot;
+
+/// Verification of all objects in a repository.
+pub mod scrub;
+
+/// Saving and loading in bounded memory.
+pub mod stream;
+
+/// Asynchronous backends and operations, based on futures.
+pub mod asynchronous;
+
+/// Retrying failed operations and resumable uploads.
+pub mod retry;
+
+/// Sanitation of the shared log into the backup log.
+pub mod backup;
+
+/// Moving a repository to another backend.
+pub mod migrate;
+
+/// The repository manifest, creating and opening repositories.
+pub mod repository;
+
+/// How far each device got with synchronizing.
+pub mod cursor;
+
+/// Local store of the decrypted state in SQLite, with the sqlite feature.
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
 pub fn save<W: Write, S: Encodable>(w: &mut W, c: &mut CryptoManager, s: &S) {
     let enc = json::encode(s).unwrap();
 
@@ -177,3 +257,42 @@ pub fn load<R: Read, D: Decodable>(c: &CryptoManager, r: &mut R) -> D {
 
     json::decode(&enc).unwrap()
 }
+
+/// Encrypts s and stores it in the backend under the given name.
+pub fn save_to<B: Backend, S: Encodable>(b: &mut B, name: &str, c: &mut CryptoManager, s: &S)
+                                         -> Result<(), StorageError> {
+    let enc = try!(seal_object(name, c, s));
+    b.put(name, &enc)
+}
+
+/// Loads and decrypts the object with the given name from the backend. Objects that don't
+/// decrypt or decode, e.g. because they were only partially written, give
+/// StorageError::Corrupt.
+pub fn load_from<B: Backend, D: Decodable>(b: &mut B, name: &str, c: &CryptoManager)
+                                           -> Result<D, StorageError> {
+    let enc = try!(b.get(name));
+    open_object(name, enc, c)
+}
+
+/// Encodes and encrypts s the way save_to stores it. name is only used for errors.
+pub fn seal_object<S: Encodable>(name: &str, c: &mut CryptoManager, s: &S)
+                                 -> Result<Vec<u8>, StorageError> {
+    let enc = match json::encode(s) {
+        Ok(e) => e,
+        Err(e) => return Err(StorageError::Protocol(format!("Can't encode {}: {}", name, e))),
+    };
+    match c.encrypt(&enc) {
+        Some(e) => Ok(e),
+        None => Err(StorageError::Protocol(format!("Can't encrypt {}", name))),
+    }
+}
+
+/// Decrypts and decodes an object stored by save_to. name is only used for errors.
+pub fn open_object<D: Decodable>(name: &str, enc: Vec<u8>, c: &CryptoManager)
+                                 -> Result<D, StorageError> {
+    let plain = match c.decrypt(enc) {
+        Some(p) => p,
+        None => return Err(StorageError::Corrupt(format!("{} does not decrypt", name))),
+    };
+    json::decode(&plain).map_err(|e| StorageError::Corrupt(format!("{} does not decode: {}", name, e)))
+}
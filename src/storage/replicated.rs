@@ -0,0 +1,479 @@
+use std::collections::{BTreeSet, HashMap};
+use chrono::UTC;
+use storage::asynchronous::SharedCrypto;
+use storage::backend::{Backend, StorageError, Version};
+use storage::{open_object, seal_object};
+
+/// State of a single replica as reported by ReplicatedBackend::status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaStatus {
+    pub name: String,
+    pub healthy: bool,
+    /// Number of objects waiting to be repaired on this replica.
+    pub pending: usize,
+    pub last_error: Option<String>,
+}
+
+struct Replica {
+    name: String,
+    backend: Box<Backend>,
+    healthy: bool,
+    last_error: Option<String>,
+}
+
+/// Backend writing every object to several other backends, so the data survives if a provider
+/// goes away and stays available while one is down.
+///
+/// Every object is stored with a generation number in front of the data. Reads ask all healthy
+/// replicas and return the copy with the highest generation. Replicas that returned an older
+/// copy are repaired on the fly, replicas that failed are skipped until repair is called.
+///
+/// The objects a replica missed changes of are recorded in the other replicas, so a replica
+/// that missed a delete doesn't bring the object back after a restart. Its copies of them are
+/// ignored until they are repaired.
+pub struct ReplicatedBackend {
+    replicas: Vec<Replica>,
+    crypto: SharedCrypto,
+    min_writes: usize,
+    pending: Vec<(usize, String)>,
+    loaded: bool,
+    generations: HashMap<String, u64>,
+    repaired: usize,
+}
+
+/// Name of the list of objects waiting to be repaired, stored encrypted but without generation
+/// in every replica.
+pub const PENDING_NAME: &'static str = "replication/pending";
+
+/// Size of the generation number in front of every object.
+const HEADER: usize = 8;
+
+fn wrap(generation: u64, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER + data.len());
+    for i in 0..HEADER {
+        out.push((generation >> (8 * (HEADER - 1 - i))) as u8);
+    }
+    out.extend_from_slice(data);
+    out
+}
+
+fn unwrap(data: &[u8]) -> Option<u64> {
+    if data.len() < HEADER {
+        return None;
+    }
+    Some(data[..HEADER].iter().fold(0, |g, &b| (g << 8) | b as u64))
+}
+
+/// Errors after which a replica is considered down.
+fn is_outage(e: &StorageError) -> bool {
+    match *e {
+        StorageError::Unavailable(_) | StorageError::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// What a single replica has stored of an object.
+struct Stored {
+    replica: usize,
+    generation: u64,
+    version: Version,
+    data: Vec<u8>,
+}
+
+impl ReplicatedBackend {
+    /// Creates a backend without replicas. A write succeeds as soon as one replica stored it.
+    /// c encrypts the list of objects waiting to be repaired, which contains their names.
+    pub fn new(c: SharedCrypto) -> ReplicatedBackend {
+        ReplicatedBackend {
+            replicas: Vec::new(),
+            crypto: c,
+            min_writes: 1,
+            pending: Vec::new(),
+            loaded: false,
+            generations: HashMap::new(),
+            repaired: 0,
+        }
+    }
+
+    /// Adds another backend to replicate to.
+    pub fn add<B: Backend + 'static>(&mut self, name: &str, backend: B) {
+        self.replicas.push(Replica {
+            name: name.to_string(),
+            backend: Box::new(backend),
+            healthy: true,
+            last_error: None,
+        });
+    }
+
+    /// Sets on how many replicas a write has to succeed.
+    pub fn set_min_writes(&mut self, n: usize) {
+        self.min_writes = n;
+    }
+
+    /// Returns the state of all replicas.
+    pub fn status(&self) -> Vec<ReplicaStatus> {
+        self.replicas
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                ReplicaStatus {
+                    name: r.name.clone(),
+                    healthy: r.healthy,
+                    pending: self.pending.iter().filter(|p| p.0 == i).count(),
+                    last_error: r.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks replicas that are down again and brings all replicas to the same state. Returns
+    /// the number of objects that had to be repaired.
+    pub fn repair(&mut self) -> Result<usize, StorageError> {
+        self.load_pending();
+        self.repaired = 0;
+        for i in 0..self.replicas.len() {
+            if !self.replicas[i].healthy {
+                if let Ok(_) = self.replicas[i].backend.list("") {
+                    self.replicas[i].healthy = true;
+                    self.replicas[i].last_error = None;
+                }
+            }
+        }
+        // Replicas that were down have an outdated list of pending objects.
+        self.save_pending();
+
+        // Deletions first, so deleted objects aren't copied back from lagging replicas.
+        let pending = ::std::mem::replace(&mut self.pending, Vec::new());
+        for (i, name) in pending {
+            if !self.replicas[i].healthy {
+                self.remember(i, &name);
+                continue;
+            }
+            let elsewhere = match self.copies(&name) {
+                Ok(copies) => copies.iter().any(|c| c.replica != i),
+                Err(_) => {
+                    self.remember(i, &name);
+                    continue;
+                }
+            };
+            if !elsewhere {
+                match self.replicas[i].backend.delete(&name) {
+                    Ok(()) => self.repaired += 1,
+                    Err(StorageError::NotFound(_)) => (),
+                    Err(e) => self.failed(i, &name, e),
+                }
+            }
+        }
+        self.save_pending();
+
+        let mut names = BTreeSet::new();
+        for i in 0..self.replicas.len() {
+            if !self.replicas[i].healthy {
+                continue;
+            }
+            match self.replicas[i].backend.list("") {
+                Ok(l) => names.extend(l),
+                Err(e) => self.failed(i, "", e),
+            }
+        }
+        names.remove(PENDING_NAME);
+        for name in names {
+            match self.get_versioned(&name) {
+                Ok(_) | Err(StorageError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.repaired)
+    }
+
+    /// Marks a replica as down if the error says so and remembers to repair the object.
+    fn failed(&mut self, i: usize, name: &str, e: StorageError) {
+        if is_outage(&e) {
+            self.replicas[i].healthy = false;
+        }
+        self.replicas[i].last_error = Some(format!("{}", e));
+        if !name.is_empty() {
+            self.remember(i, name);
+        }
+    }
+
+    /// Remembers that the object has to be repaired on the replica.
+    fn remember(&mut self, i: usize, name: &str) {
+        if !self.pending.iter().any(|p| p.0 == i && p.1 == name) {
+            self.pending.push((i, name.to_string()));
+            self.save_pending();
+        }
+    }
+
+    fn is_pending(&self, i: usize, name: &str) -> bool {
+        self.pending.iter().any(|p| p.0 == i && p.1 == name)
+    }
+
+    /// Reads the pending objects recorded by earlier instances, once.
+    fn load_pending(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+        for i in self.healthy() {
+            let data = match self.replicas[i].backend.get(PENDING_NAME) {
+                Ok(d) => d,
+                Err(StorageError::NotFound(_)) => continue,
+                Err(e) => {
+                    self.failed(i, "", e);
+                    continue;
+                }
+            };
+            let recorded = open_object::<Vec<(String, String)>>(PENDING_NAME, data, &self.crypto.lock().unwrap());
+            let recorded = match recorded {
+                Ok(r) => r,
+                Err(e) => {
+                    self.failed(i, "", e);
+                    continue;
+                }
+            };
+            for (replica, name) in recorded {
+                if let Some(j) = self.replicas.iter().position(|r| r.name == replica) {
+                    if !self.is_pending(j, &name) {
+                        self.pending.push((j, name));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the pending objects in all healthy replicas.
+    fn save_pending(&mut self) {
+        let recorded = self.pending
+                           .iter()
+                           .map(|p| (self.replicas[p.0].name.clone(), p.1.clone()))
+                           .collect::<Vec<_>>();
+        let data = seal_object(PENDING_NAME, &mut self.crypto.lock().unwrap(), &recorded).unwrap();
+        for i in self.healthy() {
+            if let Err(e) = self.replicas[i].backend.put(PENDING_NAME, &data) {
+                self.failed(i, "", e);
+            }
+        }
+    }
+
+    fn healthy(&self) -> Vec<usize> {
+        (0..self.replicas.len()).filter(|&i| self.replicas[i].healthy).collect()
+    }
+
+    /// Reads the object from all healthy replicas.
+    fn copies(&mut self, name: &str) -> Result<Vec<Stored>, StorageError> {
+        let mut copies = Vec::new();
+        let mut answered = 0;
+        for i in self.healthy() {
+            match self.replicas[i].backend.get_versioned(name) {
+                Ok((data, version)) => {
+                    answered += 1;
+                    match unwrap(&data) {
+                        Some(g) => {
+                            copies.push(Stored {
+                                replica: i,
+                                generation: g,
+                                version: version,
+                                data: data,
+                            })
+                        }
+                        None => self.failed(i, name, StorageError::Corrupt(name.to_string())),
+                    }
+                }
+                Err(StorageError::NotFound(_)) => answered += 1,
+                Err(e) => self.failed(i, name, e),
+            }
+        }
+        if answered == 0 {
+            return Err(StorageError::Unavailable("No replica available".to_string()));
+        }
+        Ok(copies)
+    }
+
+    /// Writes to all healthy replicas. With a condition, the given replica is written first
+    /// with put_if at the given version, and only a Conflict there is reported. The other
+    /// replicas follow it with put, which also brings pending and lagging replicas up to date.
+    fn write(&mut self, name: &str, data: &[u8], condition: Option<(usize, Option<Version>)>)
+             -> Result<(), StorageError> {
+        let mut written = 0;
+        let mut others = self.healthy();
+        if let Some((primary, expected)) = condition {
+            match self.replicas[primary].backend.put_if(name, data, expected.as_ref()) {
+                Ok(_) => {
+                    written += 1;
+                    self.caught_up(primary, name);
+                }
+                Err(StorageError::Conflict(c)) => return Err(StorageError::Conflict(c)),
+                Err(e) => {
+                    // Without the primary the condition can't be checked.
+                    let message = format!("{}", e);
+                    self.failed(primary, name, e);
+                    return Err(StorageError::Unavailable(message));
+                }
+            }
+            others.retain(|&i| i != primary);
+        }
+        for i in others {
+            match self.replicas[i].backend.put(name, data) {
+                Ok(()) => {
+                    written += 1;
+                    self.caught_up(i, name);
+                }
+                Err(e) => self.failed(i, name, e),
+            }
+        }
+        for i in 0..self.replicas.len() {
+            if !self.replicas[i].healthy {
+                self.remember(i, name);
+            }
+        }
+
+        if written < self.min_writes {
+            return Err(StorageError::Unavailable(format!("Only {} replicas written", written)));
+        }
+        Ok(())
+    }
+
+    /// The replica has the current copy of the object now.
+    fn caught_up(&mut self, i: usize, name: &str) {
+        if self.is_pending(i, name) {
+            self.pending.retain(|p| p.0 != i || p.1 != name);
+            self.save_pending();
+        }
+    }
+
+    /// New generations are based on the time, so that unconditional writes of different
+    /// devices are ordered by when they happened.
+    fn next_generation(&mut self, name: &str, current: u64) -> u64 {
+        let known = self.generations.get(name).cloned().unwrap_or(0);
+        let now = UTC::now().timestamp() as u64 * 1000;
+        let generation = *[now, current + 1, known + 1].iter().max().unwrap();
+        self.generations.insert(name.to_string(), generation);
+        generation
+    }
+}
+
+impl Backend for ReplicatedBackend {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        self.load_pending();
+        let (copies, outdated): (Vec<_>, Vec<_>) = try!(self.copies(name))
+                                                       .into_iter()
+                                                       .partition(|c| !self.is_pending(c.replica, name));
+        let best = match copies.iter().max_by_key(|c| c.generation) {
+            Some(b) => b,
+            None => {
+                // Only replicas that missed its deletion still have the object.
+                for c in outdated {
+                    match self.replicas[c.replica].backend.delete(name) {
+                        Ok(()) | Err(StorageError::NotFound(_)) => {
+                            self.repaired += 1;
+                            self.pending.retain(|p| p.0 != c.replica || p.1 != name);
+                            self.save_pending();
+                        }
+                        Err(e) => self.failed(c.replica, name, e),
+                    }
+                }
+                return Err(StorageError::NotFound(name.to_string()));
+            }
+        };
+
+        for i in self.healthy() {
+            let fresh = copies.iter().any(|c| c.replica == i && c.generation == best.generation);
+            if fresh {
+                continue;
+            }
+            match self.replicas[i].backend.put(name, &best.data) {
+                Ok(()) => {
+                    self.repaired += 1;
+                    self.caught_up(i, name);
+                }
+                Err(e) => self.failed(i, name, e),
+            }
+        }
+
+        self.generations.insert(name.to_string(), best.generation);
+        Ok((best.data[HEADER..].to_vec(), Version(best.generation.to_string())))
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.load_pending();
+        let generation = self.next_generation(name, 0);
+        self.write(name, &wrap(generation, data), None)
+    }
+
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        self.load_pending();
+        let copies = try!(self.copies(name));
+        let copies = copies.into_iter().filter(|c| !self.is_pending(c.replica, name)).collect::<Vec<_>>();
+        let current = copies.iter().map(|c| c.generation).max();
+        if current.map(|g| Version(g.to_string())).as_ref() != expected {
+            return Err(StorageError::Conflict(name.to_string()));
+        }
+
+        // The condition is checked again on a replica with the current copy, or on one without
+        // a copy that isn't pending if the object doesn't exist.
+        let primary = match copies.iter().find(|c| Some(c.generation) == current) {
+            Some(c) => (c.replica, Some(c.version.clone())),
+            None => {
+                match self.healthy().into_iter().find(|&i| !self.is_pending(i, name)) {
+                    Some(i) => (i, None),
+                    None => return Err(StorageError::Unavailable("No replica available".to_string())),
+                }
+            }
+        };
+        let generation = self.next_generation(name, current.unwrap_or(0));
+        try!(self.write(name, &wrap(generation, data), Some(primary)));
+        Ok(Version(generation.to_string()))
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        self.load_pending();
+        let mut deleted = 0;
+        let mut answered = 0;
+        for i in 0..self.replicas.len() {
+            if !self.replicas[i].healthy {
+                self.remember(i, name);
+                continue;
+            }
+            match self.replicas[i].backend.delete(name) {
+                Ok(()) => {
+                    deleted += 1;
+                    answered += 1;
+                }
+                Err(StorageError::NotFound(_)) => answered += 1,
+                Err(e) => self.failed(i, name, e),
+            }
+        }
+
+        if answered == 0 {
+            Err(StorageError::Unavailable("No replica available".to_string()))
+        } else if deleted == 0 {
+            Err(StorageError::NotFound(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.load_pending();
+        let mut names = BTreeSet::new();
+        let mut answered = 0;
+        for i in self.healthy() {
+            match self.replicas[i].backend.list(prefix) {
+                Ok(l) => {
+                    answered += 1;
+                    let current = l.into_iter()
+                                   .filter(|n| n != PENDING_NAME && !self.is_pending(i, n))
+                                   .collect::<Vec<_>>();
+                    names.extend(current);
+                }
+                Err(e) => self.failed(i, "", e),
+            }
+        }
+        if answered == 0 {
+            return Err(StorageError::Unavailable("No replica available".to_string()));
+        }
+        Ok(names.into_iter().collect())
+    }
+}
@@ -0,0 +1,243 @@
+use std::error::Error;
+use std::fmt;
+use chrono::{DateTime, UTC};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use sodiumoxide::crypto::auth;
+use uuid::Uuid;
+use crypto::CryptoManager;
+use storage::backend::{Backend, StorageError};
+use storage::backup::BACKUP_PREFIX;
+use storage::chunks::{CHUNK_PREFIX, MANIFEST_PREFIX};
+use storage::log::LOG_PREFIX;
+use storage::migrate::{read_redirect, Redirect};
+use storage::snapshot::SNAPSHOT_PREFIX;
+
+/// Name of the repository manifest in the backend.
+pub const REPOSITORY_NAME: &'static str = "repository";
+
+/// Version of the repository format written by init. open refuses newer ones.
+pub const FORMAT_VERSION: u64 = 2;
+
+/// Oldest repository format open accepts. Format 1 kept the shared log in a single object,
+/// which isn't read anymore.
+pub const OLDEST_FORMAT_VERSION: u64 = 2;
+
+/// Name of the device list in the backend.
+pub const DEVICES_NAME: &'static str = "devices";
+
+/// Prefix of the key wraps: the repository key encrypted for the public key of each device.
+pub const KEYS_PREFIX: &'static str = "keys/";
+
+/// How objects are encrypted and identified.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct CryptoParams {
+    pub cipher: String,
+    pub mac: String,
+}
+
+/// Where the parts of the log and the other objects are stored.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LogLayout {
+    pub log: String,
+    pub backup_prefix: String,
+    pub snapshot_prefix: String,
+    pub chunk_prefix: String,
+    pub manifest_prefix: String,
+}
+
+/// Describes a repository. It is stored unencrypted, so an incompatible version is recognized
+/// before anything is decrypted, but authenticated with the repository key.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct RepositoryManifest {
+    pub format: u64,
+    pub id: String,
+    pub created: DateTime<UTC>,
+    pub crypto: CryptoParams,
+    /// Name of the device list object.
+    pub devices: String,
+    pub layout: LogLayout,
+}
+
+impl RepositoryManifest {
+    /// The manifest of a new repository in the current format.
+    pub fn new() -> RepositoryManifest {
+        RepositoryManifest {
+            format: FORMAT_VERSION,
+            id: Uuid::new_v4().to_string(),
+            created: UTC::now(),
+            crypto: CryptoParams {
+                cipher: "xsalsa20poly1305".to_string(),
+                mac: "hmacsha512256".to_string(),
+            },
+            devices: DEVICES_NAME.to_string(),
+            layout: LogLayout {
+                log: LOG_PREFIX.to_string(),
+                backup_prefix: BACKUP_PREFIX.to_string(),
+                snapshot_prefix: SNAPSHOT_PREFIX.to_string(),
+                chunk_prefix: CHUNK_PREFIX.to_string(),
+                manifest_prefix: MANIFEST_PREFIX.to_string(),
+            },
+        }
+    }
+}
+
+/// The manifest object: the encoded manifest and its signature, so the signature covers
+/// exactly the stored bytes.
+#[derive(RustcEncodable, RustcDecodable)]
+struct SignedManifest {
+    manifest: String,
+    signature: String,
+}
+
+/// Errors when creating or opening a Repository.
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// There already is a repository in the backend.
+    Exists,
+    /// There is no repository in the backend.
+    NotARepository,
+    /// The manifest wasn't signed with the key of this device.
+    BadSignature,
+    /// The repository has a format this version doesn't understand.
+    Incompatible(u64),
+    /// The repository was migrated to another location.
+    Moved(Redirect),
+    /// Error of the backend.
+    Storage(StorageError),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RepositoryError::Exists => write!(f, "Repository exists"),
+            RepositoryError::NotARepository => write!(f, "Not a repository"),
+            RepositoryError::BadSignature => write!(f, "Repository manifest has a bad signature"),
+            RepositoryError::Incompatible(v) => {
+                write!(f, "Repository format {} is not supported, only {} to {}", v, OLDEST_FORMAT_VERSION,
+                       FORMAT_VERSION)
+            }
+            RepositoryError::Moved(ref r) => write!(f, "Repository moved to {}", r.location),
+            RepositoryError::Storage(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RepositoryError {
+    fn description(&self) -> &str {
+        match *self {
+            RepositoryError::Exists => "repository exists",
+            RepositoryError::NotARepository => "not a repository",
+            RepositoryError::BadSignature => "bad manifest signature",
+            RepositoryError::Incompatible(_) => "incompatible repository format",
+            RepositoryError::Moved(_) => "repository moved",
+            RepositoryError::Storage(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<StorageError> for RepositoryError {
+    fn from(e: StorageError) -> RepositoryError {
+        RepositoryError::Storage(e)
+    }
+}
+
+/// A shared repository: a backend holding a signed manifest. This is the "Set up repo" step of
+/// the key exchange, every device opens the repository before synchronizing with it.
+pub struct Repository<B: Backend> {
+    backend: B,
+    manifest: RepositoryManifest,
+}
+
+impl<B: Backend> Repository<B> {
+    /// Creates a new repository in backend, signing its manifest with the key of c.
+    pub fn init(mut backend: B, c: &CryptoManager) -> Result<Repository<B>, RepositoryError> {
+        let manifest = RepositoryManifest::new();
+        match backend.put_if(REPOSITORY_NAME, &encode_manifest(&manifest, c), None) {
+            Ok(_) => (),
+            Err(StorageError::Conflict(_)) => return Err(RepositoryError::Exists),
+            Err(e) => return Err(RepositoryError::Storage(e)),
+        }
+        Ok(Repository {
+            backend: backend,
+            manifest: manifest,
+        })
+    }
+
+    /// Opens the repository in backend after checking its manifest.
+    pub fn open(mut backend: B, c: &CryptoManager) -> Result<Repository<B>, RepositoryError> {
+        if let Some(r) = try!(read_redirect(&mut backend, c)) {
+            return Err(RepositoryError::Moved(r));
+        }
+        let data = match backend.get(REPOSITORY_NAME) {
+            Ok(d) => d,
+            Err(StorageError::NotFound(_)) => return Err(RepositoryError::NotARepository),
+            Err(e) => return Err(RepositoryError::Storage(e)),
+        };
+        let manifest = try!(decode_manifest(&data, c));
+        Ok(Repository {
+            backend: backend,
+            manifest: manifest,
+        })
+    }
+
+    pub fn manifest(&self) -> &RepositoryManifest {
+        &self.manifest
+    }
+
+    pub fn backend(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+}
+
+/// Signs manifest with the key of c and encodes it as content of the manifest object.
+pub fn encode_manifest(manifest: &RepositoryManifest, c: &CryptoManager) -> Vec<u8> {
+    let encoded = json::encode(manifest).unwrap();
+    let signed = SignedManifest {
+        signature: sign(encoded.as_bytes(), c).to_hex(),
+        manifest: encoded,
+    };
+    json::encode(&signed).unwrap().into_bytes()
+}
+
+/// Checks the signature and the format of the content of the manifest object and decodes it.
+pub fn decode_manifest(data: &[u8], c: &CryptoManager) -> Result<RepositoryManifest, RepositoryError> {
+    let corrupt = |what: &str| {
+        RepositoryError::Storage(StorageError::Corrupt(format!("{} {}", REPOSITORY_NAME, what)))
+    };
+    let text = String::from_utf8_lossy(data);
+    let signed: SignedManifest = match json::decode(&text) {
+        Ok(s) => s,
+        Err(_) => return Err(corrupt("does not decode")),
+    };
+    let tag = match signed.signature.from_hex().ok().and_then(|t| auth::Tag::from_slice(&t)) {
+        Some(t) => t,
+        None => return Err(corrupt("has no valid signature")),
+    };
+    if !auth::verify(&tag, signed.manifest.as_bytes(), &signing_key(c)) {
+        return Err(RepositoryError::BadSignature);
+    }
+
+    // Newer formats may not decode, so the version is checked on its own first.
+    let json = Json::from_str(&signed.manifest).ok();
+    match json.as_ref().and_then(|j| j.find("format")).and_then(|f| f.as_u64()) {
+        Some(v) if v > FORMAT_VERSION || v < OLDEST_FORMAT_VERSION => return Err(RepositoryError::Incompatible(v)),
+        Some(_) => (),
+        None => return Err(corrupt("has no format")),
+    }
+    json::decode(&signed.manifest).map_err(|_| corrupt("does not decode"))
+}
+
+fn sign(data: &[u8], c: &CryptoManager) -> Vec<u8> {
+    let auth::Tag(t) = auth::authenticate(data, &signing_key(c));
+    t.to_vec()
+}
+
+fn signing_key(c: &CryptoManager) -> auth::Key {
+    let auth::Tag(k) = auth::authenticate(b"cryptocontent repository manifest", &auth::Key(c.symkey.0));
+    auth::Key(k)
+}
@@ -0,0 +1,230 @@
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+use sodiumoxide::randombytes::randombytes;
+use storage::backend::{Backend, Resumable, StorageError, UploadSession, Version};
+
+/// How often and how fast failed operations are tried again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of tries in total, including the first one.
+    pub attempts: usize,
+    /// Wait before the first retry.
+    pub initial_delay: Duration,
+    /// The wait doubles with every retry, up to this.
+    pub max_delay: Duration,
+    /// Share of the wait (between 0 and 1) that is randomly left out, so devices that failed
+    /// at the same time don't all retry at the same time.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 5,
+            initial_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the wait before the given retry (starting at 0), random being a number between
+    /// 0 and 1.
+    pub fn delay(&self, retry: usize, random: f64) -> Duration {
+        let base = self.initial_delay.checked_mul(1 << cmp::min(retry, 31) as u32)
+                                     .unwrap_or(self.max_delay);
+        let base = cmp::min(base, self.max_delay);
+        let ms = base.as_secs() as f64 * 1000.0 + (base.subsec_nanos() / 1000000) as f64;
+        Duration::from_millis((ms * (1.0 - self.jitter * random)) as u64)
+    }
+}
+
+/// How far an upload got, as reported by RetryingBackend::upload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Bytes the provider has received.
+    pub sent: u64,
+    pub total: u64,
+    /// Number of failed requests so far.
+    pub retries: usize,
+}
+
+/// Backend retrying the operations of another backend if they fail with an error that
+/// StorageError::is_retryable, waiting longer after each failure.
+///
+/// For backends that support it, large objects can be uploaded in parts, so a failure only
+/// repeats the current part.
+pub struct RetryingBackend<B: Backend> {
+    inner: B,
+    policy: RetryPolicy,
+    sleep: Box<FnMut(Duration) + Send>,
+    rng: u64,
+    retries: usize,
+    part_size: usize,
+}
+
+impl<B: Backend> RetryingBackend<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> RetryingBackend<B> {
+        let seed = randombytes(8).iter().fold(0u64, |s, &b| (s << 8) | b as u64);
+        RetryingBackend {
+            inner: inner,
+            policy: policy,
+            sleep: Box::new(thread::sleep),
+            rng: seed | 1,
+            retries: 0,
+            part_size: 4 * 1024 * 1024,
+        }
+    }
+
+    /// Replaces how the backend waits between retries, e.g. to not wait at all in tests.
+    pub fn set_sleep<F: FnMut(Duration) + Send + 'static>(&mut self, sleep: F) {
+        self.sleep = Box::new(sleep);
+    }
+
+    /// Sets the size of the parts of uploads.
+    pub fn set_part_size(&mut self, size: usize) {
+        assert!(size > 0);
+        self.part_size = size;
+    }
+
+    /// Returns the number of retries so far.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    pub fn inner(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    /// Waits before the given retry.
+    fn backoff(&mut self, retry: usize) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let random = (self.rng % 1000000) as f64 / 1000000.0;
+        let delay = self.policy.delay(retry, random);
+        self.retries += 1;
+        (self.sleep)(delay);
+    }
+
+    /// Runs op until it succeeds, fails with an error that isn't retryable or the attempts
+    /// are used up.
+    fn retry<T, F>(&mut self, mut op: F) -> Result<T, StorageError>
+        where F: FnMut(&mut B) -> Result<T, StorageError>
+    {
+        let mut retry = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(ref e) if e.is_retryable() && retry + 1 < self.policy.attempts => (),
+                r => return r,
+            }
+            self.backoff(retry);
+            retry += 1;
+        }
+    }
+}
+
+impl<B: Resumable> RetryingBackend<B> {
+    /// Uploads data as the object name in parts. See continue_upload.
+    pub fn upload(&mut self, name: &str, data: &[u8], progress: &mut FnMut(Progress))
+                  -> Result<Version, StorageError> {
+        let mut session = try!(self.retry(|b| b.start_upload(name)));
+        self.continue_upload(&mut session, data, progress)
+    }
+
+    /// Sends the rest of data from session.offset on and stores the object. Every part is
+    /// retried on its own. If the upload fails anyway, session has the offset to continue
+    /// from, so it can be saved and the upload continued later.
+    pub fn continue_upload(&mut self, session: &mut UploadSession, data: &[u8],
+                           progress: &mut FnMut(Progress))
+                           -> Result<Version, StorageError> {
+        let total = data.len() as u64;
+        let mut failures = 0;
+        loop {
+            progress(Progress {
+                sent: session.offset,
+                total: total,
+                retries: self.retries,
+            });
+            if session.offset >= total {
+                break;
+            }
+
+            let start = session.offset as usize;
+            let end = cmp::min(start + self.part_size, data.len());
+            match self.inner.upload_part(session, &data[start..end]) {
+                Ok(()) => failures = 0,
+                Err(ref e) if e.is_retryable() && failures + 1 < self.policy.attempts => {
+                    self.backoff(failures);
+                    failures += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let session = session.clone();
+        self.retry(|b| b.finish_upload(&session))
+    }
+}
+
+impl<B: Backend> Backend for RetryingBackend<B> {
+    fn get_versioned(&mut self, name: &str) -> Result<(Vec<u8>, Version), StorageError> {
+        self.retry(|b| b.get_versioned(name))
+    }
+
+    fn version(&mut self, name: &str) -> Result<Version, StorageError> {
+        self.retry(|b| b.version(name))
+    }
+
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.retry(|b| b.put(name, data))
+    }
+
+    /// If a retry conflicts, the failed attempt before may have been stored after all. That is
+    /// found out by comparing the stored object with data.
+    fn put_if(&mut self, name: &str, data: &[u8], expected: Option<&Version>)
+              -> Result<Version, StorageError> {
+        let mut failed = false;
+        self.retry(|b| {
+            let r = b.put_if(name, data, expected);
+            match r {
+                Err(StorageError::Conflict(_)) if failed => {
+                    match b.get_versioned(name) {
+                        Ok((ref stored, ref version)) if &stored[..] == data => Ok(version.clone()),
+                        _ => r,
+                    }
+                }
+                Err(ref e) if e.is_retryable() => {
+                    failed = true;
+                    r
+                }
+                r => r,
+            }
+        })
+    }
+
+    /// A retry that doesn't find the object anymore means an earlier attempt deleted it.
+    fn delete(&mut self, name: &str) -> Result<(), StorageError> {
+        let mut failed = false;
+        self.retry(|b| {
+            match b.delete(name) {
+                Err(StorageError::NotFound(_)) if failed => Ok(()),
+                Err(e) => {
+                    failed = failed || e.is_retryable();
+                    Err(e)
+                }
+                r => r,
+            }
+        })
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.retry(|b| b.list(prefix))
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+use crypto::CryptoManager;
+use domain::{EntryType, EventLogEntry};
+use storage::backend::{Backend, StorageError};
+use storage::backup::{read_index, read_segment, BACKUP_INDEX, BACKUP_PREFIX};
+use storage::chunks::{chunk_id, Manifest, CHUNK_PREFIX, MANIFEST_PREFIX};
+use storage::lock::{LockInfo, LOCK_NAME, RELEASED};
+use storage::log::LOG_PREFIX;
+use storage::repository::{decode_manifest, RepositoryError, REPOSITORY_NAME};
+use storage::snapshot::{SnapshotInfo, SNAPSHOT_PREFIX};
+
+/// What is wrong with an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The object doesn't decrypt, authenticate or decode.
+    Corrupt,
+    /// Another object refers to this one, but it doesn't exist.
+    Missing,
+    /// Nothing refers to the object anymore.
+    Orphaned,
+}
+
+/// What can be done about a problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// Remove the object.
+    Delete,
+    /// Remove unreferenced chunks with ChunkStore::gc.
+    CollectGarbage,
+    /// Store the object again from a device that still has it.
+    Reupload,
+    /// Remove the entry with the given index from the shared log.
+    DropLogEntry(usize),
+    /// Roll back to the newest snapshot, see Snapshots::restore.
+    RestoreSnapshot,
+    /// Remove the lock, it can't be held by anyone.
+    BreakLock,
+}
+
+/// A single problem found by scrub.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    /// Name of the affected object.
+    pub name: String,
+    pub problem: Problem,
+    pub detail: String,
+    pub repair: Repair,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is {:?}: {} (repair: {:?})", self.name, self.problem, self.detail, self.repair)
+    }
+}
+
+/// Result of scrub.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubReport {
+    /// Number of objects read.
+    pub checked: usize,
+    /// Number of log entries checked.
+    pub entries: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl ScrubReport {
+    /// True if nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Returns the findings with the given problem.
+    pub fn with_problem(&self, problem: Problem) -> Vec<&Finding> {
+        self.findings.iter().filter(|f| f.problem == problem).collect()
+    }
+
+    fn add(&mut self, name: &str, problem: Problem, detail: String, repair: Repair) {
+        self.findings.push(Finding {
+            name: name.to_string(),
+            problem: problem,
+            detail: detail,
+            repair: repair,
+        });
+    }
+}
+
+/// Reads every object of the repository and checks that it decrypts and decodes, that every
+/// entry of the shared log is valid and that everything referenced exists. Entries moved into
+/// the backup log by the Sanitizer count as part of the log. Nothing is changed,
+/// the report suggests how to repair what was found.
+///
+/// Only fails if the backend can't be read at all.
+pub fn scrub<B: Backend>(b: &mut B, c: &CryptoManager) -> Result<ScrubReport, StorageError> {
+    let mut report = ScrubReport {
+        checked: 0,
+        entries: 0,
+        findings: Vec::new(),
+    };
+    let mut log_ids = None;
+    let mut log = BTreeMap::new();
+    let mut chunks = Vec::new();
+    let mut referenced = HashSet::new();
+    let mut snapshots = BTreeMap::new();
+    let archived = try!(read_archived(b, c, &mut report));
+
+    for name in try!(b.list("")) {
+        if name.starts_with(BACKUP_PREFIX) {
+            // Read with the index by read_archived.
+            continue;
+        }
+        let data = match b.get(&name) {
+            Ok(d) => d,
+            // Removed by another device in the meantime.
+            Err(StorageError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        report.checked += 1;
+
+        if name == LOCK_NAME {
+            if data == RELEASED {
+                continue;
+            }
+            // The lock is stored unencrypted.
+            let info = String::from_utf8(data).ok().and_then(|d| json::decode::<LockInfo>(&d).ok());
+            if info.is_none() {
+                report.add(&name, Problem::Corrupt, "does not decode".to_string(), Repair::BreakLock);
+            }
+            continue;
+        }
+        if name == REPOSITORY_NAME {
+            // Signed, but not encrypted either.
+            match decode_manifest(&data, c) {
+                Ok(_) | Err(RepositoryError::Incompatible(_)) => (),
+                Err(e) => report.add(&name, Problem::Corrupt, e.to_string(), Repair::Reupload),
+            }
+            continue;
+        }
+
+        let plain = match c.decrypt_bytes(data) {
+            Some(p) => p,
+            None => {
+                if name.starts_with(CHUNK_PREFIX) {
+                    chunks.push(name[CHUNK_PREFIX.len()..].to_string());
+                }
+                let repair = if name.starts_with(LOG_PREFIX) {
+                    Repair::RestoreSnapshot
+                } else if name.starts_with(MANIFEST_PREFIX) || name.starts_with(CHUNK_PREFIX) {
+                    Repair::Reupload
+                } else {
+                    Repair::Delete
+                };
+                report.add(&name, Problem::Corrupt, "does not decrypt".to_string(), repair);
+                continue;
+            }
+        };
+
+        if name.starts_with(LOG_PREFIX) {
+            match name[LOG_PREFIX.len()..].parse::<usize>() {
+                Ok(number) => {
+                    log.insert(number, (name, plain));
+                }
+                Err(_) => {
+                    report.add(&name, Problem::Orphaned, "is not a segment of the log".to_string(),
+                               Repair::Delete)
+                }
+            }
+        } else if name.starts_with(CHUNK_PREFIX) {
+            let id = name[CHUNK_PREFIX.len()..].to_string();
+            if chunk_id(c, &plain) != id {
+                report.add(&name, Problem::Corrupt, "content does not match id".to_string(),
+                           Repair::Reupload);
+            }
+            chunks.push(id);
+        } else if name.starts_with(MANIFEST_PREFIX) {
+            match decode::<Manifest>(&plain) {
+                Some(m) => referenced.extend(m.chunks.into_iter().map(|id| (id, name.clone()))),
+                None => {
+                    report.add(&name, Problem::Corrupt, "does not decode".to_string(),
+                               Repair::Reupload)
+                }
+            }
+        } else if name.starts_with(SNAPSHOT_PREFIX) {
+            let id = name[SNAPSHOT_PREFIX.len()..].split('/').next().unwrap().to_string();
+            let entry = snapshots.entry(id).or_insert((None, false));
+            if name.ends_with("/info") {
+                match decode::<SnapshotInfo>(&plain) {
+                    Some(info) => entry.0 = Some(info),
+                    None => {
+                        report.add(&name, Problem::Corrupt, "does not decode".to_string(),
+                                   Repair::Delete)
+                    }
+                }
+            } else {
+                entry.1 = true;
+            }
+        }
+    }
+
+    // Chunks referenced by manifests.
+    let stored = chunks.iter().cloned().collect::<HashSet<_>>();
+    let mut missing = referenced.iter().filter(|r| !stored.contains(&r.0)).collect::<Vec<_>>();
+    missing.sort();
+    for &&(ref id, ref manifest) in missing.iter() {
+        report.add(&format!("{}{}", CHUNK_PREFIX, id), Problem::Missing,
+                   format!("referenced by {}", manifest), Repair::Reupload);
+    }
+    let used = referenced.iter().map(|r| &r.0).collect::<HashSet<_>>();
+    for id in chunks.iter().filter(|id| !used.contains(id)) {
+        report.add(&format!("{}{}", CHUNK_PREFIX, id), Problem::Orphaned,
+                   "not referenced by any manifest".to_string(), Repair::CollectGarbage);
+    }
+
+    if !log.is_empty() || !archived.is_empty() {
+        log_ids = Some(check_log(&log, &archived, &mut report));
+    }
+
+    // Snapshots consist of an info and the account, and refer to a log entry.
+    for (id, (info, account)) in snapshots {
+        let name = format!("{}{}", SNAPSHOT_PREFIX, id);
+        match info {
+            None => {
+                report.add(&format!("{}/account", name), Problem::Orphaned,
+                           "snapshot has no info".to_string(), Repair::Delete)
+            }
+            Some(_) if !account => {
+                report.add(&format!("{}/account", name), Problem::Missing,
+                           "snapshot has no account".to_string(), Repair::Delete)
+            }
+            Some(info) => {
+                if let (Some(last), Some(ids)) = (info.position.last_entry, log_ids.as_ref()) {
+                    if !ids.contains(&last) {
+                        report.add(&format!("{}/info", name), Problem::Missing,
+                                   format!("log entry {} does not exist", last), Repair::Delete);
+                    }
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Reads the entries of the backup log, oldest first. Segments that can't be read are reported
+/// and skipped.
+fn read_archived<B: Backend>(b: &mut B, c: &CryptoManager, report: &mut ScrubReport)
+                             -> Result<Vec<EventLogEntry>, StorageError> {
+    let index = match read_index(b, c) {
+        Ok(i) => i,
+        Err(StorageError::Corrupt(e)) => {
+            report.checked += 1;
+            report.add(BACKUP_INDEX, Problem::Corrupt, e, Repair::Reupload);
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+    if !index.segments.is_empty() {
+        report.checked += 1;
+    }
+
+    let mut entries = Vec::new();
+    for segment in index.segments.iter() {
+        let name = format!("{}{}", BACKUP_PREFIX, segment.id);
+        match read_segment(b, c, segment) {
+            Ok(e) => {
+                report.checked += 1;
+                entries.extend(e);
+            }
+            Err(StorageError::NotFound(_)) => {
+                report.add(&name, Problem::Missing, "listed in the backup index".to_string(),
+                           Repair::Reupload)
+            }
+            Err(StorageError::Corrupt(e)) => {
+                report.checked += 1;
+                report.add(&name, Problem::Corrupt, e, Repair::Reupload)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+/// Checks every entry of the decrypted segments of the shared log on its own, so a single
+/// broken entry doesn't hide the others. The entries of the backup log come before them,
+/// copies of them left in the shared log by an interrupted sanitation are skipped. Entries
+/// are numbered across all segments. Returns the ids of the valid entries.
+fn check_log(log: &BTreeMap<usize, (String, Vec<u8>)>, archived: &[EventLogEntry],
+             report: &mut ScrubReport)
+             -> HashSet<String> {
+    let mut ids = HashSet::new();
+    let mut created = HashSet::new();
+    for entry in archived {
+        match entry.entry_type {
+            EntryType::Create => {
+                created.insert(entry.obj_id.clone());
+            }
+            EntryType::Delete => {
+                created.remove(&entry.obj_id);
+            }
+            EntryType::Update => (),
+        }
+        ids.insert(entry.id.clone());
+    }
+
+    let mut i = 0;
+    for &(ref name, ref plain) in log.values() {
+        let entries = match String::from_utf8(plain.clone()).ok().and_then(|p| Json::from_str(&p).ok()) {
+            Some(Json::Array(entries)) => entries,
+            _ => {
+                report.add(name, Problem::Corrupt, "is not a list of entries".to_string(),
+                           Repair::RestoreSnapshot);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            i += 1;
+            report.entries += 1;
+            if let Some(id) = check_entry(i - 1, entry, name, &ids, &mut created, report) {
+                ids.insert(id);
+            }
+        }
+    }
+    ids
+}
+
+/// Checks the entry with index i of the segment name. Returns its id if it is valid and not a
+/// copy of an entry that came before.
+fn check_entry(i: usize, entry: Json, name: &str, ids: &HashSet<String>,
+               created: &mut HashSet<String>, report: &mut ScrubReport)
+               -> Option<String> {
+    let mut decoder = json::Decoder::new(entry);
+    let entry = match EventLogEntry::decode(&mut decoder) {
+        Ok(e) => e,
+        Err(e) => {
+            report.add(name, Problem::Corrupt, format!("entry {} does not decode: {}", i, e),
+                       Repair::DropLogEntry(i));
+            return None;
+        }
+    };
+    if ids.contains(&entry.id) {
+        return None;
+    }
+
+    match entry.entry_type {
+        EntryType::Create | EntryType::Update if Json::from_str(&entry.data).is_err() => {
+            report.add(name, Problem::Corrupt,
+                       format!("entry {} has invalid data for {}", i, entry.obj_id),
+                       Repair::DropLogEntry(i));
+        }
+        EntryType::Create => {
+            created.insert(entry.obj_id.clone());
+        }
+        _ if !created.contains(&entry.obj_id) => {
+            report.add(name, Problem::Missing,
+                       format!("entry {} refers to {}, which was never created", i, entry.obj_id),
+                       Repair::DropLogEntry(i));
+        }
+        EntryType::Delete => {
+            created.remove(&entry.obj_id);
+        }
+        EntryType::Update => (),
+    }
+    Some(entry.id)
+}
+
+fn decode<D: Decodable>(plain: &[u8]) -> Option<D> {
+    String::from_utf8(plain.to_vec()).ok().and_then(|p| json::decode(&p).ok())
+}
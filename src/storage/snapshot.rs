@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use chrono::{DateTime, Duration, UTC};
+use rustc_serialize::json;
+use uuid::Uuid;
+use clock::HybridClock;
+use crypto::CryptoManager;
+use domain::{Account, EntryType, EventData, EventLogEntry};
+use storage::{load_from, save_to};
+use storage::backend::{Backend, StorageError};
+use storage::log::{append_log, read_log, LogPosition};
+use tracking::calendar_properties;
+
+/// Prefix of the snapshot objects in the backend.
+pub const SNAPSHOT_PREFIX: &'static str = "snapshots/";
+
+/// Description of a stored snapshot. It is stored apart from the account itself, so listing
+/// the snapshots doesn't have to download all of them.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub created: DateTime<UTC>,
+    /// The account is the state after all log entries up to this position.
+    pub position: LogPosition,
+    /// Id of the device that made the snapshot.
+    pub device: String,
+}
+
+/// Encrypted copies of the whole Account, taken regularly, to get back to a known good state
+/// if a device synchronized garbage into the shared log.
+///
+/// Restoring doesn't rewrite the shared log. Instead the entries that turn the current state
+/// back into the snapshot are appended, so other devices pick up the restore like any other
+/// change.
+pub struct Snapshots {
+    device: String,
+    interval: Duration,
+    keep: usize,
+}
+
+impl Snapshots {
+    /// Creates snapshots for the given device once a day, keeping the last 30.
+    pub fn new(device: &str) -> Snapshots {
+        Snapshots {
+            device: device.to_string(),
+            interval: Duration::days(1),
+            keep: 30,
+        }
+    }
+
+    /// Sets how old the newest snapshot has to be before create_if_due makes a new one.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Sets how many snapshots create_if_due keeps, older ones are removed.
+    pub fn set_keep(&mut self, keep: usize) {
+        self.keep = keep;
+    }
+
+    /// Stores a snapshot of account, which has to be the state at position.
+    pub fn create<B: Backend>(&self, b: &mut B, c: &mut CryptoManager, account: &Account,
+                              position: LogPosition)
+                              -> Result<SnapshotInfo, StorageError> {
+        let info = SnapshotInfo {
+            id: Uuid::new_v4().to_string(),
+            created: UTC::now(),
+            position: position,
+            device: self.device.clone(),
+        };
+        // The info goes last, so listed snapshots are always complete.
+        try!(save_to(b, &account_name(&info.id), c, account));
+        try!(save_to(b, &info_name(&info.id), c, &info));
+        Ok(info)
+    }
+
+    /// Stores a snapshot if the newest one is older than the interval, then removes the
+    /// snapshots exceeding the number to keep.
+    pub fn create_if_due<B: Backend>(&self, b: &mut B, c: &mut CryptoManager, account: &Account,
+                                     position: LogPosition)
+                                     -> Result<Option<SnapshotInfo>, StorageError> {
+        let snapshots = try!(self.list(b, c));
+        let due = match snapshots.last() {
+            Some(s) => s.created + self.interval <= UTC::now(),
+            None => true,
+        };
+        if !due {
+            return Ok(None);
+        }
+
+        let info = try!(self.create(b, c, account, position));
+        let excess = (snapshots.len() + 1).saturating_sub(self.keep);
+        for old in snapshots.iter().take(excess) {
+            try!(self.delete(b, &old.id));
+        }
+        Ok(Some(info))
+    }
+
+    /// Returns all snapshots, oldest first.
+    pub fn list<B: Backend>(&self, b: &mut B, c: &CryptoManager)
+                            -> Result<Vec<SnapshotInfo>, StorageError> {
+        let mut snapshots = Vec::new();
+        for name in try!(b.list(SNAPSHOT_PREFIX)) {
+            if !name.ends_with("/info") {
+                continue;
+            }
+            match load_from::<_, SnapshotInfo>(b, &name, c) {
+                Ok(info) => snapshots.push(info),
+                // Deleted by another device while listing.
+                Err(StorageError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(snapshots)
+    }
+
+    /// Loads the account stored in the snapshot.
+    pub fn load<B: Backend>(&self, b: &mut B, c: &CryptoManager, id: &str)
+                            -> Result<Account, StorageError> {
+        load_from(b, &account_name(id), c)
+    }
+
+    /// Removes a snapshot.
+    pub fn delete<B: Backend>(&self, b: &mut B, id: &str) -> Result<(), StorageError> {
+        try!(b.delete(&info_name(id)));
+        match b.delete(&account_name(id)) {
+            Ok(()) | Err(StorageError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rolls back to the snapshot: appends the entries turning current into the state of the
+    /// snapshot to the shared log and returns them. They are stamped with clock after all
+    /// entries in the log, so they take effect on every device.
+    pub fn restore<B: Backend>(&self, b: &mut B, c: &mut CryptoManager, id: &str,
+                               current: &Account, clock: &mut HybridClock)
+                               -> Result<Vec<EventLogEntry>, StorageError> {
+        let target = try!(self.load(b, c, id));
+        let mut entries = rollback_entries(current, &target);
+        if !entries.is_empty() {
+            let log = try!(read_log(b, c));
+            for ts in log.iter().filter_map(|e| e.timestamp.as_ref()) {
+                // Entries too far ahead are outdated by the restore anyway.
+                let _ = clock.update(ts);
+            }
+            for entry in entries.iter_mut() {
+                entry.stamp(clock);
+            }
+            try!(append_log(b, c, &LogPosition::end_of(&log), &entries));
+        }
+        Ok(entries)
+    }
+}
+
+/// Returns the log entries that turn the state from into the state to, with the same data
+/// TrackedAccount logs: new calendars are created and changed ones updated, then the events
+/// are created, updated or deleted one by one and at last the calendars missing in to are
+/// deleted.
+pub fn rollback_entries(from: &Account, to: &Account) -> Vec<EventLogEntry> {
+    let mut entries = Vec::new();
+    for cal in to.items.iter() {
+        let data = json::encode(&calendar_properties(cal)).unwrap();
+        match from.items.iter().find(|c| c.id == cal.id) {
+            None => entries.push(EventLogEntry::new(EntryType::Create, &cal.id, &data)),
+            Some(old) if calendar_properties(old) != calendar_properties(cal) => {
+                entries.push(EventLogEntry::new(EntryType::Update, &cal.id, &data))
+            }
+            Some(_) => (),
+        }
+    }
+
+    let old_events = events(from);
+    let new_events = events(to);
+    for (id, _) in old_events.iter() {
+        if !new_events.contains_key(id) {
+            entries.push(EventLogEntry::new(EntryType::Delete, id, ""));
+        }
+    }
+    for (id, data) in new_events.iter() {
+        let entry_type = match old_events.get(id) {
+            None => EntryType::Create,
+            Some(old) if old != data => EntryType::Update,
+            Some(_) => continue,
+        };
+        entries.push(EventLogEntry::new(entry_type, id, &json::encode(data).unwrap()));
+    }
+
+    for cal in from.items.iter() {
+        if !to.items.iter().any(|c| c.id == cal.id) {
+            entries.push(EventLogEntry::new(EntryType::Delete, &cal.id, ""));
+        }
+    }
+    entries
+}
+
+/// All events of account by id, with their calendar.
+fn events(account: &Account) -> BTreeMap<String, EventData> {
+    let mut events = BTreeMap::new();
+    for cal in account.items.iter() {
+        for e in cal.get_events() {
+            events.insert(e.id.clone(),
+                          EventData {
+                              calendar: cal.id.clone(),
+                              event: e.clone(),
+                          });
+        }
+    }
+    events
+}
+
+fn info_name(id: &str) -> String {
+    format!("{}{}/info", SNAPSHOT_PREFIX, id)
+}
+
+fn account_name(id: &str) -> String {
+    format!("{}{}/account", SNAPSHOT_PREFIX, id)
+}
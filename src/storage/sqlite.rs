@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::path::Path;
+use chrono::{Date, Local};
+use rusqlite::{self, Connection, Row, ToSql, NO_PARAMS};
+use rustc_serialize::hex::ToHex;
+use sodiumoxide::crypto::auth;
+use crypto::CryptoManager;
+use domain::{Account, Calendar, Event, EventLogEntry};
+use storage::{open_object, seal_object};
+use storage::backend::StorageError;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS calendars (
+        id TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        id TEXT PRIMARY KEY,
+        calendar TEXT NOT NULL,
+        day TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_by_day ON events (calendar, day, position);
+    CREATE TABLE IF NOT EXISTS log (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        log TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS log_by_name ON log (log, seq);
+";
+
+/// The logs a device keeps locally, see the module documentation of storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalLog {
+    /// Changes that haven't been synchronized yet.
+    Local,
+    /// Changes to already synchronized objects made in this session.
+    Remote,
+}
+
+impl LocalLog {
+    fn name(&self) -> &'static str {
+        match *self {
+            LocalLog::Local => "local",
+            LocalLog::Remote => "remote",
+        }
+    }
+}
+
+/// The fields of a Calendar stored in its row, apart from its events.
+#[derive(RustcEncodable, RustcDecodable)]
+struct CalendarRow {
+    name: String,
+    desc: String,
+    sync: bool,
+}
+
+/// Local store of the decrypted state of a device in an SQLite database, as an alternative to
+/// saving the whole Account with storage::save. Calendars, events and log entries are rows of
+/// their own, so a change only writes what changed and several changes are written in a
+/// single transaction.
+///
+/// The content of every row is encrypted with the key of the CryptoManager. Only the ids and a
+/// keyed hash of the day of each event are stored in plain, so the events of a day are found
+/// with an index without revealing their dates.
+pub struct SqliteStore {
+    conn: Connection,
+    day_key: auth::Key,
+}
+
+impl SqliteStore {
+    /// Opens the database at path, creating it if it doesn't exist.
+    pub fn open(path: &Path, c: &CryptoManager) -> Result<SqliteStore, StorageError> {
+        let conn = try!(Connection::open(path).map_err(db));
+        SqliteStore::init(conn, c)
+    }
+
+    /// Opens a database that only lives in memory.
+    pub fn open_in_memory(c: &CryptoManager) -> Result<SqliteStore, StorageError> {
+        let conn = try!(Connection::open_in_memory().map_err(db));
+        SqliteStore::init(conn, c)
+    }
+
+    fn init(conn: Connection, c: &CryptoManager) -> Result<SqliteStore, StorageError> {
+        try!(conn.execute_batch(SCHEMA).map_err(db));
+        let auth::Tag(k) = auth::authenticate(b"cryptocontent local event days", &auth::Key(c.symkey.0));
+        Ok(SqliteStore {
+            conn: conn,
+            day_key: auth::Key(k),
+        })
+    }
+
+    /// Replaces everything stored with account.
+    pub fn save_account(&mut self, c: &mut CryptoManager, account: &Account) -> Result<(), StorageError> {
+        let tx = try!(self.conn.transaction().map_err(db));
+        try!(tx.execute("DELETE FROM events", NO_PARAMS).map_err(db));
+        try!(tx.execute("DELETE FROM calendars", NO_PARAMS).map_err(db));
+        for (i, cal) in account.items.iter().enumerate() {
+            try!(write_calendar(&tx, &self.day_key, c, cal, i as i64));
+        }
+        tx.commit().map_err(db)
+    }
+
+    /// Loads all calendars with their events.
+    pub fn load_account(&self, c: &CryptoManager) -> Result<Account, StorageError> {
+        let mut account = Account::new();
+        for id in try!(self.calendar_ids()) {
+            account.items.push(try!(self.load_calendar(c, &id)));
+        }
+        Ok(account)
+    }
+
+    /// Returns the ids of the stored calendars, in the order of the account.
+    pub fn calendar_ids(&self) -> Result<Vec<String>, StorageError> {
+        let mut stmt = try!(self.conn.prepare("SELECT id FROM calendars ORDER BY position").map_err(db));
+        let ids = try!(stmt.query_map(NO_PARAMS, |row| row.get(0)).map_err(db));
+        ids.collect::<Result<Vec<String>, _>>().map_err(db)
+    }
+
+    /// Stores cal with its events, replacing the stored version of it. Events that are no
+    /// longer in cal are removed. A new calendar is added after the others.
+    pub fn save_calendar(&mut self, c: &mut CryptoManager, cal: &Calendar) -> Result<(), StorageError> {
+        let tx = try!(self.conn.transaction().map_err(db));
+        let position = try!(tx.query_row("SELECT COALESCE((SELECT position FROM calendars WHERE id = ?1),
+                                                           (SELECT COUNT(*) FROM calendars))",
+                                         &[&cal.id as &ToSql],
+                                         |row| row.get(0))
+                              .map_err(db));
+        try!(tx.execute("DELETE FROM events WHERE calendar = ?1", &[&cal.id as &ToSql]).map_err(db));
+        try!(write_calendar(&tx, &self.day_key, c, cal, position));
+        tx.commit().map_err(db)
+    }
+
+    /// Loads a calendar with its events.
+    pub fn load_calendar(&self, c: &CryptoManager, id: &str) -> Result<Calendar, StorageError> {
+        let data: Vec<u8> = match self.conn.query_row("SELECT data FROM calendars WHERE id = ?1",
+                                                      &[&id as &ToSql],
+                                                      |row| row.get(0)) {
+            Ok(d) => d,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(StorageError::NotFound(format!("Calendar {}", id)))
+            }
+            Err(e) => return Err(db(e)),
+        };
+        let row: CalendarRow = try!(open_object(id, data, c));
+        let mut cal = Calendar::new(&row.name, &row.desc, row.sync);
+        cal.id = id.to_string();
+
+        let mut stmt = try!(self.conn
+                                .prepare("SELECT id, data FROM events WHERE calendar = ?1
+                                          ORDER BY day, position")
+                                .map_err(db));
+        let rows = try!(stmt.query_map(&[&id as &ToSql], id_and_data).map_err(db));
+        for row in rows {
+            let (event_id, data) = try!(row.map_err(db));
+            cal.add_event(try!(open_object(&event_id, data, c)));
+        }
+        Ok(cal)
+    }
+
+    /// Removes a calendar and its events.
+    pub fn delete_calendar(&mut self, id: &str) -> Result<(), StorageError> {
+        let tx = try!(self.conn.transaction().map_err(db));
+        try!(tx.execute("DELETE FROM events WHERE calendar = ?1", &[&id as &ToSql]).map_err(db));
+        if try!(tx.execute("DELETE FROM calendars WHERE id = ?1", &[&id as &ToSql]).map_err(db)) == 0 {
+            return Err(StorageError::NotFound(format!("Calendar {}", id)));
+        }
+        tx.commit().map_err(db)
+    }
+
+    /// Stores a single event of the calendar with the given id, replacing the stored version of
+    /// it.
+    pub fn save_event(&mut self, c: &mut CryptoManager, calendar: &str, event: &Event)
+                      -> Result<(), StorageError> {
+        let day = day_tag(&self.day_key, &event.start.date());
+        let data = try!(seal_object(&event.id, c, event));
+        let tx = try!(self.conn.transaction().map_err(db));
+        try!(tx.execute("DELETE FROM events WHERE id = ?1", &[&event.id as &ToSql]).map_err(db));
+        try!(tx.execute("INSERT INTO events (id, calendar, day, position, data)
+                         SELECT ?1, ?2, ?3, COALESCE(MAX(position) + 1, 0), ?4 FROM events
+                         WHERE calendar = ?2 AND day = ?3",
+                        &[&event.id as &ToSql, &calendar, &day, &data])
+                  .map_err(db));
+        tx.commit().map_err(db)
+    }
+
+    /// Removes a single event.
+    pub fn delete_event(&mut self, id: &str) -> Result<(), StorageError> {
+        match try!(self.conn.execute("DELETE FROM events WHERE id = ?1", &[&id as &ToSql]).map_err(db)) {
+            0 => Err(StorageError::NotFound(format!("Event {}", id))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the events of a calendar on the given day, like Calendar::get_events_by_day.
+    pub fn events_on(&self, c: &CryptoManager, calendar: &str, date: Date<Local>)
+                     -> Result<Vec<Event>, StorageError> {
+        let day = day_tag(&self.day_key, &date);
+        let mut stmt = try!(self.conn
+                                .prepare("SELECT id, data FROM events WHERE calendar = ?1 AND day = ?2
+                                          ORDER BY position")
+                                .map_err(db));
+        let rows = try!(stmt.query_map(&[&calendar as &ToSql, &day], id_and_data).map_err(db));
+        let mut events = Vec::new();
+        for row in rows {
+            let (id, data) = try!(row.map_err(db));
+            events.push(try!(open_object(&id, data, c)));
+        }
+        Ok(events)
+    }
+
+    /// Appends entries to one of the local logs.
+    pub fn append_log(&mut self, log: LocalLog, c: &mut CryptoManager, entries: &[EventLogEntry])
+                      -> Result<(), StorageError> {
+        let tx = try!(self.conn.transaction().map_err(db));
+        for entry in entries {
+            let data = try!(seal_object(&entry.id, c, entry));
+            try!(tx.execute("INSERT INTO log (log, data) VALUES (?1, ?2)",
+                            &[&log.name() as &ToSql, &data])
+                   .map_err(db));
+        }
+        tx.commit().map_err(db)
+    }
+
+    /// Reads the entries of one of the local logs, oldest first.
+    pub fn read_log(&self, log: LocalLog, c: &CryptoManager) -> Result<Vec<EventLogEntry>, StorageError> {
+        let mut stmt = try!(self.conn
+                                .prepare("SELECT data FROM log WHERE log = ?1 ORDER BY seq")
+                                .map_err(db));
+        let rows = try!(stmt.query_map(&[&log.name() as &ToSql], |row| row.get(0)).map_err(db));
+        let mut entries = Vec::new();
+        for row in rows {
+            let data: Vec<u8> = try!(row.map_err(db));
+            entries.push(try!(open_object(log.name(), data, c)));
+        }
+        Ok(entries)
+    }
+
+    /// Empties one of the local logs, e.g. after it has been synchronized.
+    pub fn clear_log(&mut self, log: LocalLog) -> Result<(), StorageError> {
+        try!(self.conn.execute("DELETE FROM log WHERE log = ?1", &[&log.name() as &ToSql]).map_err(db));
+        Ok(())
+    }
+}
+
+/// Writes the row of cal and rows for all of its events.
+fn write_calendar(conn: &Connection, day_key: &auth::Key, c: &mut CryptoManager, cal: &Calendar,
+                  position: i64)
+                  -> Result<(), StorageError> {
+    let row = CalendarRow {
+        name: cal.name.clone(),
+        desc: cal.desc.clone(),
+        sync: cal.sync,
+    };
+    let data = try!(seal_object(&cal.id, c, &row));
+    try!(conn.execute("INSERT OR REPLACE INTO calendars (id, position, data) VALUES (?1, ?2, ?3)",
+                      &[&cal.id as &ToSql, &position, &data])
+             .map_err(db));
+
+    // Within a day the events keep their order.
+    let mut positions = HashMap::new();
+    for event in cal.get_events() {
+        let day = day_tag(day_key, &event.start.date());
+        let position = positions.entry(day.clone()).or_insert(0i64);
+        let data = try!(seal_object(&event.id, c, event));
+        try!(conn.execute("INSERT OR REPLACE INTO events (id, calendar, day, position, data)
+                           VALUES (?1, ?2, ?3, ?4, ?5)",
+                          &[&event.id as &ToSql, &cal.id, &day, &*position, &data])
+                 .map_err(db));
+        *position += 1;
+    }
+    Ok(())
+}
+
+fn id_and_data(row: &Row) -> rusqlite::Result<(String, Vec<u8>)> {
+    Ok((try!(row.get(0)), try!(row.get(1))))
+}
+
+/// The keyed hash of a day that is stored instead of it. Only the calendar day is hashed, not
+/// the UTC offset, so the tags stay valid when the timezone of the device changes.
+fn day_tag(key: &auth::Key, date: &Date<Local>) -> String {
+    let day = date.naive_local().format("%Y-%m-%d").to_string();
+    let auth::Tag(t) = auth::authenticate(day.as_bytes(), key);
+    t.to_hex()
+}
+
+fn db(e: rusqlite::Error) -> StorageError {
+    StorageError::Unavailable(format!("SQLite: {}", e))
+}
@@ -0,0 +1,672 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{self, Json, JsonEvent, StackElement};
+use sodiumoxide::crypto::secretbox;
+use crypto::CryptoManager;
+use domain::{Account, Calendar, Event, EventLogEntry};
+use storage::backend::StorageError;
+
+/// Marks the streaming format, so it isn't mistaken for data written by save.
+const MAGIC: &'static [u8; 4] = b"CCS1";
+
+/// Size of the plaintext of a segment. This is about what reading and writing keep in memory.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Set in the length of the last segment.
+const FINAL: u32 = 1 << 31;
+
+/// The nonce of a segment is the random nonce of the stream combined with the number of the
+/// segment and whether it is the last one. Segments that are reordered, dropped or taken from
+/// another stream don't authenticate, and neither does a stream that was cut off.
+fn segment_nonce(base: &secretbox::Nonce, counter: u64, last: bool) -> secretbox::Nonce {
+    let mut n = base.0;
+    for i in 0..8 {
+        n[secretbox::NONCEBYTES - 1 - i] ^= (counter >> (8 * i)) as u8;
+    }
+    if last {
+        n[secretbox::NONCEBYTES - 9] ^= 1;
+    }
+    secretbox::Nonce(n)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Writer encrypting everything written to it in segments. finish has to be called at the end,
+/// otherwise the stream is incomplete and won't decrypt.
+pub struct EncryptWriter<W: Write> {
+    inner: Option<W>,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Starts a stream encrypted with the key of c.
+    pub fn new(mut inner: W, c: &CryptoManager) -> io::Result<EncryptWriter<W>> {
+        let nonce = secretbox::gen_nonce();
+        try!(inner.write_all(MAGIC));
+        try!(inner.write_all(&nonce.0));
+        Ok(EncryptWriter {
+            inner: Some(inner),
+            key: c.symkey.clone(),
+            nonce: nonce,
+            counter: 0,
+            buf: Vec::with_capacity(SEGMENT_SIZE),
+        })
+    }
+
+    /// Writes the last segment and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.seal(true));
+        let mut inner = self.inner.take().unwrap();
+        try!(inner.flush());
+        Ok(inner)
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let nonce = segment_nonce(&self.nonce, self.counter, last);
+        let ct = secretbox::seal(&self.buf, &nonce, &self.key);
+        let mut len = ct.len() as u32;
+        if last {
+            len |= FINAL;
+        }
+        let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+        let inner = self.inner.as_mut().unwrap();
+        try!(inner.write_all(&header));
+        try!(inner.write_all(&ct));
+        self.counter += 1;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = ::std::cmp::min(data.len(), SEGMENT_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == SEGMENT_SIZE {
+            try!(self.seal(false));
+        }
+        Ok(n)
+    }
+
+    /// Only flushes what is already sealed, segments are always full except the last one.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+/// Reader decrypting a stream written by EncryptWriter. Fails with InvalidData if the stream
+/// was tampered with or cut off.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: Option<secretbox::Nonce>,
+    counter: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Decrypts inner with the key of c.
+    pub fn new(inner: R, c: &CryptoManager) -> DecryptReader<R> {
+        DecryptReader {
+            inner: inner,
+            key: c.symkey.clone(),
+            nonce: None,
+            counter: 0,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let mut header = [0; 4 + secretbox::NONCEBYTES];
+        try!(read_exact(&mut self.inner, &mut header));
+        if &header[..4] != MAGIC {
+            return Err(invalid("Not an encrypted stream"));
+        }
+        self.nonce = secretbox::Nonce::from_slice(&header[4..]);
+        Ok(())
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        if self.nonce.is_none() {
+            try!(self.read_header());
+        }
+
+        let mut header = [0; 4];
+        try!(read_exact(&mut self.inner, &mut header));
+        let len = header.iter().fold(0u32, |l, &b| (l << 8) | b as u32);
+        let last = len & FINAL != 0;
+        let len = (len & !FINAL) as usize;
+        if len > SEGMENT_SIZE + secretbox::MACBYTES {
+            return Err(invalid("Segment too large"));
+        }
+
+        let mut ct = vec![0; len];
+        try!(read_exact(&mut self.inner, &mut ct));
+        let nonce = segment_nonce(self.nonce.as_ref().unwrap(), self.counter, last);
+        self.buf = match secretbox::open(&ct, &nonce, &self.key) {
+            Ok(p) => p,
+            Err(_) => return Err(invalid("Segment does not decrypt")),
+        };
+        self.pos = 0;
+        self.counter += 1;
+
+        if last {
+            let mut rest = [0; 1];
+            if try!(self.inner.read(&mut rest)) != 0 {
+                return Err(invalid("Data after the end of the stream"));
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            try!(self.next_segment());
+        }
+        let n = ::std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Like Read::read_exact, but a stream ending early is InvalidData, as it was cut off.
+fn read_exact<R: Read>(r: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match r.read(buf) {
+            Ok(0) => return Err(invalid("Stream is incomplete")),
+            Ok(n) => {
+                let tmp = buf;
+                buf = &mut tmp[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Lets the JSON encoder write directly into a Write.
+struct FmtWriter<'a, W: Write + 'a> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<'a, W: Write> fmt::Write for FmtWriter<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.inner.write_all(s.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.error = Some(e);
+                Err(fmt::Error)
+            }
+        }
+    }
+}
+
+/// Iterates over the characters of a UTF-8 stream. Errors end the iteration and are kept.
+struct Chars<R: Read> {
+    inner: R,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Chars<R> {
+    fn byte(&mut self) -> Option<u8> {
+        let mut b = [0; 1];
+        loop {
+            match self.inner.read(&mut b) {
+                Ok(0) => return None,
+                Ok(_) => return Some(b[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn check(self) -> Result<R, StorageError> {
+        match self.error {
+            Some(e) => Err(stream_error(e)),
+            None => Ok(self.inner),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        let first = match self.byte() {
+            Some(b) => b,
+            None => return None,
+        };
+        let width = match first {
+            0x00...0x7f => return Some(first as char),
+            0xc0...0xdf => 2,
+            0xe0...0xef => 3,
+            0xf0...0xf7 => 4,
+            _ => 0,
+        };
+
+        let mut bytes = [first, 0, 0, 0];
+        for i in 1..width {
+            match self.byte() {
+                Some(b) => bytes[i] = b,
+                None => break,
+            }
+        }
+        match ::std::str::from_utf8(&bytes[..width]) {
+            Ok(s) if width > 0 => s.chars().next(),
+            _ => {
+                if self.error.is_none() {
+                    self.error = Some(invalid("Invalid UTF-8"));
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Encrypted data that doesn't authenticate is Corrupt, everything else an Io error.
+fn stream_error(e: io::Error) -> StorageError {
+    if e.kind() == io::ErrorKind::InvalidData {
+        StorageError::Corrupt(format!("{}", e))
+    } else {
+        StorageError::Io(e)
+    }
+}
+
+/// Like save, but encodes and encrypts s piece by piece while writing, without holding the
+/// whole JSON or ciphertext in memory.
+pub fn save_stream<W: Write, S: Encodable>(w: W, c: &CryptoManager, s: &S)
+                                           -> Result<W, StorageError> {
+    let mut enc = try!(EncryptWriter::new(w, c));
+    {
+        let mut out = FmtWriter {
+            inner: &mut enc,
+            error: None,
+        };
+        let result = {
+            let mut encoder = json::Encoder::new(&mut out);
+            s.encode(&mut encoder)
+        };
+        if let Some(e) = out.error {
+            return Err(StorageError::Io(e));
+        }
+        if let Err(e) = result {
+            return Err(StorageError::Protocol(format!("Can't encode: {}", e)));
+        }
+    }
+    enc.finish().map_err(StorageError::Io)
+}
+
+/// Reads what save_stream wrote. Decrypts, parses and decodes while reading, so only the
+/// decoded value and the JSON of a single calendar, event or log entry are in memory.
+pub fn load_stream<R: Read, D: StreamDecodable>(r: R, c: &CryptoManager) -> Result<D, StorageError> {
+    let mut chars = Chars {
+        inner: DecryptReader::new(r, c),
+        error: None,
+    };
+    let result = {
+        let mut p = JsonStream::new(&mut chars);
+        D::decode_stream(&mut p).and_then(|d| p.end().map(|_| d))
+    };
+    // A stream that doesn't decrypt ends early, which is the error to report.
+    try!(chars.check());
+    result
+}
+
+/// Values load_stream decodes while parsing. Their lists are decoded an element at a time.
+pub trait StreamDecodable: Sized {
+    fn decode_stream<T: Iterator<Item = char>>(p: &mut JsonStream<T>) -> Result<Self, StorageError>;
+}
+
+/// Lists of anything decodable, e.g. a log.
+impl<D: Decodable> StreamDecodable for Vec<D> {
+    fn decode_stream<T: Iterator<Item = char>>(p: &mut JsonStream<T>) -> Result<Vec<D>, StorageError> {
+        let mut list = Vec::new();
+        try!(p.array(|p| {
+            list.push(try!(p.value()));
+            Ok(())
+        }));
+        Ok(list)
+    }
+}
+
+impl StreamDecodable for Account {
+    fn decode_stream<T: Iterator<Item = char>>(p: &mut JsonStream<T>) -> Result<Account, StorageError> {
+        let mut account = None;
+        try!(p.object(|p, key| {
+            match key {
+                "items" => {
+                    let mut items = Vec::new();
+                    try!(p.array(|p| {
+                        items.push(try!(Calendar::decode_stream(p)));
+                        Ok(())
+                    }));
+                    account = Some(Account { items: items });
+                }
+                _ => try!(p.skip()),
+            }
+            Ok(())
+        }));
+        required(account, "items")
+    }
+}
+
+/// The days of the calendar are derived from its events again, like add_event does.
+impl StreamDecodable for Calendar {
+    fn decode_stream<T: Iterator<Item = char>>(p: &mut JsonStream<T>) -> Result<Calendar, StorageError> {
+        let mut cal = Calendar::new("", "", false);
+        let (mut id, mut name, mut desc, mut sync, mut days) = (None, None, None, None, None);
+        try!(p.object(|p, key| {
+            match key {
+                "id" => id = Some(try!(p.value())),
+                "name" => name = Some(try!(p.value())),
+                "desc" => desc = Some(try!(p.value())),
+                "sync" => sync = Some(try!(p.value())),
+                "days" => {
+                    try!(p.object(|p, _| {
+                        p.array(|p| {
+                            cal.add_event(try!(p.value::<Event>()));
+                            Ok(())
+                        })
+                    }));
+                    days = Some(());
+                }
+                _ => try!(p.skip()),
+            }
+            Ok(())
+        }));
+        cal.id = try!(required(id, "id"));
+        cal.name = try!(required(name, "name"));
+        cal.desc = try!(required(desc, "desc"));
+        cal.sync = try!(required(sync, "sync"));
+        try!(required(days, "days"));
+        Ok(cal)
+    }
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T, StorageError> {
+    value.ok_or(StorageError::Corrupt(format!("Missing field {}", field)))
+}
+
+/// The JSON of a stream as it is parsed, for StreamDecodable.
+pub struct JsonStream<T: Iterator<Item = char>> {
+    parser: json::Parser<T>,
+    /// The first event of the next value, once it had to be read for the key of the value.
+    peeked: Option<JsonEvent>,
+}
+
+impl<T: Iterator<Item = char>> JsonStream<T> {
+    fn new(chars: T) -> JsonStream<T> {
+        JsonStream {
+            parser: json::Parser::new(chars),
+            peeked: None,
+        }
+    }
+
+    fn next(&mut self) -> Result<JsonEvent, StorageError> {
+        match self.peeked.take().or_else(|| self.parser.next()) {
+            Some(JsonEvent::Error(e)) => Err(StorageError::Corrupt(format!("Does not parse: {}", e))),
+            Some(event) => Ok(event),
+            None => Err(StorageError::Corrupt("Stream is incomplete".to_string())),
+        }
+    }
+
+    /// Decodes the next value, holding only its JSON in memory.
+    pub fn value<D: Decodable>(&mut self) -> Result<D, StorageError> {
+        let first = try!(self.next());
+        let tree = try!(self.tree(first));
+        Decodable::decode(&mut json::Decoder::new(tree))
+            .map_err(|e| StorageError::Corrupt(format!("Does not decode: {}", e)))
+    }
+
+    /// Skips the next value.
+    pub fn skip(&mut self) -> Result<(), StorageError> {
+        let first = try!(self.next());
+        self.tree(first).map(|_| ())
+    }
+
+    /// Reads an object, calling member with the key of every member. member has to read its
+    /// value.
+    pub fn object<F>(&mut self, mut member: F) -> Result<(), StorageError>
+        where F: FnMut(&mut JsonStream<T>, &str) -> Result<(), StorageError>
+    {
+        match try!(self.next()) {
+            JsonEvent::ObjectStart => (),
+            _ => return Err(StorageError::Corrupt("Expected an object".to_string())),
+        }
+        loop {
+            let event = try!(self.next());
+            if let JsonEvent::ObjectEnd = event {
+                return Ok(());
+            }
+            let key = try!(self.key());
+            self.peeked = Some(event);
+            try!(member(self, &key));
+        }
+    }
+
+    /// Reads an array, calling element for every element. element has to read it.
+    pub fn array<F>(&mut self, mut element: F) -> Result<(), StorageError>
+        where F: FnMut(&mut JsonStream<T>) -> Result<(), StorageError>
+    {
+        match try!(self.next()) {
+            JsonEvent::ArrayStart => (),
+            _ => return Err(StorageError::Corrupt("Expected a list".to_string())),
+        }
+        loop {
+            let event = try!(self.next());
+            if let JsonEvent::ArrayEnd = event {
+                return Ok(());
+            }
+            self.peeked = Some(event);
+            try!(element(self));
+        }
+    }
+
+    /// Key of the member the last event belongs to.
+    fn key(&self) -> Result<String, StorageError> {
+        match self.parser.stack().top() {
+            Some(StackElement::Key(key)) => Ok(key.to_string()),
+            _ => Err(StorageError::Corrupt("Expected a key".to_string())),
+        }
+    }
+
+    /// Builds the value starting with first.
+    fn tree(&mut self, first: JsonEvent) -> Result<Json, StorageError> {
+        Ok(match first {
+            JsonEvent::ObjectStart => {
+                let mut object = json::Object::new();
+                loop {
+                    let event = try!(self.next());
+                    if let JsonEvent::ObjectEnd = event {
+                        break;
+                    }
+                    let key = try!(self.key());
+                    object.insert(key, try!(self.tree(event)));
+                }
+                Json::Object(object)
+            }
+            JsonEvent::ArrayStart => {
+                let mut array = Vec::new();
+                loop {
+                    match try!(self.next()) {
+                        JsonEvent::ArrayEnd => break,
+                        event => array.push(try!(self.tree(event))),
+                    }
+                }
+                Json::Array(array)
+            }
+            JsonEvent::BooleanValue(b) => Json::Boolean(b),
+            JsonEvent::I64Value(n) => Json::I64(n),
+            JsonEvent::U64Value(n) => Json::U64(n),
+            JsonEvent::F64Value(n) => Json::F64(n),
+            JsonEvent::StringValue(s) => Json::String(s),
+            JsonEvent::NullValue => Json::Null,
+            JsonEvent::ObjectEnd | JsonEvent::ArrayEnd | JsonEvent::Error(_) => {
+                return Err(StorageError::Corrupt("Unexpected end of a value".to_string()))
+            }
+        })
+    }
+
+    /// Checks that nothing follows the value.
+    fn end(&mut self) -> Result<(), StorageError> {
+        match self.parser.next() {
+            None => Ok(()),
+            Some(JsonEvent::Error(e)) => Err(StorageError::Corrupt(format!("Does not parse: {}", e))),
+            Some(_) => Err(StorageError::Corrupt("Data after the value".to_string())),
+        }
+    }
+}
+
+/// Writes log entries as an encrypted JSON list, one at a time, so the entries can come from an
+/// iterator instead of being collected first. The result can be read by load_stream as well.
+pub fn write_entries<'a, W, I>(w: W, c: &CryptoManager, entries: I) -> Result<W, StorageError>
+    where W: Write,
+          I: IntoIterator<Item = &'a EventLogEntry>
+{
+    let mut enc = try!(EncryptWriter::new(w, c));
+    try!(enc.write_all(b"["));
+    for (i, entry) in entries.into_iter().enumerate() {
+        if i > 0 {
+            try!(enc.write_all(b","));
+        }
+        let e = try!(json::encode(entry).map_err(|e| StorageError::Protocol(format!("{}", e))));
+        try!(enc.write_all(e.as_bytes()));
+    }
+    try!(enc.write_all(b"]"));
+    enc.finish().map_err(StorageError::Io)
+}
+
+/// Iterator over the entries of a log written by write_entries or save_stream. Only a single
+/// entry is held in memory at a time.
+pub struct EntryReader<R: Read> {
+    chars: Chars<DecryptReader<R>>,
+    started: bool,
+    done: bool,
+}
+
+/// Reads log entries one by one from an encrypted JSON list.
+pub fn read_entries<R: Read>(r: R, c: &CryptoManager) -> EntryReader<R> {
+    EntryReader {
+        chars: Chars {
+            inner: DecryptReader::new(r, c),
+            error: None,
+        },
+        started: false,
+        done: false,
+    }
+}
+
+impl<R: Read> EntryReader<R> {
+    fn next_char(&mut self) -> Result<Option<char>, StorageError> {
+        let c = self.chars.next();
+        if let Some(e) = self.chars.error.take() {
+            return Err(stream_error(e));
+        }
+        Ok(c)
+    }
+
+    fn next_token(&mut self) -> Result<Option<char>, StorageError> {
+        loop {
+            match try!(self.next_char()) {
+                Some(c) if c.is_whitespace() => (),
+                c => return Ok(c),
+            }
+        }
+    }
+
+    /// Collects the text of the next element of the list, or None at its end.
+    fn next_element(&mut self) -> Result<Option<String>, StorageError> {
+        if !self.started {
+            self.started = true;
+            if try!(self.next_token()) != Some('[') {
+                return Err(StorageError::Corrupt("Log is not a list".to_string()));
+            }
+        }
+
+        let mut element = String::new();
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        loop {
+            let c = match try!(self.next_char()) {
+                Some(c) => c,
+                None => return Err(StorageError::Corrupt("Log is incomplete".to_string())),
+            };
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+            } else {
+                match c {
+                    '"' => in_string = true,
+                    '{' | '[' => depth += 1,
+                    '}' | ']' if depth > 0 => depth -= 1,
+                    ']' => {
+                        if try!(self.next_token()).is_some() {
+                            return Err(StorageError::Corrupt("Data after the log".to_string()));
+                        }
+                        self.done = true;
+                        let empty = element.trim().is_empty();
+                        return Ok(if empty { None } else { Some(element) });
+                    }
+                    ',' if depth == 0 => return Ok(Some(element)),
+                    _ => (),
+                }
+            }
+            element.push(c);
+        }
+    }
+}
+
+impl<R: Read> Iterator for EntryReader<R> {
+    type Item = Result<EventLogEntry, StorageError>;
+
+    fn next(&mut self) -> Option<Result<EventLogEntry, StorageError>> {
+        if self.done {
+            return None;
+        }
+        let element = match self.next_element() {
+            Ok(Some(e)) => e,
+            Ok(None) => return None,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let entry = Json::from_str(&element)
+                        .ok()
+                        .and_then(|j| Decodable::decode(&mut json::Decoder::new(j)).ok());
+        Some(entry.ok_or(StorageError::Corrupt(format!("Invalid log entry: {}", element))))
+    }
+}
@@ -0,0 +1,723 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use chrono::{DateTime, Duration, UTC};
+use futures::Future;
+use futures::future::{self, Loop};
+use clock::{ClockSkew, Timestamp};
+use conflict::{resolve_conflicts, Conflict, ConflictPolicy};
+use crypto::CryptoManager;
+use domain::{EntryType, EventLogEntry};
+use replay::{Replay, ReplayError};
+use storage::{open_object, seal_object};
+use storage::asynchronous::{AsyncBackend, Immediate, StorageFuture};
+use storage::backend::{Backend, StorageError, Version};
+use storage::backup::{join_log, segment_name, BackupIndex, BackupSegment, BACKUP_INDEX};
+use storage::cursor::{cursor_name, SeenLog, SyncCursor};
+use storage::lock::{LockError, LockInfo, RemoteLock, RELEASED};
+use storage::log::{decode_log, encode_log, log_segment_name, segment_numbers, LogPosition, LOG_PREFIX};
+use tracking::{compact_local_log, merge_remote_log, TrackedAccount};
+
+/// What a synchronization changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncSummary {
+    /// Number of entries of this device added to the shared log.
+    pub sent: usize,
+    /// Number of entries of other devices applied to the account.
+    pub received: usize,
+    /// Ids of the objects changed by other devices, in the order of their first change.
+    pub changed: Vec<String>,
+    /// Timestamps of other devices that were too far ahead to move the clock.
+    pub skewed: Vec<ClockSkew>,
+    /// Entries that couldn't be applied, with the reason.
+    pub rejected: Vec<(EventLogEntry, ReplayError)>,
+    /// Conflicts the policy left to the user, see ConflictQueue.
+    pub unresolved: Vec<Conflict>,
+    /// Stale lock of another device that was broken to synchronize.
+    pub broken_lock: Option<LockInfo>,
+    /// Why the lock couldn't be released once the changes were sent. The synchronization
+    /// still succeeded, the lock is left to expire.
+    pub release_error: Option<String>,
+}
+
+/// Errors when synchronizing. The logs of the account are kept, so synchronizing can simply
+/// be tried again.
+#[derive(Debug)]
+pub enum SyncError {
+    /// The lock couldn't be taken or was lost.
+    Lock(LockError),
+    /// Error of the backend.
+    Storage(StorageError),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncError::Lock(ref e) => write!(f, "{}", e),
+            SyncError::Storage(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SyncError {
+    fn description(&self) -> &str {
+        match *self {
+            SyncError::Lock(ref e) => e.description(),
+            SyncError::Storage(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<LockError> for SyncError {
+    fn from(e: LockError) -> SyncError {
+        SyncError::Lock(e)
+    }
+}
+
+impl From<StorageError> for SyncError {
+    fn from(e: StorageError) -> SyncError {
+        SyncError::Storage(e)
+    }
+}
+
+/// Result of Synchronizer::sync_async.
+pub type SyncFuture<'a> = Box<Future<Item = SyncSummary, Error = SyncError> + 'a>;
+
+/// Synchronizes a TrackedAccount with the shared log in a backend, following the steps in the
+/// module documentation of storage.
+///
+/// The Synchronizer keeps a SyncCursor with the position in the shared log it reached, so
+/// only entries of other devices after it are applied. The cursor is published in the backend
+/// with every synchronization and should be saved locally to continue with it after a restart.
+///
+/// The first synchronization reads the whole log, the following ones only the segments added
+/// since, starting with the segment in the cursor. This holds for a restored cursor as well.
+pub struct Synchronizer {
+    lock: RemoteLock,
+    cursor: SyncCursor,
+    policy: ConflictPolicy,
+    /// The last entries of the objects, as far as they were read or sent by this Synchronizer.
+    /// Missing bases of conflicting changes are read from the log.
+    entries: HashMap<String, EventLogEntry>,
+}
+
+impl Synchronizer {
+    /// Creates the Synchronizer of a device that hasn't synchronized yet.
+    pub fn new(device: &str) -> Synchronizer {
+        Synchronizer::from_cursor(SyncCursor::new(device))
+    }
+
+    /// Continues where the device of cursor left off.
+    pub fn from_cursor(cursor: SyncCursor) -> Synchronizer {
+        Synchronizer {
+            lock: RemoteLock::new(&cursor.device, Duration::minutes(5)),
+            cursor: cursor,
+            policy: ConflictPolicy::LastWriterWins,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Sets how conflicting changes of other devices are resolved. The default is
+    /// last-writer-wins.
+    pub fn set_policy(&mut self, policy: ConflictPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn cursor(&self) -> &SyncCursor {
+        &self.cursor
+    }
+
+    /// The lock taken while synchronizing, e.g. to change its grace period.
+    pub fn lock(&mut self) -> &mut RemoteLock {
+        &mut self.lock
+    }
+
+    /// Position in the shared log after the last synchronization.
+    pub fn position(&self) -> &LogPosition {
+        &self.cursor.position
+    }
+
+    pub fn last_sync(&self) -> Option<DateTime<UTC>> {
+        self.cursor.synced
+    }
+
+    /// Continues from a position reached at last_sync, e.g. one saved by a previous run.
+    pub fn set_position(&mut self, position: LogPosition, last_sync: Option<DateTime<UTC>>) {
+        self.cursor.position = position;
+        self.cursor.synced = last_sync;
+        self.cursor.segment = 0;
+        self.cursor.seen = None;
+    }
+
+    /// Sends the changes of account to the shared log in b and applies those of the other
+    /// devices to it. If anything fails the logs of account are put back.
+    pub fn sync<B: Backend>(&mut self, account: &mut TrackedAccount, b: &mut B, c: &mut CryptoManager)
+                                   -> Result<SyncSummary, SyncError> {
+        self.sync_async(account, &Immediate::new(b), c).wait()
+    }
+
+    /// Asynchronous sync. The Synchronizer and account stay borrowed until the future
+    /// completes.
+    pub fn sync_async<'a, A: AsyncBackend>(&'a mut self, account: &'a mut TrackedAccount, b: &'a A,
+                                           c: &'a mut CryptoManager)
+                                           -> SyncFuture<'a> {
+        let logs = account.take_logs();
+        // Steps 1 and 2
+        let local = compact_local_log(&logs.0);
+        let remote = merge_remote_log(&logs.1);
+        let run = Run {
+            sync: self,
+            account: account,
+            b: b,
+            c: c,
+            logs: logs,
+        };
+
+        Box::new(run.acquire().then(|acquired| -> SyncFuture<'a> {
+            let run = match acquired {
+                Ok((run, ())) => run,
+                Err((run, e)) => return Box::new(future::err(run.abort(e))),
+            };
+            Box::new(run.exchange(local, remote).then(|exchanged| {
+                let (run, result) = match exchanged {
+                    Ok((run, summary)) => (run, Ok(summary)),
+                    Err((run, e)) => (run, Err(e)),
+                };
+                run.release().then(|released| {
+                    match (result, released) {
+                        (Ok(summary), Ok(_)) => Ok(summary),
+                        (Ok(summary), Err((_, SyncError::Lock(LockError::Lost(_))))) => Ok(summary),
+                        // The changes are in the shared log, failing would make them be sent
+                        // again.
+                        (Ok(mut summary), Err((_, e))) => {
+                            summary.release_error = Some(e.to_string());
+                            Ok(summary)
+                        }
+                        (Err(e), Ok((run, _))) | (Err(e), Err((run, _))) => Err(run.abort(e)),
+                    }
+                })
+            }))
+        }))
+    }
+
+    /// Steps 5 to 7, once the entries of other devices since the last synchronization are
+    /// read: the entries to send and to apply.
+    fn merge(&mut self, account: &mut TrackedAccount, incoming: Vec<EventLogEntry>,
+             mut local: Vec<EventLogEntry>, remote: Vec<EventLogEntry>)
+             -> Exchange {
+        let seen = self.cursor.seen.take().unwrap();
+
+        let mut skewed = Vec::new();
+        if let Some(ref ts) = self.cursor.clock {
+            // The clock may have been reset since.
+            let _ = account.clock().update(ts);
+        }
+        for ts in incoming.iter().filter_map(|e| e.timestamp.as_ref()) {
+            if let Err(skew) = account.clock().update(ts) {
+                skewed.push(skew);
+            }
+        }
+
+        // Step 6, after resolving the conflicts with changes of other devices.
+        let base = remote.iter().filter_map(|e| self.last_entry(&seen, &e.obj_id)).cloned().collect::<Vec<_>>();
+        let (remote, unresolved) = resolve_conflicts(&self.policy, remote, &incoming, &base,
+                                                     account.clock());
+        let merged = merge_shared_log(&incoming, &remote);
+
+        // Step 7, the local entries get their timestamps now, after everything seen.
+        for entry in local.iter_mut() {
+            entry.stamp(account.clock());
+        }
+        let mut sent = remote;
+        sent.extend(local.iter().cloned());
+
+        Exchange {
+            incoming: incoming,
+            merged: merged,
+            local: local,
+            sent: sent,
+            seen: seen,
+            skewed: skewed,
+            unresolved: unresolved,
+        }
+    }
+
+    /// Step 10, once the entries are sent as the segment next: moves the cursor after them and
+    /// applies them.
+    fn finish(&mut self, account: &mut TrackedAccount, exchange: Exchange, start: usize, next: usize)
+              -> SyncSummary {
+        let Exchange { incoming, merged, local, sent, mut seen, skewed, unresolved } = exchange;
+        let previous = self.cursor.position.clone();
+        self.cursor.position.index = start + incoming.len() + sent.len();
+        if let Some(entry) = incoming.iter().chain(sent.iter()).last() {
+            self.cursor.position.last_entry = Some(entry.id.clone());
+        }
+        self.cursor.segment = if sent.is_empty() { next } else { next + 1 };
+        self.cursor.synced = Some(UTC::now());
+        self.cursor.clock = Some(account.clock().now());
+
+        // The entries take effect in the merged order. Changes older than the last one of
+        // their object that already took effect are outdated, so every device ends up with the
+        // latest change, whatever order it saw them in.
+        let mut replay = Replay::from_checkpoint(account.account().clone(), previous);
+        let mut rejected = Vec::new();
+        for entry in merged.iter().chain(local.iter()) {
+            if !newer(&mut seen.latest, entry) {
+                continue;
+            }
+            if let Err(e) = replay.apply(entry) {
+                rejected.push((entry.clone(), e));
+            }
+        }
+        account.reset(replay.into_account());
+        for entry in incoming.iter().chain(sent.iter()) {
+            seen.last.insert(entry.obj_id.clone(), entry.id.clone());
+            self.entries.insert(entry.obj_id.clone(), entry.clone());
+        }
+        self.cursor.seen = Some(seen);
+
+        let mut changed = Vec::new();
+        for entry in incoming.iter() {
+            if !changed.contains(&entry.obj_id) {
+                changed.push(entry.obj_id.clone());
+            }
+        }
+        SyncSummary {
+            sent: sent.len(),
+            received: incoming.len(),
+            changed: changed,
+            skewed: skewed,
+            rejected: rejected,
+            unresolved: unresolved,
+            broken_lock: self.lock.broken().cloned(),
+            release_error: None,
+        }
+    }
+
+    /// Finds the cursor in the whole log, including the backup log, and returns the entries
+    /// after it and the number of entries before them. What the log up to the cursor says
+    /// about the objects is kept for the following synchronizations.
+    fn start_from(&mut self, log: Vec<EventLogEntry>) -> Result<(Vec<EventLogEntry>, usize), StorageError> {
+        let start = match self.cursor.position.last_entry {
+            Some(ref id) => {
+                match log.iter().position(|e| &e.id == id) {
+                    Some(i) => i + 1,
+                    None => return Err(StorageError::NotFound(format!("Log entry {}", id))),
+                }
+            }
+            None => 0,
+        };
+        let mut seen = SeenLog::default();
+        for entry in log[..start].iter() {
+            newer(&mut seen.latest, entry);
+            seen.last.insert(entry.obj_id.clone(), entry.id.clone());
+            self.entries.insert(entry.obj_id.clone(), entry.clone());
+        }
+        self.cursor.seen = Some(seen);
+        Ok((log[start..].to_vec(), start))
+    }
+
+    /// The last entry of the object up to the cursor, if it is in memory.
+    fn last_entry(&self, seen: &SeenLog, obj_id: &str) -> Option<&EventLogEntry> {
+        match (seen.last.get(obj_id), self.entries.get(obj_id)) {
+            (Some(id), Some(entry)) if &entry.id == id => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Ids of the entries remote may conflict with on top of incoming, that aren't in memory.
+    fn missing_bases(&self, incoming: &[EventLogEntry], remote: &[EventLogEntry]) -> Vec<String> {
+        let seen = match self.cursor.seen {
+            Some(ref seen) => seen,
+            None => return Vec::new(),
+        };
+        remote.iter()
+              .filter(|e| e.entry_type == EntryType::Update)
+              .filter(|e| incoming.iter().any(|i| i.obj_id == e.obj_id))
+              .filter(|e| self.last_entry(seen, &e.obj_id).is_none())
+              .filter_map(|e| seen.last.get(&e.obj_id))
+              .cloned()
+              .collect()
+    }
+}
+
+/// What a synchronization sends and applies, see Synchronizer::merge.
+struct Exchange {
+    /// Entries of other devices since the last synchronization.
+    incoming: Vec<EventLogEntry>,
+    /// The incoming entries and those of the remote log, in the order they take effect.
+    merged: Vec<EventLogEntry>,
+    local: Vec<EventLogEntry>,
+    /// The remote and local entries, added to the shared log.
+    sent: Vec<EventLogEntry>,
+    seen: SeenLog,
+    skewed: Vec<ClockSkew>,
+    unresolved: Vec<Conflict>,
+}
+
+/// A synchronization in progress. It is handed from step to step, also when a step fails, so
+/// the lock can be released and the logs put back.
+struct Run<'a, A: 'a> {
+    sync: &'a mut Synchronizer,
+    account: &'a mut TrackedAccount,
+    b: &'a A,
+    c: &'a mut CryptoManager,
+    /// The local and remote log as taken from the account.
+    logs: (Vec<EventLogEntry>, Vec<EventLogEntry>),
+}
+
+/// A step of a Run, giving back the Run with its result.
+type Step<'a, A, T> = Box<Future<Item = (Run<'a, A>, T), Error = (Run<'a, A>, SyncError)> + 'a>;
+
+impl<'a, A: AsyncBackend> Run<'a, A> {
+    /// Step 3, see RemoteLock::acquire.
+    fn acquire(self) -> Step<'a, A, ()> {
+        let name = self.sync.lock.name().to_string();
+        Box::new(self.read(&name, lock_error).and_then(move |(run, current)| {
+            let (info, data) = match run.sync.lock.begin_acquire(current.as_ref().map(|c| &c.0[..])) {
+                Ok(l) => l,
+                Err(e) => return run.fail(SyncError::Lock(e)),
+            };
+            let write = run.b.put_if(&name, data, current.map(|c| c.1));
+            Box::new(run.attempt(write)
+                        .and_then(move |(run, written)| {
+                            match written {
+                                Ok(_) | Err(StorageError::Conflict(_)) => run.read(&name, lock_error),
+                                Err(e) => run.fail(lock_error(e)),
+                            }
+                        })
+                        .and_then(move |(run, current)| {
+                            match run.sync.lock.finish_acquire(info, current.as_ref().map(|c| &c.0[..])) {
+                                Ok(_) => run.done(()),
+                                Err(e) => run.fail(SyncError::Lock(e)),
+                            }
+                        }))
+        }))
+    }
+
+    /// Extends the lease of the lock if less than half of it is left, see RemoteLock::renew.
+    fn renew(self) -> Step<'a, A, ()> {
+        if !self.sync.lock.needs_renewal() {
+            return self.done(());
+        }
+        let name = self.sync.lock.name().to_string();
+        Box::new(self.read(&name, lock_error).and_then(move |(run, current)| {
+            let (info, data) = match run.sync.lock.begin_renew(current.as_ref().map(|c| &c.0[..])) {
+                Ok(l) => l,
+                Err(e) => return run.fail(SyncError::Lock(e)),
+            };
+            let write = run.b.put_if(&name, data, current.map(|c| c.1));
+            Box::new(run.attempt(write)
+                        .and_then(move |(run, written)| {
+                            match written {
+                                Ok(_) | Err(StorageError::Conflict(_)) => run.read(&name, lock_error),
+                                Err(e) => run.fail(lock_error(e)),
+                            }
+                        })
+                        .and_then(move |(run, current)| {
+                            match run.sync.lock.finish_renew(info, current.as_ref().map(|c| &c.0[..])) {
+                                Ok(_) => run.done(()),
+                                Err(e) => run.fail(SyncError::Lock(e)),
+                            }
+                        }))
+        }))
+    }
+
+    /// Steps 4 to 8 and 10, while holding the lock.
+    fn exchange(self, local: Vec<EventLogEntry>, remote: Vec<EventLogEntry>) -> Step<'a, A, SyncSummary> {
+        Box::new(self.read_incoming().and_then(move |(run, (incoming, start, next))| {
+            let missing = run.sync.missing_bases(&incoming, &remote);
+            run.read_bases(missing).map(move |(run, ())| (run, (incoming, remote, start, next)))
+        }).and_then(move |(run, (incoming, remote, start, next))| {
+            let exchange = run.sync.merge(run.account, incoming, local, remote);
+
+            // Step 8: the entries become the next segment of the shared log.
+            let written = if exchange.sent.is_empty() {
+                run.done(())
+            } else {
+                match encode_log(&exchange.sent, run.c) {
+                    // Reading may have taken a while, the lock has to last for the upload.
+                    Ok(data) => {
+                        Box::new(run.renew().and_then(move |(run, ())| {
+                            let write = run.b.put_if(&log_segment_name(next), data, None);
+                            run.wait(write, SyncError::Storage).map(|(run, _)| (run, ()))
+                        }))
+                    }
+                    Err(e) => run.fail(SyncError::Storage(e)),
+                }
+            };
+
+            Box::new(written.and_then(move |(run, ())| {
+                // Step 10
+                let summary = run.sync.finish(run.account, exchange, start, next);
+                // Nothing may fail after the upload, the logs would be sent twice. A cursor that
+                // can't be published now is published with the next synchronization.
+                // Neither may a failed renewal, the lock is released right after.
+                let renewed: Step<'a, A, ()> = Box::new(run.renew().or_else(|(run, _)| Ok((run, ()))));
+                renewed.and_then(move |(run, ())| {
+                    let name = cursor_name(&run.sync.cursor.device);
+                    let save = match seal_object(&name, run.c, &run.sync.cursor) {
+                        Ok(data) => run.b.put(&name, data),
+                        Err(e) => Box::new(future::err(e)),
+                    };
+                    run.attempt(save).map(|(run, _)| (run, summary))
+                })
+            }))
+        }))
+    }
+
+    /// Steps 4 and 5: returns the entries of other devices since the last synchronization,
+    /// the number of entries before them and the number of the next segment of the shared log.
+    ///
+    /// Only the segments from the one in the cursor on are read, unless the log hasn't been
+    /// read yet. Then the whole log is read, including the backup log. If the Sanitizer has
+    /// moved the cursor into the backup log, the backup segments archived since are read.
+    fn read_incoming(self) -> Step<'a, A, (Vec<EventLogEntry>, usize, usize)> {
+        let segment = self.sync.cursor.segment;
+        if self.sync.cursor.seen.is_none() || segment == 0 {
+            return self.read_all();
+        }
+        Box::new(self.read_segments(segment).and_then(move |(run, (incoming, next))| {
+            let start = run.sync.cursor.position.index;
+            if !incoming.is_empty() {
+                return run.done((incoming, start, next));
+            }
+            // The Sanitizer always keeps the newest segment, so the last one read is still
+            // there unless the following ones were added and moved as well.
+            let previous = run.b.version(&log_segment_name(segment - 1));
+            Box::new(run.attempt(previous).and_then(move |(run, previous)| {
+                match previous {
+                    Ok(_) => run.done((incoming, start, next)),
+                    Err(StorageError::NotFound(_)) => run.read_archived(),
+                    Err(e) => run.fail(SyncError::Storage(e)),
+                }
+            }))
+        }))
+    }
+
+    /// Reads the segments from first on, like read_log_from.
+    fn read_segments(self, first: usize) -> Step<'a, A, (Vec<EventLogEntry>, usize)> {
+        Box::new(future::loop_fn((self, Vec::new(), first), |(run, mut entries, number)| {
+            let read = run.b.get(&log_segment_name(number));
+            run.attempt(read).and_then(move |(run, data)| {
+                match data.and_then(|d| decode_log(d, run.c)) {
+                    Ok(e) => {
+                        entries.extend(e);
+                        Ok(Loop::Continue((run, entries, number + 1)))
+                    }
+                    Err(StorageError::NotFound(_)) => Ok(Loop::Break((run, (entries, number)))),
+                    Err(e) => Err((run, SyncError::Storage(e))),
+                }
+            })
+        }))
+    }
+
+    /// Reads the entries with the ids from the whole log, the bases of conflicting changes the
+    /// Synchronizer doesn't have since a restart.
+    fn read_bases(self, ids: Vec<String>) -> Step<'a, A, ()> {
+        if ids.is_empty() {
+            return self.done(());
+        }
+        Box::new(self.read_joined(|index| index.segments.clone()).and_then(move |(run, (log, _))| {
+            for entry in log.into_iter().filter(|e| ids.contains(&e.id)) {
+                run.sync.entries.insert(entry.obj_id.clone(), entry);
+            }
+            run.done(())
+        }))
+    }
+
+    /// Steps 4 and 5 from the start of the log, the backup log first.
+    fn read_all(self) -> Step<'a, A, (Vec<EventLogEntry>, usize, usize)> {
+        Box::new(self.read_joined(|index| index.segments.clone()).and_then(|(run, (log, next))| {
+            match run.sync.start_from(log) {
+                Ok((incoming, start)) => run.done((incoming, start, next)),
+                Err(e) => run.fail(SyncError::Storage(e)),
+            }
+        }))
+    }
+
+    /// Steps 4 and 5 once the Sanitizer moved the cursor into the backup log: only the backup
+    /// segments archived since the last synchronization are read, unless the cursor isn't
+    /// found in them.
+    fn read_archived(self) -> Step<'a, A, (Vec<EventLogEntry>, usize, usize)> {
+        let synced = self.sync.cursor.synced;
+        Box::new(self.read_joined(move |index| index.archived_since(synced))
+                     .and_then(|(run, (log, next))| {
+                         let found = run.sync
+                                        .cursor
+                                        .position
+                                        .last_entry
+                                        .as_ref()
+                                        .and_then(|id| log.iter().position(|e| &e.id == id));
+                         match found {
+                             Some(i) => {
+                                 let start = run.sync.cursor.position.index;
+                                 run.done((log[i + 1..].to_vec(), start, next))
+                             }
+                             // The saved time was off.
+                             None => run.read_all(),
+                         }
+                     }))
+    }
+
+    /// Reads the backup segments pick chooses from the index of the backup log, followed by
+    /// the segments of the shared log. Returns their entries and the number of the next
+    /// segment.
+    fn read_joined<F>(self, pick: F) -> Step<'a, A, (Vec<EventLogEntry>, usize)>
+        where F: FnOnce(&BackupIndex) -> Vec<BackupSegment> + 'a
+    {
+        // Segments added after listing make the upload fail, instead of being missed.
+        let list = self.b.list(LOG_PREFIX);
+        Box::new(self.wait(list, SyncError::Storage)
+                     .and_then(|(run, names)| {
+                         run.read(BACKUP_INDEX, SyncError::Storage)
+                            .map(|(run, index)| (run, (names, index)))
+                     })
+                     .and_then(move |(run, (names, index))| {
+                         let index = match index {
+                             Some((data, _)) => {
+                                 match open_object::<BackupIndex>(BACKUP_INDEX, data, run.c) {
+                                     Ok(index) => index,
+                                     Err(e) => return run.fail(SyncError::Storage(e)),
+                                 }
+                             }
+                             None => BackupIndex::default(),
+                         };
+                         let picked = pick(&index);
+                         let numbers = segment_numbers(&names);
+                         let next = numbers.last().map_or(0, |n| n + 1);
+                         let archived = picked.iter()
+                                              .map(|s| run.b.get(&segment_name(&s.id)))
+                                              .collect::<Vec<_>>();
+                         // Segments moved into the backup log in the meantime are skipped.
+                         let segments = numbers.iter()
+                                               .map(|&n| {
+                                                   Box::new(run.b.get(&log_segment_name(n)).then(found)) as
+                                                   StorageFuture<Option<Vec<u8>>>
+                                               })
+                                               .collect::<Vec<_>>();
+                         let read = future::join_all(archived).join(future::join_all(segments));
+                         Box::new(run.wait(Box::new(read), SyncError::Storage)
+                                     .and_then(move |(run, (archived, segments))| {
+                                         match decode_all(run.c, &picked, archived, segments) {
+                                             Ok(log) => run.done((log, next)),
+                                             Err(e) => run.fail(SyncError::Storage(e)),
+                                         }
+                                     }))
+                     }))
+    }
+
+    /// Step 9, see RemoteLock::release.
+    fn release(self) -> Step<'a, A, ()> {
+        let name = self.sync.lock.name().to_string();
+        Box::new(self.read(&name, lock_error).and_then(move |(run, current)| {
+            if let Err(e) = run.sync.lock.begin_release(current.as_ref().map(|c| &c.0[..])) {
+                return run.fail(SyncError::Lock(e));
+            }
+            let write = run.b.put_if(&name, RELEASED.to_vec(), current.map(|c| c.1));
+            Box::new(run.attempt(write).and_then(|(run, written)| {
+                match written {
+                    Ok(_) => run.done(()),
+                    Err(StorageError::Conflict(_)) => run.fail(SyncError::Lock(LockError::Lost(None))),
+                    Err(e) => run.fail(lock_error(e)),
+                }
+            }))
+        }))
+    }
+
+    /// Reads an object and its version, None if it doesn't exist. Other errors fail the step,
+    /// made a SyncError with error.
+    fn read<F>(self, name: &str, error: F) -> Step<'a, A, Option<(Vec<u8>, Version)>>
+        where F: FnOnce(StorageError) -> SyncError + 'a
+    {
+        let read = self.b.get_versioned(name);
+        Box::new(self.wait(Box::new(read.then(found)), error))
+    }
+
+    /// Waits for f. Its errors fail the step, made a SyncError with error.
+    fn wait<T, F>(self, f: StorageFuture<T>, error: F) -> Step<'a, A, T>
+        where T: 'a,
+              F: FnOnce(StorageError) -> SyncError + 'a
+    {
+        Box::new(f.then(move |r| {
+            match r {
+                Ok(t) => Ok((self, t)),
+                Err(e) => Err((self, error(e))),
+            }
+        }))
+    }
+
+    /// Waits for f, leaving its errors to the step.
+    fn attempt<T: 'a>(self, f: StorageFuture<T>) -> Step<'a, A, Result<T, StorageError>> {
+        Box::new(f.then(move |r| Ok((self, r))))
+    }
+
+    fn done<T: 'a>(self, t: T) -> Step<'a, A, T> {
+        Box::new(future::ok((self, t)))
+    }
+
+    fn fail<T: 'a>(self, e: SyncError) -> Step<'a, A, T> {
+        Box::new(future::err((self, e)))
+    }
+
+    /// Puts the logs back after the synchronization failed with e.
+    fn abort(self, e: SyncError) -> SyncError {
+        let Run { account, logs, .. } = self;
+        account.restore_logs(logs.0, logs.1);
+        e
+    }
+}
+
+/// Errors of the backend while working with the lock.
+fn lock_error(e: StorageError) -> SyncError {
+    SyncError::Lock(LockError::Storage(e))
+}
+
+/// An object that doesn't exist is None.
+fn found<T>(r: Result<T, StorageError>) -> Result<Option<T>, StorageError> {
+    match r {
+        Ok(t) => Ok(Some(t)),
+        Err(StorageError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The log from what read_joined downloaded: the backup segments picked and the segments of
+/// the shared log that still existed.
+fn decode_all(c: &CryptoManager, picked: &[BackupSegment], archived: Vec<Vec<u8>>,
+              segments: Vec<Option<Vec<u8>>>)
+              -> Result<Vec<EventLogEntry>, StorageError> {
+    let mut entries = Vec::new();
+    for (segment, data) in picked.iter().zip(archived) {
+        let decoded: Vec<EventLogEntry> = try!(open_object(&segment_name(&segment.id), data, c));
+        entries.extend(decoded);
+    }
+    let mut log = Vec::new();
+    for data in segments.into_iter().filter_map(|d| d) {
+        log.extend(try!(decode_log(data, c)));
+    }
+    Ok(join_log(entries, &log))
+}
+
+/// Merges the entries of other devices the shared log got since the last synchronization with
+/// the merged remote log, step 6 of the synchronization. The result is ordered by timestamp,
+/// which is the order the changes take effect in. Entries without a timestamp come first.
+pub fn merge_shared_log(shared: &[EventLogEntry], remote: &[EventLogEntry]) -> Vec<EventLogEntry> {
+    let mut merged = shared.iter().chain(remote.iter()).cloned().collect::<Vec<_>>();
+    merged.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    merged
+}
+
+/// Records the timestamp of entry as the latest of its object, unless there is a later one
+/// already. Returns false in that case. Entries without timestamp always count as newer.
+fn newer(latest: &mut HashMap<String, Timestamp>, entry: &EventLogEntry) -> bool {
+    let ts = match entry.timestamp {
+        Some(ref ts) => ts,
+        None => return true,
+    };
+    if latest.get(&entry.obj_id).map_or(false, |l| l > ts) {
+        return false;
+    }
+    latest.insert(entry.obj_id.clone(), ts.clone());
+    true
+}
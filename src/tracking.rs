@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use chrono::Duration;
+use rustc_serialize::json;
+use clock::HybridClock;
+use domain::{Account, Calendar, EntryType, Event, EventData, EventLogEntry};
+
+/// An Account whose changes are recorded as log entries, so they can be synchronized.
+///
+/// As described in the module documentation of storage, changes of objects that haven't been
+/// synchronized yet go to the local log, unstamped, as they get their timestamp when they are
+/// synchronized. Changes of objects that are already known to other devices go to the remote
+/// log, stamped right away, so they can be merged with the shared log by time.
+///
+/// Entries about a calendar have the Calendar without its events as data, entries about an
+/// event an EventData. Calendars that aren't synchronized don't produce any entries.
+pub struct TrackedAccount {
+    account: Account,
+    clock: HybridClock,
+    synced: HashSet<String>,
+    local: Vec<EventLogEntry>,
+    remote: Vec<EventLogEntry>,
+}
+
+impl TrackedAccount {
+    /// Tracks the changes of account, which has been synchronized up to now.
+    pub fn new(account: Account, clock: HybridClock) -> TrackedAccount {
+        let mut synced = HashSet::new();
+        for cal in account.items.iter() {
+            synced.insert(cal.id.clone());
+            synced.extend(cal.get_events().iter().map(|e| e.id.clone()));
+        }
+        TrackedAccount {
+            account: account,
+            clock: clock,
+            synced: synced,
+            local: Vec::new(),
+            remote: Vec::new(),
+        }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn clock(&mut self) -> &mut HybridClock {
+        &mut self.clock
+    }
+
+    /// Changes of objects that haven't been synchronized yet.
+    pub fn local_log(&self) -> &[EventLogEntry] {
+        &self.local
+    }
+
+    /// Changes of objects that have already been synchronized.
+    pub fn remote_log(&self) -> &[EventLogEntry] {
+        &self.remote
+    }
+
+    /// Returns the local and the remote log and starts new ones, when synchronizing.
+    pub fn take_logs(&mut self) -> (Vec<EventLogEntry>, Vec<EventLogEntry>) {
+        (mem::replace(&mut self.local, Vec::new()), mem::replace(&mut self.remote, Vec::new()))
+    }
+
+    /// Puts logs taken with take_logs back in front of the current ones, e.g. when
+    /// synchronizing them failed.
+    pub fn restore_logs(&mut self, mut local: Vec<EventLogEntry>, mut remote: Vec<EventLogEntry>) {
+        local.extend(self.local.drain(..));
+        remote.extend(self.remote.drain(..));
+        self.local = local;
+        self.remote = remote;
+    }
+
+    /// Records that the objects with the given ids are known to other devices now, so their
+    /// further changes go to the remote log.
+    pub fn mark_synced<I: IntoIterator<Item = String>>(&mut self, ids: I) {
+        self.synced.extend(ids);
+    }
+
+    /// Replaces the state, e.g. after changes of other devices have been applied. Nothing is
+    /// logged and all objects count as synchronized.
+    pub fn reset(&mut self, account: Account) {
+        let logs = self.take_logs();
+        *self = TrackedAccount::new(account, self.clock.clone());
+        self.local = logs.0;
+        self.remote = logs.1;
+    }
+
+    /// Adds cal. Its events get entries of their own after the one of cal.
+    pub fn add_calendar(&mut self, cal: Calendar) {
+        self.log_calendar(EntryType::Create, &cal);
+        if cal.sync {
+            for e in cal.get_events() {
+                self.log_event(EntryType::Create, &cal.id, e);
+            }
+        }
+        self.account.items.push(cal);
+    }
+
+    /// Changes the properties of the calendar with the given id. Its events are changed with
+    /// the event methods, so every change gets an entry. Returns false if there is none.
+    ///
+    /// Switching sync off is logged, so the other devices know. Switching it on logs the
+    /// calendar and all its events, as changes made in the meantime weren't.
+    pub fn update_calendar(&mut self, id: &str, name: &str, desc: &str, sync: bool) -> bool {
+        let (cal, was_synced) = match self.calendar(id) {
+            Some(c) => {
+                let was_synced = c.sync;
+                c.name = name.to_string();
+                c.desc = desc.to_string();
+                c.sync = sync;
+                (c.clone(), was_synced)
+            }
+            None => return false,
+        };
+        match (was_synced, sync) {
+            (true, true) => self.log_calendar(EntryType::Update, &cal),
+            (true, false) => {
+                let entry = calendar_entry(EntryType::Update, &cal);
+                self.log(entry);
+            }
+            (false, true) => {
+                // Creates replace what other devices may still have from before.
+                let entry_type = if self.is_known(&cal.id) { EntryType::Update } else { EntryType::Create };
+                self.log_calendar(entry_type, &cal);
+                for e in cal.get_events() {
+                    self.log_event(EntryType::Create, &cal.id, e);
+                }
+            }
+            (false, false) => (),
+        }
+        true
+    }
+
+    /// Removes the calendar with the given id and returns it.
+    pub fn delete_calendar(&mut self, id: &str) -> Option<Calendar> {
+        let index = match self.account.items.iter().position(|c| c.id == id) {
+            Some(i) => i,
+            None => return None,
+        };
+        let cal = self.account.items.remove(index);
+        self.log_calendar(EntryType::Delete, &cal);
+        Some(cal)
+    }
+
+    /// Adds e to a calendar. Returns false if there is no calendar with the given id.
+    pub fn add_event(&mut self, calendar: &str, e: Event) -> bool {
+        let sync = match self.calendar(calendar) {
+            Some(c) => {
+                c.add_event(e.clone());
+                c.sync
+            }
+            None => return false,
+        };
+        if sync {
+            self.log_event(EntryType::Create, calendar, &e);
+        }
+        true
+    }
+
+    /// Replaces the event with the id of e. Returns false if the calendar doesn't contain it.
+    pub fn update_event(&mut self, calendar: &str, e: Event) -> bool {
+        let sync = match self.calendar(calendar) {
+            Some(c) => {
+                let old = match c.get_event(&e.id) {
+                    Some(old) => old.clone(),
+                    None => return false,
+                };
+                c.delete_event(&old);
+                c.add_event(e.clone());
+                c.sync
+            }
+            None => return false,
+        };
+        if sync {
+            self.log_event(EntryType::Update, calendar, &e);
+        }
+        true
+    }
+
+    /// Removes the event with the id of e from a calendar. Returns false if the calendar
+    /// doesn't contain it.
+    pub fn delete_event(&mut self, calendar: &str, e: &Event) -> bool {
+        let (old, sync) = match self.calendar(calendar) {
+            Some(c) => {
+                let old = match c.get_event(&e.id) {
+                    Some(old) => old.clone(),
+                    None => return false,
+                };
+                c.delete_event(&old);
+                (old, c.sync)
+            }
+            None => return false,
+        };
+        if sync {
+            self.log_event(EntryType::Delete, calendar, &old);
+        }
+        true
+    }
+
+    /// Adds n repetitions of e a week apart, like Calendar::repeat_event_n_times.
+    pub fn repeat_event_n_times(&mut self, calendar: &str, e: &Event, n: usize) -> bool {
+        if self.calendar(calendar).is_none() {
+            return false;
+        }
+        for _ in 0..n {
+            self.add_event(calendar, e.repeat(Duration::weeks(1)));
+        }
+        true
+    }
+
+    fn calendar(&mut self, id: &str) -> Option<&mut Calendar> {
+        self.account.items.iter_mut().find(|c| c.id == id)
+    }
+
+    fn log_calendar(&mut self, entry_type: EntryType, cal: &Calendar) {
+        if cal.sync {
+            self.log(calendar_entry(entry_type, cal));
+        }
+    }
+
+    /// True if other devices know the object or will with the next synchronization.
+    fn is_known(&self, id: &str) -> bool {
+        self.synced.contains(id) || self.local.iter().any(|e| e.obj_id == id)
+    }
+
+    fn log_event(&mut self, entry_type: EntryType, calendar: &str, e: &Event) {
+        let data = match entry_type {
+            EntryType::Delete => String::new(),
+            _ => {
+                json::encode(&EventData {
+                    calendar: calendar.to_string(),
+                    event: e.clone(),
+                })
+                .unwrap()
+            }
+        };
+        self.log(EventLogEntry::new(entry_type, &e.id, &data));
+    }
+
+    fn log(&mut self, mut entry: EventLogEntry) {
+        if self.synced.contains(&entry.obj_id) {
+            entry.stamp(&mut self.clock);
+            self.remote.push(entry);
+        } else {
+            self.local.push(entry);
+        }
+    }
+}
+
+fn calendar_entry(entry_type: EntryType, cal: &Calendar) -> EventLogEntry {
+    let data = match entry_type {
+        EntryType::Delete => String::new(),
+        _ => json::encode(&calendar_properties(cal)).unwrap(),
+    };
+    EventLogEntry::new(entry_type, &cal.id, &data)
+}
+
+/// Returns cal without its events, the data of the entries about it. Its events have entries
+/// of their own, so an Update of the calendar can't bring back events deleted before.
+pub fn calendar_properties(cal: &Calendar) -> Calendar {
+    let mut properties = Calendar::new(&cal.name, &cal.desc, cal.sync);
+    properties.id = cal.id.clone();
+    properties
+}
+
+/// Merges the local log, step 1 of the synchronization: every object created in the log gets a
+/// single Create with its final state, at the position of its first Create, and objects that
+/// are deleted again disappear completely, calendars with the events created in them. Entries
+/// about objects that weren't created in the log are kept as they are.
+pub fn compact_local_log(log: &[EventLogEntry]) -> Vec<EventLogEntry> {
+    let mut compacted: Vec<Option<EventLogEntry>> = Vec::new();
+    // Position of the Create of every object that currently exists after being created here.
+    let mut created: HashMap<String, usize> = HashMap::new();
+    // Objects with entries that are kept as they are. A later Create of them can't absorb the
+    // following entries, as dropping them would leave the earlier ones in effect.
+    let mut kept: HashSet<String> = HashSet::new();
+    for entry in log {
+        match (&entry.entry_type, created.get(&entry.obj_id).cloned()) {
+            (&EntryType::Create, None) if !kept.contains(&entry.obj_id) => {
+                created.insert(entry.obj_id.clone(), compacted.len());
+                compacted.push(Some(entry.clone()));
+            }
+            (&EntryType::Create, Some(i)) |
+            (&EntryType::Update, Some(i)) => {
+                if let Some(ref mut create) = compacted[i] {
+                    create.data = entry.data.clone();
+                }
+            }
+            (&EntryType::Delete, Some(i)) => {
+                compacted[i] = None;
+                created.remove(&entry.obj_id);
+                // The events created in a deleted calendar went away with it.
+                let events = created.iter()
+                                    .filter(|&(_, &j)| in_calendar(&compacted[j], &entry.obj_id))
+                                    .map(|(id, &j)| (id.clone(), j))
+                                    .collect::<Vec<_>>();
+                for (id, j) in events {
+                    compacted[j] = None;
+                    created.remove(&id);
+                }
+            }
+            (_, _) => {
+                kept.insert(entry.obj_id.clone());
+                compacted.push(Some(entry.clone()));
+            }
+        }
+    }
+    compacted.into_iter().filter_map(|e| e).collect()
+}
+
+fn in_calendar(entry: &Option<EventLogEntry>, calendar: &str) -> bool {
+    match *entry {
+        Some(ref e) => {
+            json::decode::<EventData>(&e.data).map(|d| d.calendar == calendar).unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+/// Merges the remote log, step 2 of the synchronization. As entries carry the whole state of
+/// their object, only the last entry of every object is needed, with its timestamp. Earlier
+/// updates of deleted objects go away with that as well.
+pub fn merge_remote_log(log: &[EventLogEntry]) -> Vec<EventLogEntry> {
+    let mut last = HashMap::new();
+    for (i, entry) in log.iter().enumerate() {
+        last.insert(&entry.obj_id, i);
+    }
+    log.iter()
+       .enumerate()
+       .filter(|&(i, e)| last[&e.obj_id] == i)
+       .map(|(_, e)| e.clone())
+       .collect()
+}